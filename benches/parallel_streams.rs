@@ -0,0 +1,114 @@
+//! Compare single-stream and multi-stream transfers over a high-latency link.
+//!
+//! The link is simulated by a loopback proxy that forwards at most one
+//! window (64 KiB) per round trip, so each connection is capped at
+//! `WINDOW / LATENCY` the way a long fat network caps a single TCP flow.
+//!
+//! Run with `cargo bench --bench parallel_streams`. Optional arguments:
+//! `<size in MiB> <latency in ms> <streams>` (defaults: 16, 20, 8).
+
+use std::{
+    env,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use nettool_rust::commands::file_transfer::{FileReceiver, FileSender};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, tcp::OwnedReadHalf, tcp::OwnedWriteHalf},
+    time::sleep,
+};
+
+const WINDOW: usize = 64 * 1024;
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().skip(1).filter(|a| !a.starts_with("--")).collect();
+    let size_mib: usize = args.first().and_then(|a| a.parse().ok()).unwrap_or(16);
+    let latency = Duration::from_millis(args.get(1).and_then(|a| a.parse().ok()).unwrap_or(20));
+    let streams: u16 = args.get(2).and_then(|a| a.parse().ok()).unwrap_or(8);
+
+    let workdir = env::temp_dir().join(format!("nettool-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&workdir).unwrap();
+    let source = workdir.join("payload.bin");
+    std::fs::write(&source, pseudo_random(size_mib * 1024 * 1024)).unwrap();
+
+    println!("{} MiB payload, {:?} injected latency, {} KiB window", size_mib, latency, WINDOW / 1024);
+    let single = run(&workdir, &source, 1, latency, 19_101).await;
+    let multi = run(&workdir, &source, streams, latency, 19_201).await;
+
+    let mib = size_mib as f64;
+    println!();
+    println!("streams   time      throughput");
+    println!("{:>7}   {:>6.2}s  {:>7.2} MiB/s", 1, single.as_secs_f64(), mib / single.as_secs_f64());
+    println!("{:>7}   {:>6.2}s  {:>7.2} MiB/s", streams, multi.as_secs_f64(), mib / multi.as_secs_f64());
+    println!("speedup: {:.2}x", single.as_secs_f64() / multi.as_secs_f64());
+
+    std::fs::remove_dir_all(&workdir).unwrap();
+}
+
+/// Send `source` through the latency proxy with `streams` connections and time it.
+async fn run(workdir: &Path, source: &Path, streams: u16, latency: Duration, port: u16) -> Duration {
+    let output = workdir.join(format!("out-{}", streams));
+    let output = output.to_str().unwrap().to_string();
+    let proxy_port = port + 1;
+
+    let receiver = tokio::spawn(FileReceiver::new(port, output).receive());
+    let proxy = tokio::spawn(proxy(proxy_port, port, latency));
+    sleep(Duration::from_millis(200)).await;
+
+    let sender = FileSender::new("127.0.0.1", proxy_port)
+        .file(source.to_str().unwrap())
+        .streams(streams)
+        .chunk_size(CHUNK_SIZE);
+    let started = Instant::now();
+    sender.send().await.unwrap();
+    receiver.await.unwrap().unwrap();
+    let elapsed = started.elapsed();
+
+    proxy.abort();
+    elapsed
+}
+
+/// Forward every connection on `listen` to `target`, delaying each window by `latency`.
+async fn proxy(listen: u16, target: u16, latency: Duration) {
+    let listener = TcpListener::bind(("127.0.0.1", listen)).await.unwrap();
+    loop {
+        let (client, _) = listener.accept().await.unwrap();
+        let server = TcpStream::connect(("127.0.0.1", target)).await.unwrap();
+        let (client_read, client_write) = client.into_split();
+        let (server_read, server_write) = server.into_split();
+        tokio::spawn(pipe(client_read, server_write, latency));
+        tokio::spawn(pipe(server_read, client_write, latency));
+    }
+}
+
+async fn pipe(mut from: OwnedReadHalf, mut to: OwnedWriteHalf, latency: Duration) {
+    let mut buffer = vec![0u8; WINDOW];
+    loop {
+        let n = match from.read(&mut buffer).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        sleep(latency).await;
+        if to.write_all(&buffer[..n]).await.is_err() {
+            break;
+        }
+    }
+    let _ = to.shutdown().await;
+}
+
+/// Incompressible test data without pulling in an RNG.
+fn pseudo_random(len: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
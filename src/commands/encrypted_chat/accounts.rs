@@ -0,0 +1,139 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs,
+    io::Write,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use argon2::{
+    Argon2,
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+};
+use rand::RngCore;
+use tokio::sync::Semaphore;
+
+/// Argon2 hashes computed at once; more wait their turn, so password guesses can't take over the CPU.
+const MAX_CONCURRENT_HASHES: usize = 2;
+
+/// Usernames match regardless of case, so `Alice` can't pose as `alice`.
+pub fn fold(username: &str) -> String {
+    username.to_lowercase()
+}
+
+/// Registered usernames and their Argon2id password hashes, one
+/// `username <PHC hash>` line per account in a file only the server reads.
+pub struct Accounts {
+    path: PathBuf,
+    hashes: Mutex<HashMap<String, String>>,
+    hashing: Semaphore,
+}
+
+impl Accounts {
+    /// Load the accounts in `path`; a missing file means no accounts yet.
+    pub fn load(path: PathBuf) -> Result<Accounts, Box<dyn Error + Send + Sync>> {
+        let contents = match fs::read_to_string(&path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("can't read accounts file '{}': {}", path.display(), e).into()),
+        };
+        let mut hashes = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let Some((name, hash)) = line.split_once(' ') else { continue };
+            PasswordHash::new(hash).map_err(|_| format!("'{}' line {}: not a password hash", path.display(), number + 1))?;
+            hashes.insert(fold(name), hash.to_string());
+        }
+        Ok(Accounts { path, hashes: Mutex::new(hashes), hashing: Semaphore::new(MAX_CONCURRENT_HASHES) })
+    }
+
+    pub fn is_registered(&self, username: &str) -> bool {
+        self.hashes.lock().unwrap().contains_key(&fold(username))
+    }
+
+    /// Whether `password` is the one `username` registered with.
+    pub async fn verify(&self, username: &str, password: String) -> bool {
+        let Some(hash) = self.hashes.lock().unwrap().get(&fold(username)).cloned() else {
+            return false;
+        };
+        // Argon2 is deliberately slow; keep it off the async workers.
+        let Ok(_permit) = self.hashing.acquire().await else { return false };
+        tokio::task::spawn_blocking(move || {
+            PasswordHash::new(&hash).is_ok_and(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    /// Reserve `username` for whoever knows `password`.
+    pub async fn register(&self, username: &str, password: String) -> Result<(), String> {
+        if password.is_empty() {
+            return Err("the password must not be empty".to_string());
+        }
+        if self.is_registered(username) {
+            return Err(format!("{} is already registered", username));
+        }
+        let _permit = self.hashing.acquire().await.map_err(|e| e.to_string())?;
+        let hash = tokio::task::spawn_blocking(move || {
+            let mut salt = [0u8; 16];
+            rand::rngs::OsRng.fill_bytes(&mut salt);
+            let salt = SaltString::encode_b64(&salt).map_err(|e| e.to_string())?;
+            Argon2::default().hash_password(password.as_bytes(), &salt).map(|h| h.to_string()).map_err(|e| e.to_string())
+        })
+        .await
+        .map_err(|e| e.to_string())??;
+
+        let mut hashes = self.hashes.lock().unwrap();
+        if hashes.contains_key(&fold(username)) {
+            return Err(format!("{} is already registered", username));
+        }
+        self.append(username, &hash).map_err(|e| format!("can't save the account: {}", e))?;
+        hashes.insert(fold(username), hash);
+        Ok(())
+    }
+
+    fn append(&self, username: &str, hash: &str) -> std::io::Result<()> {
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let mut options = fs::OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        writeln!(options.open(&self.path)?, "{} {}", username, hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn names_collide_regardless_of_case() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("accounts");
+        let accounts = Accounts::load(path.clone()).unwrap();
+        accounts.register("Alice", "s3cret".to_string()).await.unwrap();
+        assert!(accounts.is_registered("ALICE"));
+        assert!(accounts.register("alice", "other".to_string()).await.is_err());
+
+        assert!(accounts.verify("aLiCe", "s3cret".to_string()).await);
+        assert!(!accounts.verify("alice", "S3CRET".to_string()).await);
+        assert!(!accounts.verify("bob", "s3cret".to_string()).await);
+
+        let reloaded = Accounts::load(path).unwrap();
+        assert!(reloaded.verify("alice", "s3cret".to_string()).await);
+    }
+
+    #[tokio::test]
+    async fn empty_passwords_and_bad_files_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let accounts = Accounts::load(dir.path().join("accounts")).unwrap();
+        assert!(accounts.register("alice", String::new()).await.is_err());
+        assert!(!accounts.is_registered("alice"));
+
+        let corrupt = dir.path().join("corrupt");
+        std::fs::write(&corrupt, "alice not-a-hash\n").unwrap();
+        assert!(Accounts::load(corrupt).is_err());
+    }
+}
@@ -0,0 +1,483 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    io::IsTerminal,
+    path::Path,
+    sync::Arc,
+};
+
+use chrono::{Local, TimeZone};
+use colored::Colorize;
+use ed25519_dalek::VerifyingKey;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines, Stdin},
+    net::TcpStream,
+    sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+};
+use tracing::warn;
+
+use super::e2e::Keyring;
+use super::identity::{Identity, KnownHosts, Trust, fingerprint};
+use super::protocol::{ClientMessage, Envelope, Login, Payload, RoomInfo, ServerMessage, UserId, read_message, write_message};
+use super::tui;
+use super::{Role, Session, load_identity, perform_key_exchange};
+
+/// Print a loud warning that the server's key differs from the one recorded in `known_hosts`.
+fn warn_key_changed(server: &str, recorded: &VerifyingKey, presented: &VerifyingKey, known_hosts: &Path) {
+    let rule = "@".repeat(60);
+    eprintln!("{}", rule.red().bold());
+    eprintln!("{}", "@    WARNING: CHAT SERVER IDENTITY HAS CHANGED!".red().bold());
+    eprintln!("{}", rule.red().bold());
+    eprintln!("Someone could be intercepting this connection, or the server's key was replaced.");
+    eprintln!("Recorded key for {}: {}", server, fingerprint(recorded));
+    eprintln!("Key presented now:  {}", fingerprint(presented));
+    eprintln!("If the change is expected, remove the line for {} from '{}'.", server, known_hosts.display());
+}
+
+/// Show the control characters in `text` as escapes, so whatever a peer sends
+/// is printed rather than acted on by the terminal.
+fn escape_controls(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_control() { c.escape_default().to_string() } else { c.to_string() })
+        .collect()
+}
+
+fn payload_text(payload: &Payload) -> String {
+    match payload {
+        Payload::Plain(text) => escape_controls(text),
+        Payload::Sealed { .. } => "(encrypted)".dimmed().to_string(),
+    }
+}
+
+/// Format a server envelope for the terminal; `None` for ones not worth a line.
+///
+/// `me` is this client's user ID, so its own messages can be told apart by
+/// sender rather than by guessing from the text.
+pub fn render(envelope: &Envelope, me: UserId) -> Option<String> {
+    let time = Local
+        .timestamp_millis_opt(envelope.timestamp)
+        .single()
+        .map(|t| t.format("[%H:%M:%S]").to_string())
+        .unwrap_or_default();
+    let line = match &envelope.body {
+        ServerMessage::Chat { sender, username, payload, .. } => {
+            let name = if *sender == me { username.blue().bold() } else { username.magenta().bold() };
+            format!("{} {}: {}", time, name, payload_text(payload))
+        }
+        ServerMessage::Direct { from, to, payload } if from.id == me => {
+            format!("{} {} {}", time, format!("→ {} (private):", to.username).magenta().bold(), payload_text(payload))
+        }
+        ServerMessage::Direct { from, payload, .. } => {
+            format!("{} {} {}", time, format!("← {} (private):", from.username).magenta().bold(), payload_text(payload))
+        }
+        ServerMessage::Join { member, .. } if member.id == me => return None,
+        ServerMessage::Join { room, member } => format!("{} {}", time, format!("→ {} joined {}", member.username, room).yellow()),
+        ServerMessage::Renamed { user, to, .. } if *user == me => format!("{} {}", time, format!("You are now known as {}", to).yellow()),
+        ServerMessage::Renamed { from, to, .. } => format!("{} {}", time, format!("{} is now known as {}", from, to).yellow()),
+        ServerMessage::Leave { room, username, .. } => format!("{} {}", time, format!("← {} left {}", username, room).yellow()),
+        ServerMessage::Joined { room, members } => {
+            let names: Vec<&str> = members.iter().map(|m| m.username.as_str()).collect();
+            format!("{} {}", time, format!("You are now in {} with {}", room, names.join(", ")).cyan())
+        }
+        ServerMessage::Rooms { rooms } => rooms.iter().map(render_room).collect::<Vec<_>>().join("\n"),
+        ServerMessage::Members { room, members } => {
+            let names: Vec<&str> = members.iter().map(|m| m.username.as_str()).collect();
+            format!("{}: {}", room.bold(), names.join(", "))
+        }
+        ServerMessage::History { room, query, messages } => {
+            let count = match messages.len() {
+                1 => "1 message".to_string(),
+                n => format!("{} messages", n),
+            };
+            let heading = match query {
+                _ if messages.is_empty() => format!("— nothing found in {} —", room),
+                Some(query) => format!("— {} in {} matching '{}' —", count, room, escape_controls(query)),
+                None => format!("— last {} in {} —", count, room),
+            };
+            let lines = messages.iter().filter_map(|m| render(m, me));
+            std::iter::once(heading.dimmed().to_string()).chain(lines).collect::<Vec<_>>().join("\n")
+        }
+        ServerMessage::System { text } => format!("{} {}", time, text.cyan()),
+        ServerMessage::Ack { .. } => format!("{}", "✔ Delivered".green()),
+        ServerMessage::Queued { to, .. } => format!("{}", format!("✉ {} is offline; the message will be delivered when they log in", to).yellow()),
+        ServerMessage::Error { reason, .. } => format!("{}", format!("✖ {}", reason).red()),
+        ServerMessage::RoomKey { .. } | ServerMessage::User { .. } => return None,
+    };
+    Some(line)
+}
+
+fn render_room(room: &RoomInfo) -> String {
+    let people = if room.members == 1 { "member" } else { "members" };
+    let lock = if room.locked { " (locked)" } else { "" };
+    format!("  {:<24} {} {}{}", room.name.bold(), room.members, people, lock)
+}
+
+/// Turn a line typed at the prompt into a message for the server; `seq` numbers
+/// the ones the server acknowledges.
+fn parse_input(line: &str, seq: &mut u64) -> Result<ClientMessage, String> {
+    let mut words = line.split_whitespace();
+    match words.next() {
+        Some(word) if !word.starts_with('/') => {
+            *seq += 1;
+            Ok(ClientMessage::Chat { seq: *seq, payload: Payload::Plain(line.to_string()) })
+        }
+        Some("/msg") => {
+            let usage = "usage: /msg <user> <text>";
+            let rest = line.trim_start().trim_start_matches("/msg").trim_start();
+            let (to, text) = rest.split_once(char::is_whitespace).ok_or(usage)?;
+            *seq += 1;
+            Ok(ClientMessage::Direct { seq: *seq, to: to.to_string(), payload: Payload::Plain(text.trim().to_string()) })
+        }
+        Some("/join") => {
+            let room = words.next().ok_or("usage: /join #room [password]")?.to_string();
+            let password = words.next().map(str::to_string);
+            Ok(ClientMessage::Join { room, password })
+        }
+        Some("/part") => Ok(ClientMessage::Part),
+        Some("/rooms") => Ok(ClientMessage::Rooms),
+        Some("/who") => Ok(ClientMessage::Who),
+        Some("/nick") => {
+            let username = words.next().ok_or("usage: /nick <name> [password]")?.to_string();
+            let password = words.next().map(str::to_string);
+            Ok(ClientMessage::Nick { username, password })
+        }
+        Some("/history") => {
+            let count = match words.next() {
+                Some(n) => n.parse().map_err(|_| "usage: /history [count]")?,
+                None => 20,
+            };
+            Ok(ClientMessage::History { count })
+        }
+        Some("/search") => {
+            let text = line.trim_start().trim_start_matches("/search").trim();
+            if text.is_empty() {
+                return Err("usage: /search <text>".to_string());
+            }
+            Ok(ClientMessage::Search { text: text.to_string() })
+        }
+        Some("/register") => {
+            let password = words.next().ok_or("usage: /register <password>")?.to_string();
+            Ok(ClientMessage::Register { password })
+        }
+        _ => Err(
+            "commands: /join #room [password], /part, /rooms, /who, /msg <user> <text>, /history [count], /search <text>, /nick <name> [password], /register <password>, /fingerprint [user]"
+                .to_string(),
+        ),
+    }
+}
+
+/// Everything the client keeps between lines: who it is, its keys, and what it still has to send.
+pub struct Chat {
+    pub me: UserId,
+    identity: Arc<Identity>,
+    session: Session,
+    seq: u64,
+    /// Present when the server relays only end-to-end encrypted text.
+    keyring: Option<Keyring>,
+    /// Direct messages waiting for the recipient's identity key, by username.
+    pending: HashMap<String, Vec<(u64, String)>>,
+    outgoing: UnboundedSender<ClientMessage>,
+}
+
+impl Chat {
+    fn send(&self, message: ClientMessage) {
+        // Only fails once the connection is gone, which the receiving task reports.
+        let _ = self.outgoing.send(message);
+    }
+
+    /// Handle a line typed by the user, returning anything to print.
+    pub fn input(&mut self, line: &str) -> Option<String> {
+        if line == "/fingerprint" || line.starts_with("/fingerprint ") {
+            return Some(self.fingerprints(line["/fingerprint".len()..].trim()));
+        }
+        let message = match parse_input(line, &mut self.seq) {
+            Ok(message) => message,
+            Err(help) => return Some(help.red().to_string()),
+        };
+        let Some(keyring) = &mut self.keyring else {
+            self.send(message);
+            return None;
+        };
+        match message {
+            ClientMessage::Chat { seq, payload: Payload::Plain(text) } => match keyring.seal_room(&text) {
+                Ok(payload) => self.send(ClientMessage::Chat { seq, payload }),
+                Err(reason) => return Some(format!("✖ {}", reason).red().to_string()),
+            },
+            // Sealed once the server tells us the recipient's identity key.
+            ClientMessage::Direct { seq, to, payload: Payload::Plain(text) } => {
+                self.pending.entry(to.clone()).or_default().push((seq, text));
+                self.send(ClientMessage::Lookup { username: to });
+            }
+            other => self.send(other),
+        }
+        None
+    }
+
+    fn fingerprints(&self, user: &str) -> String {
+        if !user.is_empty() {
+            return match self.keyring.as_ref().and_then(|k| k.key_of(user)) {
+                Some(key) => format!("{}'s key: {}", user, fingerprint(&key)),
+                None => format!("no key seen for {} yet", user).red().to_string(),
+            };
+        }
+        [
+            format!("Your key:     {}", fingerprint(&self.identity.public())),
+            format!("Server key:   {}", fingerprint(&self.session.peer)),
+            format!("Session code: {} (the server logs the same code unless the connection is intercepted)", self.session.code),
+        ]
+        .join("\n")
+    }
+
+    /// Handle an envelope from the server, returning anything to print.
+    /// Encrypted text in `envelope` is replaced by what it decrypts to.
+    pub fn receive(&mut self, envelope: &mut Envelope) -> Option<String> {
+        if let Some(keyring) = &mut self.keyring {
+            let revealed = match &mut envelope.body {
+                ServerMessage::Joined { room, members } => keyring.enter_room(room, members),
+                ServerMessage::Join { room, member } => keyring.member_joined(room, member),
+                ServerMessage::Leave { room, user, .. } => keyring.member_left(room, *user),
+                ServerMessage::Renamed { user, to, .. } => keyring.renamed(*user, to).map(|()| Vec::new()),
+                ServerMessage::RoomKey { room, sender, payload } => keyring.accept_sender_key(room, *sender, payload).map(|()| Vec::new()),
+                ServerMessage::User { username, member } => {
+                    let waiting = self.pending.remove(username).unwrap_or_default();
+                    match member {
+                        Some(member) => waiting
+                            .into_iter()
+                            .map(|(seq, text)| {
+                                let payload = keyring.seal_direct(member, &text)?;
+                                Ok(ClientMessage::Direct { seq, to: username.clone(), payload })
+                            })
+                            .collect(),
+                        None if waiting.is_empty() => Ok(Vec::new()),
+                        None => return Some(format!("✖ {} is not online", username).red().to_string()),
+                    }
+                }
+                ServerMessage::Chat { room, sender, payload, .. } => {
+                    keyring.open_room(room, *sender, payload).map(|text| *payload = Payload::Plain(text)).map(|()| Vec::new())
+                }
+                // Older messages may be under sender keys this client never got; those stay "(encrypted)".
+                ServerMessage::History { messages, .. } => {
+                    for message in messages {
+                        if let ServerMessage::Chat { room, sender, payload, .. } = &mut message.body
+                            && let Ok(text) = keyring.open_room(room, *sender, payload)
+                        {
+                            *payload = Payload::Plain(text);
+                        }
+                    }
+                    Ok(Vec::new())
+                }
+                ServerMessage::Direct { from, to, payload } => {
+                    keyring.open_direct(from, to, payload).map(|text| *payload = Payload::Plain(text)).map(|()| Vec::new())
+                }
+                _ => Ok(Vec::new()),
+            };
+            let mut problems = keyring.take_refusals();
+            match revealed {
+                Ok(messages) => messages.into_iter().for_each(|m| self.send(m)),
+                Err(reason) => problems.push(reason),
+            }
+            if !problems.is_empty() {
+                let problems = problems.iter().map(|p| format!("🔒 {}", p).red().to_string());
+                return Some(render(envelope, self.me).into_iter().chain(problems).collect::<Vec<_>>().join("\n"));
+            }
+        }
+        render(envelope, self.me)
+    }
+}
+
+/// Read the password of a registered username, without echo at a terminal.
+async fn read_password(stdin: &mut Lines<BufReader<Stdin>>, username: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+    if std::io::stdin().is_terminal() {
+        let prompt = format!("Password for {}: ", username);
+        return Ok(tokio::task::spawn_blocking(move || rpassword::prompt_password(prompt)).await??);
+    }
+    Ok(stdin.next_line().await?.ok_or("Failed to read password")?)
+}
+
+/// Print envelopes and read commands line by line, for pipes and `--plain`.
+async fn line_mode(
+    mut chat: Chat,
+    mut stdin: Lines<BufReader<Stdin>>,
+    mut incoming: UnboundedReceiver<Result<Envelope, String>>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    loop {
+        tokio::select! {
+            line = stdin.next_line() => {
+                let Ok(Some(line)) = line else { break };
+                let text = line.trim();
+                if !text.is_empty()
+                    && let Some(output) = chat.input(text)
+                {
+                    println!("{}", output);
+                }
+            }
+            received = incoming.recv() => match received {
+                Some(Ok(mut envelope)) => {
+                    if let Some(line) = chat.receive(&mut envelope) {
+                        println!("{}", line);
+                    }
+                }
+                Some(Err(reason)) => {
+                    warn!("{}", reason);
+                    break;
+                }
+                None => break,
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Join the chat at `host:port`, authenticating with the identity key at
+/// `identity_path` and checking the server's key against `known_hosts`.
+/// Opens the full-screen interface at a terminal, unless `plain` is set.
+pub async fn chat_client(host: &str, port: u16, identity_path: Option<&Path>, plain: bool) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let full_screen = !plain && std::io::stdin().is_terminal() && std::io::stdout().is_terminal();
+    if !full_screen {
+        tracing_subscriber::fmt::init();
+    } else if !std::io::stderr().is_terminal() {
+        // Log lines written to the terminal would tear through the interface,
+        // so they are only kept when stderr goes somewhere else.
+        tracing_subscriber::fmt().with_writer(std::io::stderr).init();
+    }
+    let identity = Arc::new(load_identity(identity_path, false)?);
+    let mut stream = TcpStream::connect((host, port)).await?;
+    let session = perform_key_exchange(&mut stream, &identity, Role::Client).await?;
+
+    let server = format!("{}:{}", host, port);
+    let known_hosts = KnownHosts::default_store();
+    match known_hosts.check(&server, &session.peer)? {
+        Trust::Known => {}
+        Trust::New => eprintln!(
+            "{}",
+            format!("Trusting new server key for {} ({}) from now on", server, fingerprint(&session.peer)).yellow()
+        ),
+        Trust::Changed { recorded } => {
+            warn_key_changed(&server, &recorded, &session.peer, known_hosts.path());
+            return Err(format!("server key for {} does not match known_hosts", server).into());
+        }
+    }
+    let key = session.key;
+
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+    let Some(Login::Prompt) = read_message(&mut stream, &key).await? else {
+        return Err("server did not ask for a username".into());
+    };
+    let mut retry = None;
+    let (me, e2e) = loop {
+        let request = match retry.take() {
+            Some(request) => request,
+            None => {
+                print!("Enter your username: ");
+                let _ = tokio::io::stdout().flush().await;
+                let Ok(Some(name)) = stdin.next_line().await else {
+                    return Err("Failed to read username".into());
+                };
+                Login::Request { username: name.trim().to_string(), password: None }
+            }
+        };
+        write_message(&mut stream, &key, &request).await?;
+        match read_message(&mut stream, &key).await?.ok_or("connection closed during login")? {
+            Login::Accepted { user_id, e2e } => break (user_id, e2e),
+            Login::Rejected { reason } => println!("{}", reason.red()),
+            Login::PasswordRequired { username } => {
+                let password = read_password(&mut stdin, &username).await?;
+                retry = Some(Login::Request { username, password: Some(password) });
+            }
+            other => return Err(format!("unexpected login reply {:?}", other).into()),
+        }
+    };
+    let banner = if e2e {
+        "Messages are end-to-end encrypted; the server only relays ciphertext.".green()
+    } else {
+        "The server can read messages here; it needs --e2e for end-to-end encryption.".yellow()
+    };
+
+    let (r, mut w) = stream.into_split();
+    let (outgoing, mut queue) = unbounded_channel::<ClientMessage>();
+    tokio::spawn(async move {
+        while let Some(message) = queue.recv().await {
+            if let Err(e) = write_message(&mut w, &key, &message).await {
+                warn!("❌ {}", e);
+                break;
+            }
+        }
+    });
+
+    let chat = Chat {
+        me,
+        keyring: e2e.then(|| Keyring::new(Arc::clone(&identity), me, KnownHosts::peer_store(), server)),
+        identity,
+        session,
+        seq: 0,
+        pending: HashMap::new(),
+        outgoing,
+    };
+
+    // Envelopes as they arrive, then why the connection ended.
+    let (received, incoming) = unbounded_channel();
+    let mut recv_reader = BufReader::new(r);
+    tokio::spawn(async move {
+        loop {
+            match read_message::<_, Envelope>(&mut recv_reader, &key).await {
+                Ok(Some(envelope)) => {
+                    if received.send(Ok(envelope)).is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => {
+                    let _ = received.send(Err("Connection closed.".to_string()));
+                    break;
+                }
+                Err(e) => {
+                    let _ = received.send(Err(format!("❌ {}", e)));
+                    break;
+                }
+            }
+        }
+    });
+
+    if !full_screen {
+        println!("{}", banner);
+        return line_mode(chat, stdin, incoming).await;
+    }
+    drop(stdin);
+    tui::run(chat, incoming, banner.to_string()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_lines_and_direct_messages_are_numbered() {
+        let mut seq = 0;
+        let message = parse_input("hello there", &mut seq).unwrap();
+        assert!(matches!(message, ClientMessage::Chat { seq: 1, payload: Payload::Plain(ref t) } if t == "hello there"));
+        let message = parse_input("  /msg bob  see you  later ", &mut seq).unwrap();
+        assert!(
+            matches!(message, ClientMessage::Direct { seq: 2, ref to, payload: Payload::Plain(ref t) } if to == "bob" && t == "see you  later")
+        );
+        assert_eq!(parse_input("/msg bob", &mut seq).unwrap_err(), "usage: /msg <user> <text>");
+        assert_eq!(seq, 2);
+    }
+
+    #[test]
+    fn commands_take_their_arguments() {
+        let mut seq = 0;
+        let join = parse_input("/join #ops hunter2", &mut seq).unwrap();
+        assert!(matches!(join, ClientMessage::Join { ref room, password: Some(ref p) } if room == "#ops" && p == "hunter2"));
+        assert!(matches!(parse_input("/join #ops", &mut seq).unwrap(), ClientMessage::Join { password: None, .. }));
+        assert!(matches!(parse_input("/history", &mut seq).unwrap(), ClientMessage::History { count: 20 }));
+        assert!(matches!(parse_input("/history 5", &mut seq).unwrap(), ClientMessage::History { count: 5 }));
+        assert!(parse_input("/history lots", &mut seq).is_err());
+        let search = parse_input("/search  two words ", &mut seq).unwrap();
+        assert!(matches!(search, ClientMessage::Search { ref text } if text == "two words"));
+        assert!(parse_input("/search", &mut seq).is_err());
+        assert!(parse_input("/join", &mut seq).is_err());
+        assert!(parse_input("/nick", &mut seq).is_err());
+        assert!(parse_input("/register", &mut seq).is_err());
+        assert!(parse_input("/bogus", &mut seq).unwrap_err().starts_with("commands:"));
+        assert_eq!(seq, 0);
+    }
+}
@@ -0,0 +1,291 @@
+use std::{collections::HashMap, sync::Arc};
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use super::identity::{Identity, KnownHosts, Trust, fingerprint, verify};
+use super::protocol::{ClientMessage, Member, Payload, UserId};
+use crate::utils::encryption::{open_chunk, seal_chunk};
+
+/// Domain separation for everything derived or signed for end-to-end encryption.
+const E2E_LABEL: &[u8] = b"nettool chat e2e v1";
+
+struct Peer {
+    username: String,
+    key: VerifyingKey,
+    /// Shared with this peer alone, from X25519 agreement between the identity keys.
+    pairwise: [u8; 32],
+}
+
+/// This client's own key for the room it is in.
+struct SenderKey {
+    id: u32,
+    key: [u8; 32],
+}
+
+/// A chat client's end-to-end keys.
+///
+/// Direct messages are sealed with the pairwise key of sender and recipient.
+/// Room messages are sealed with the sender's own random sender key, which it
+/// hands to every other member under their pairwise keys and replaces whenever
+/// someone leaves. Every ciphertext is also signed with the sender's identity
+/// key, so members holding a sender key still can't forge messages from its owner.
+///
+/// Each username's identity key is pinned in `known_peers` the first time it
+/// is seen on a server; a user showing up under that name with another key
+/// is refused, so the server can't swap in a key of its own.
+pub struct Keyring {
+    identity: Arc<Identity>,
+    me: UserId,
+    /// Pinned identity keys of other users.
+    pins: KnownHosts,
+    /// `host:port` of the server, which usernames are pinned under.
+    server: String,
+    peers: HashMap<UserId, Peer>,
+    room: String,
+    /// Everyone else in the room.
+    members: Vec<UserId>,
+    own: Option<SenderKey>,
+    sender_keys: HashMap<(UserId, u32), [u8; 32]>,
+    /// Why members were left out when entering the room, until [`Keyring::take_refusals`].
+    refusals: Vec<String>,
+}
+
+impl Keyring {
+    pub fn new(identity: Arc<Identity>, me: UserId, pins: KnownHosts, server: String) -> Keyring {
+        Keyring {
+            identity,
+            me,
+            pins,
+            server,
+            peers: HashMap::new(),
+            room: String::new(),
+            members: Vec::new(),
+            own: None,
+            sender_keys: HashMap::new(),
+            refusals: Vec::new(),
+        }
+    }
+
+    /// Check `key` against the one pinned for `username` on this server, pinning it if there is none.
+    fn pin(&self, username: &str, key: &VerifyingKey) -> Result<(), String> {
+        let name = format!("{}@{}", username, self.server);
+        match self.pins.check(&name, key).map_err(|e| format!("could not check the key of {}: {}", username, e))? {
+            Trust::Known | Trust::New => Ok(()),
+            Trust::Changed { recorded } => Err(format!(
+                "WARNING: {}'s identity key has changed from {} to {}; someone may be impersonating them, so they are refused. \
+                 If the change is expected, remove the line for {} from '{}'.",
+                username,
+                fingerprint(&recorded),
+                fingerprint(key),
+                name,
+                self.pins.path().display()
+            )),
+        }
+    }
+
+    /// Why members were left out of the room since the last call.
+    pub fn take_refusals(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.refusals)
+    }
+
+    /// Identity key of the user last seen as `username`.
+    pub fn key_of(&self, username: &str) -> Option<VerifyingKey> {
+        self.peers.values().find(|p| p.username == username).map(|p| p.key)
+    }
+
+    /// `user` is now called `username`, which must not be pinned to another key.
+    pub fn renamed(&mut self, user: UserId, username: &str) -> Result<(), String> {
+        let Some(key) = self.peers.get(&user).map(|p| p.key) else { return Ok(()) };
+        if let Err(reason) = self.pin(username, &key) {
+            self.forget(user);
+            return Err(reason);
+        }
+        if let Some(peer) = self.peers.get_mut(&user) {
+            peer.username = username.to_string();
+        }
+        Ok(())
+    }
+
+    /// Drop everything known about `user`, so nothing more is sealed for or accepted from it.
+    fn forget(&mut self, user: UserId) {
+        self.peers.remove(&user);
+        self.members.retain(|&id| id != user);
+        self.sender_keys.retain(|(sender, _), _| *sender != user);
+    }
+
+    /// Remember `member`'s identity key, once it matches the one pinned for its
+    /// username, and derive the pairwise key shared with it.
+    fn learn(&mut self, member: &Member) -> Result<&Peer, String> {
+        let key = VerifyingKey::from_bytes(&member.key).map_err(|_| format!("{} has an invalid identity key", member.username))?;
+        if self.peers.get(&member.id).is_none_or(|p| p.key != key || p.username != member.username) {
+            self.pin(&member.username, &key)?;
+        }
+        if self.peers.get(&member.id).is_none_or(|p| p.key != key) {
+            let shared = self.identity.agree(&key);
+            if shared == [0u8; 32] {
+                return Err(format!("{} has an unusable identity key", member.username));
+            }
+            let mine = self.identity.public();
+            let (low, high) = if mine.as_bytes() <= key.as_bytes() { (mine, key) } else { (key, mine) };
+            let mut hasher = Sha256::new();
+            hasher.update(E2E_LABEL);
+            hasher.update(b"pairwise");
+            hasher.update(shared);
+            hasher.update(low.as_bytes());
+            hasher.update(high.as_bytes());
+            let pairwise = hasher.finalize().into();
+            self.peers.insert(member.id, Peer { username: member.username.clone(), key, pairwise });
+        }
+        let peer = self.peers.get_mut(&member.id).expect("peer was just recorded");
+        peer.username = member.username.clone();
+        Ok(peer)
+    }
+
+    fn peer(&self, id: UserId) -> Result<&Peer, String> {
+        self.peers.get(&id).ok_or_else(|| format!("no identity key for user {}", id))
+    }
+
+    /// Enter `room` with `members` (this client included), returning a fresh
+    /// sender key sealed for each of the others.
+    pub fn enter_room(&mut self, room: &str, members: &[Member]) -> Result<Vec<ClientMessage>, String> {
+        self.room = room.to_string();
+        self.members.clear();
+        self.sender_keys.clear();
+        let me = self.me;
+        for member in members.iter().filter(|m| m.id != me) {
+            // A refused member doesn't get our sender key, but the others still do.
+            match self.learn(member) {
+                Ok(_) => self.members.push(member.id),
+                Err(reason) => self.refusals.push(reason),
+            }
+        }
+        self.rotate()
+    }
+
+    /// `member` arrived in `room`: hand it the current sender key.
+    pub fn member_joined(&mut self, room: &str, member: &Member) -> Result<Vec<ClientMessage>, String> {
+        if room != self.room || member.id == self.me {
+            return Ok(Vec::new());
+        }
+        self.learn(member)?;
+        self.members.push(member.id);
+        match &self.own {
+            Some(own) => Ok(vec![self.seal_sender_key(member.id, own)?]),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// `user` left `room`: replace the sender key so it can't read what comes next.
+    pub fn member_left(&mut self, room: &str, user: UserId) -> Result<Vec<ClientMessage>, String> {
+        if room != self.room {
+            return Ok(Vec::new());
+        }
+        self.members.retain(|&id| id != user);
+        self.sender_keys.retain(|(sender, _), _| *sender != user);
+        self.rotate()
+    }
+
+    fn rotate(&mut self) -> Result<Vec<ClientMessage>, String> {
+        let mut key = [0u8; 32];
+        rand::rngs::OsRng.fill_bytes(&mut key);
+        let own = SenderKey { id: rand::rngs::OsRng.next_u32(), key };
+        // Kept alongside the others' so the server's echo of our own messages can be read.
+        self.sender_keys.insert((self.me, own.id), own.key);
+        let sealed = self.members.iter().map(|&to| self.seal_sender_key(to, &own)).collect();
+        self.own = Some(own);
+        sealed
+    }
+
+    fn seal_sender_key(&self, to: UserId, own: &SenderKey) -> Result<ClientMessage, String> {
+        let peer = self.peer(to)?;
+        let aad = context(b"room-key", &[self.identity.public().as_bytes(), peer.key.as_bytes(), self.room.as_bytes(), &own.id.to_be_bytes()]);
+        let payload = self.seal(&peer.pairwise, own.id, &own.key, &aad)?;
+        Ok(ClientMessage::RoomKey { to, room: self.room.clone(), payload })
+    }
+
+    /// Store a sender key handed to us by `sender`.
+    pub fn accept_sender_key(&mut self, room: &str, sender: UserId, payload: &Payload) -> Result<(), String> {
+        if room != self.room {
+            return Ok(());
+        }
+        let peer = self.peer(sender)?;
+        let key_id = key_id(payload)?;
+        let aad = context(b"room-key", &[peer.key.as_bytes(), self.identity.public().as_bytes(), room.as_bytes(), &key_id.to_be_bytes()]);
+        let key = open(&peer.pairwise, &peer.key, &aad, payload)?
+            .try_into()
+            .map_err(|_| format!("malformed room key from {}", peer.username))?;
+        self.sender_keys.insert((sender, key_id), key);
+        Ok(())
+    }
+
+    pub fn seal_room(&self, text: &str) -> Result<Payload, String> {
+        let own = self.own.as_ref().ok_or("no room key yet; wait until you have joined a room")?;
+        let aad = context(b"room", &[self.identity.public().as_bytes(), self.room.as_bytes(), &own.id.to_be_bytes()]);
+        self.seal(&own.key, own.id, text.as_bytes(), &aad)
+    }
+
+    pub fn open_room(&self, room: &str, sender: UserId, payload: &Payload) -> Result<String, String> {
+        let key_id = key_id(payload)?;
+        let sender_key = if sender == self.me { self.identity.public() } else { self.peer(sender)?.key };
+        let key = self.sender_keys.get(&(sender, key_id)).ok_or("no room key from the sender yet")?;
+        let aad = context(b"room", &[sender_key.as_bytes(), room.as_bytes(), &key_id.to_be_bytes()]);
+        text(open(key, &sender_key, &aad, payload)?)
+    }
+
+    pub fn seal_direct(&mut self, to: &Member, text: &str) -> Result<Payload, String> {
+        let mine = self.identity.public();
+        let peer = self.learn(to)?;
+        let (pairwise, theirs) = (peer.pairwise, peer.key);
+        let aad = context(b"direct", &[mine.as_bytes(), theirs.as_bytes()]);
+        self.seal(&pairwise, 0, text.as_bytes(), &aad)
+    }
+
+    /// Open a direct message from `from` to `to`, one of which is this client.
+    pub fn open_direct(&mut self, from: &Member, to: &Member, payload: &Payload) -> Result<String, String> {
+        let other = if from.id == self.me { to } else { from };
+        let pairwise = self.learn(other)?.pairwise;
+        let from_key = VerifyingKey::from_bytes(&from.key).map_err(|_| "invalid sender key")?;
+        let aad = context(b"direct", &[&from.key, &to.key]);
+        text(open(&pairwise, &from_key, &aad, payload)?)
+    }
+
+    fn seal(&self, key: &[u8; 32], key_id: u32, plaintext: &[u8], aad: &[u8]) -> Result<Payload, String> {
+        let ciphertext = seal_chunk(plaintext, key, aad).map_err(|e| e.to_string())?;
+        let signature = self.identity.sign(&[aad, &ciphertext].concat()).to_bytes().to_vec();
+        Ok(Payload::Sealed { key_id, ciphertext, signature })
+    }
+}
+
+/// Check `payload` was signed by `sender`, then decrypt it.
+fn open(key: &[u8; 32], sender: &VerifyingKey, aad: &[u8], payload: &Payload) -> Result<Vec<u8>, String> {
+    let Payload::Sealed { ciphertext, signature, .. } = payload else {
+        return Err("message was not encrypted end to end".to_string());
+    };
+    let signature = Signature::from_slice(signature).map_err(|_| "malformed signature")?;
+    verify(sender, &[aad, ciphertext].concat(), &signature).map_err(|_| "signature does not match the sender's identity key")?;
+    open_chunk(ciphertext, key, aad).map_err(|_| "decryption failed".to_string())
+}
+
+fn key_id(payload: &Payload) -> Result<u32, String> {
+    match payload {
+        Payload::Sealed { key_id, .. } => Ok(*key_id),
+        Payload::Plain(_) => Err("message was not encrypted end to end".to_string()),
+    }
+}
+
+fn text(plaintext: Vec<u8>) -> Result<String, String> {
+    String::from_utf8(plaintext).map_err(|_| "message is not valid UTF-8".to_string())
+}
+
+/// Associated data binding a ciphertext to what it is for: `kind`, then
+/// `parts` such as keys, room name and key ID, each length-prefixed.
+fn context(kind: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let mut aad = E2E_LABEL.to_vec();
+    for part in [kind].iter().chain(parts) {
+        aad.extend_from_slice(&(part.len() as u32).to_be_bytes());
+        aad.extend_from_slice(part);
+    }
+    aad
+}
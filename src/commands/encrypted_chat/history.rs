@@ -0,0 +1,324 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
+
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::Mutex,
+};
+use tracing::warn;
+
+use super::identity::{config_dir, load_or_create_secret};
+use super::protocol::{Envelope, Payload, ServerMessage};
+use crate::utils::encryption::{open_chunk, seal_chunk};
+
+/// Key that encrypts history at rest, kept apart from the history itself.
+pub fn default_key_path() -> PathBuf {
+    config_dir().join("chat_history_key")
+}
+
+/// One line of a room's history file. Only the ID, time and lock tag are in the clear.
+#[derive(Serialize, Deserialize)]
+struct Record {
+    id: u64,
+    timestamp: i64,
+    /// [`History::lock_tag`] of the room's password when the message was sent; base64.
+    lock: String,
+    /// The bincode [`ServerMessage`], sealed with the history key and bound
+    /// to the room, the record's offset in the file and its lock tag; base64.
+    sealed: String,
+}
+
+/// Where a record is in its room's file, and which lock it was sent under.
+struct Line {
+    offset: u64,
+    len: usize,
+    lock: [u8; 16],
+}
+
+/// Every record of one room's file, found by reading it once.
+struct Index {
+    lines: Vec<Line>,
+    /// Length of the file, where the next record goes.
+    end: u64,
+    /// The file ends in a line cut short, which the next record must not run on from.
+    torn: bool,
+}
+
+/// Append-only chat history, one JSON Lines file per room, each message
+/// encrypted with the server's history key.
+///
+/// A message is only served to a room opened with the same password it was
+/// sent under, so reopening a formerly locked room without its password
+/// doesn't reveal what was said in it.
+pub struct History {
+    dir: PathBuf,
+    key: [u8; 32],
+    /// Index of each room's file, built on first use; also serializes appends.
+    rooms: Mutex<HashMap<String, Index>>,
+}
+
+impl History {
+    /// Keep history under `dir`, encrypted with the key at `key_path` (created on first use).
+    pub fn open(dir: PathBuf, key_path: &Path) -> Result<History, Box<dyn Error + Send + Sync>> {
+        std::fs::create_dir_all(&dir)?;
+        let (key, created) = load_or_create_secret(key_path)?;
+        if created {
+            eprintln!("Created history key '{}'", key_path.display());
+        }
+        Ok(History { dir, key, rooms: Mutex::new(HashMap::new()) })
+    }
+
+    /// `#dev` is kept in `dev.jsonl`; bytes that aren't safe in a file name are %-escaped.
+    fn path(&self, room: &str) -> PathBuf {
+        let mut name = String::new();
+        for byte in room.trim_start_matches('#').bytes() {
+            if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+                name.push(byte as char);
+            } else {
+                name.push_str(&format!("%{:02X}", byte));
+            }
+        }
+        self.dir.join(format!("{}.jsonl", name))
+    }
+
+    /// Stands for `room`'s password hash in the clear; keyed with the history
+    /// key so it can't be used to guess the password.
+    fn lock_tag(&self, room: &str, lock: Option<&[u8; 32]>) -> [u8; 16] {
+        let digest = Sha256::new()
+            .chain_update(self.key)
+            .chain_update(b"room lock")
+            .chain_update((room.len() as u32).to_be_bytes())
+            .chain_update(room)
+            .chain_update(lock.map_or(&[][..], |l| &l[..]))
+            .finalize();
+        digest[..16].try_into().unwrap()
+    }
+
+    fn aad(room: &str, offset: u64, lock: &[u8; 16]) -> Vec<u8> {
+        [&(room.len() as u32).to_be_bytes(), room.as_bytes(), &offset.to_be_bytes(), lock].concat()
+    }
+
+    /// Read `room`'s file once to find where each record is.
+    async fn index(&self, room: &str) -> Result<Index, Box<dyn Error + Send + Sync>> {
+        let path = self.path(room);
+        let contents = match fs::read(&path).await {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut index = Index { lines: Vec::new(), end: contents.len() as u64, torn: contents.last().is_some_and(|&b| b != b'\n') };
+        let mut offset = 0;
+        for (number, line) in contents.split_inclusive(|&b| b == b'\n').enumerate() {
+            let lock = serde_json::from_slice::<Record>(line)
+                .map_err(|e| e.to_string())
+                .and_then(|r| base64::engine::general_purpose::STANDARD.decode(r.lock).map_err(|e| e.to_string()))
+                .and_then(|l| <[u8; 16]>::try_from(l).map_err(|_| "malformed lock tag".to_string()));
+            match lock {
+                Ok(lock) => index.lines.push(Line { offset, len: line.len(), lock }),
+                Err(e) => warn!(" Skipping '{}' line {}: {}", path.display(), number + 1, e),
+            }
+            offset += line.len() as u64;
+        }
+        Ok(index)
+    }
+
+    /// Record `envelope`, sent in `room` while it was locked with password hash `lock`.
+    pub async fn append(&self, room: &str, lock: Option<&[u8; 32]>, envelope: &Envelope) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut rooms = self.rooms.lock().await;
+        if !rooms.contains_key(room) {
+            rooms.insert(room.to_string(), self.index(room).await?);
+        }
+        let index = rooms.get_mut(room).expect("index was just built");
+
+        let lock = self.lock_tag(room, lock);
+        let separator = if index.torn { "\n" } else { "" };
+        let offset = index.end + separator.len() as u64;
+        let sealed = seal_chunk(&bincode::serialize(&envelope.body)?, &self.key, &History::aad(room, offset, &lock))?;
+        let record = Record {
+            id: envelope.id,
+            timestamp: envelope.timestamp,
+            lock: base64::engine::general_purpose::STANDARD.encode(lock),
+            sealed: base64::engine::general_purpose::STANDARD.encode(sealed),
+        };
+        let line = format!("{}{}\n", separator, serde_json::to_string(&record)?);
+
+        let mut options = fs::OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let written = match options.open(self.path(room)).await {
+            // tokio finishes the write in the background; flushing waits for it.
+            Ok(mut file) => match file.write_all(line.as_bytes()).await {
+                Ok(()) => file.flush().await,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            // Part of the line may have made it to the file; index it afresh next time.
+            rooms.remove(room);
+            return Err(e.into());
+        }
+        index.lines.push(Line { offset, len: line.len() - separator.len(), lock });
+        index.end += line.len() as u64;
+        index.torn = false;
+        Ok(())
+    }
+
+    /// Offsets of `room`'s records sent under password hash `lock`, oldest first.
+    async fn visible(&self, room: &str, lock: Option<&[u8; 32]>) -> Result<Vec<(u64, usize)>, Box<dyn Error + Send + Sync>> {
+        let mut rooms = self.rooms.lock().await;
+        if !rooms.contains_key(room) {
+            rooms.insert(room.to_string(), self.index(room).await?);
+        }
+        let lock = self.lock_tag(room, lock);
+        Ok(rooms[room].lines.iter().filter(|l| l.lock == lock).map(|l| (l.offset, l.len)).collect())
+    }
+
+    /// Read and decrypt the records at `lines`, in the order given, until
+    /// `keep` has accepted `limit` of them.
+    async fn read(
+        &self,
+        room: &str,
+        lines: impl Iterator<Item = (u64, usize)>,
+        limit: usize,
+        keep: impl Fn(&Envelope) -> bool,
+    ) -> Result<Vec<Envelope>, Box<dyn Error + Send + Sync>> {
+        let path = self.path(room);
+        let mut file = None;
+        let mut messages = Vec::new();
+        for (offset, len) in lines {
+            if messages.len() == limit {
+                break;
+            }
+            let file = match &mut file {
+                Some(file) => file,
+                None => file.insert(fs::File::open(&path).await?),
+            };
+            let mut line = vec![0u8; len];
+            file.seek(SeekFrom::Start(offset)).await?;
+            file.read_exact(&mut line).await?;
+            match self.decode(room, offset, &line) {
+                Ok(envelope) if keep(&envelope) => messages.push(envelope),
+                Ok(_) => {}
+                Err(e) => warn!(" Skipping the record at byte {} of '{}': {}", offset, path.display(), e),
+            }
+        }
+        Ok(messages)
+    }
+
+    fn decode(&self, room: &str, offset: u64, line: &[u8]) -> Result<Envelope, Box<dyn Error + Send + Sync>> {
+        let record: Record = serde_json::from_slice(line)?;
+        let lock: [u8; 16] = base64::engine::general_purpose::STANDARD.decode(&record.lock)?.try_into().map_err(|_| "malformed lock tag")?;
+        let sealed = base64::engine::general_purpose::STANDARD.decode(&record.sealed)?;
+        let body = bincode::deserialize(&open_chunk(&sealed, &self.key, &History::aad(room, offset, &lock))?)?;
+        Ok(Envelope { id: record.id, timestamp: record.timestamp, body })
+    }
+
+    /// The last `count` messages of `room` that its current password `lock` may see, oldest first.
+    pub async fn recent(&self, room: &str, lock: Option<&[u8; 32]>, count: usize) -> Result<Vec<Envelope>, Box<dyn Error + Send + Sync>> {
+        let lines = self.visible(room, lock).await?;
+        let mut messages = self.read(room, lines.into_iter().rev(), count, |_| true).await?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    /// Up to `limit` of the newest messages in `room` containing `text`, ignoring
+    /// case, that its current password `lock` may see; oldest first.
+    pub async fn search(
+        &self,
+        room: &str,
+        lock: Option<&[u8; 32]>,
+        text: &str,
+        limit: usize,
+    ) -> Result<Vec<Envelope>, Box<dyn Error + Send + Sync>> {
+        let wanted = text.to_lowercase();
+        let lines = self.visible(room, lock).await?;
+        let mut matches = self
+            .read(room, lines.into_iter().rev(), limit, |m| {
+                matches!(&m.body, ServerMessage::Chat { payload: Payload::Plain(text), .. } if text.to_lowercase().contains(&wanted))
+            })
+            .await?;
+        matches.reverse();
+        Ok(matches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(id: u64, room: &str, text: &str) -> Envelope {
+        let payload = Payload::Plain(text.to_string());
+        Envelope { id, timestamp: id as i64, body: ServerMessage::Chat { room: room.to_string(), sender: 1, username: "alice".to_string(), payload } }
+    }
+
+    fn texts(messages: &[Envelope]) -> Vec<&str> {
+        messages
+            .iter()
+            .map(|m| match &m.body {
+                ServerMessage::Chat { payload: Payload::Plain(text), .. } => text.as_str(),
+                _ => panic!("not a chat message"),
+            })
+            .collect()
+    }
+
+    fn open(dir: &tempfile::TempDir) -> History {
+        History::open(dir.path().join("history"), &dir.path().join("key")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn recent_and_search_survive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = open(&dir);
+        for (id, text) in ["one", "two", "Three", "four"].into_iter().enumerate() {
+            history.append("#dev", None, &chat(id as u64, "#dev", text)).await.unwrap();
+        }
+        assert_eq!(texts(&history.recent("#dev", None, 2).await.unwrap()), ["Three", "four"]);
+
+        let history = open(&dir);
+        history.append("#dev", None, &chat(9, "#dev", "five")).await.unwrap();
+        assert_eq!(texts(&history.recent("#dev", None, 10).await.unwrap()), ["one", "two", "Three", "four", "five"]);
+        assert_eq!(texts(&history.search("#dev", None, "t", 2).await.unwrap()), ["two", "Three"]);
+        assert!(history.recent("#other", None, 10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn locked_history_needs_the_same_password() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = open(&dir);
+        let password = [7u8; 32];
+        history.append("#ops", Some(&password), &chat(1, "#ops", "secret")).await.unwrap();
+        history.append("#ops", None, &chat(2, "#ops", "public")).await.unwrap();
+
+        assert_eq!(texts(&history.recent("#ops", Some(&password), 10).await.unwrap()), ["secret"]);
+        assert_eq!(texts(&history.recent("#ops", None, 10).await.unwrap()), ["public"]);
+        assert!(history.search("#ops", Some(&[8u8; 32]), "e", 10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn moved_records_and_torn_lines_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = open(&dir);
+        history.append("#dev", None, &chat(1, "#dev", "first")).await.unwrap();
+        history.append("#dev", None, &chat(2, "#dev", "second")).await.unwrap();
+
+        // Swap the two records and cut a third short, as a crash mid-append would.
+        let path = history.path("#dev");
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        std::fs::write(&path, format!("{}\n{}\n{}", lines[1], lines[0], &lines[0][..20])).unwrap();
+
+        let history = open(&dir);
+        history.append("#dev", None, &chat(3, "#dev", "third")).await.unwrap();
+        assert_eq!(texts(&history.recent("#dev", None, 10).await.unwrap()), ["third"]);
+    }
+}
@@ -0,0 +1,171 @@
+use std::{
+    error::Error,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use base64::Engine;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+/// Overrides the directory holding identity keys and `known_hosts` (default `~/.nettool`).
+pub const HOME_ENV: &str = "NETTOOL_HOME";
+
+/// Directory for chat keys and trust records.
+pub fn config_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os(HOME_ENV) {
+        return PathBuf::from(dir);
+    }
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")).unwrap_or_default();
+    PathBuf::from(home).join(".nettool")
+}
+
+/// Default identity key file for a chat client or server.
+pub fn default_identity_path(server: bool) -> PathBuf {
+    config_dir().join(if server { "chat_server_ed25519" } else { "chat_client_ed25519" })
+}
+
+/// `SHA256:<base64>` fingerprint of a public key, in the style of `ssh-keygen -l`.
+pub fn fingerprint(key: &VerifyingKey) -> String {
+    let digest = Sha256::digest(key.as_bytes());
+    format!("SHA256:{}", base64::engine::general_purpose::STANDARD_NO_PAD.encode(digest))
+}
+
+/// A long-term Ed25519 key pair that signs the chat handshake.
+pub struct Identity {
+    signing: SigningKey,
+}
+
+impl Identity {
+    /// Load the key stored at `path`, or generate one and save it there (readable only by its owner).
+    ///
+    /// Returns the identity and whether it was just created.
+    pub fn load_or_create(path: &Path) -> Result<(Identity, bool), Box<dyn Error + Send + Sync>> {
+        let (seed, created) = load_or_create_secret(path)?;
+        Ok((Identity { signing: SigningKey::from_bytes(&seed) }, created))
+    }
+
+    pub fn public(&self) -> VerifyingKey {
+        self.signing.verifying_key()
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        self.signing.sign(message)
+    }
+
+    /// X25519 agreement with `peer`, using the Curve25519 form of both Ed25519 keys.
+    pub fn agree(&self, peer: &VerifyingKey) -> [u8; 32] {
+        peer.to_montgomery().mul_clamped(self.signing.to_scalar_bytes()).to_bytes()
+    }
+}
+
+/// Load the 32-byte secret stored in hex at `path`, or generate one and save
+/// it there (readable only by its owner). Returns the secret and whether it was just created.
+pub fn load_or_create_secret(path: &Path) -> Result<([u8; 32], bool), Box<dyn Error + Send + Sync>> {
+    if path.exists() {
+        let contents = fs::read_to_string(path)?;
+        let secret = from_hex(contents.trim())
+            .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+            .ok_or_else(|| format!("'{}' is not a valid key file", path.display()))?;
+        return Ok((secret, false));
+    }
+
+    let mut secret = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut secret);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    writeln!(file, "{}", to_hex(&secret))?;
+    Ok((secret, true))
+}
+
+/// Check that `signature` over `message` was made by `key`.
+pub fn verify(key: &VerifyingKey, message: &[u8], signature: &Signature) -> Result<(), Box<dyn Error + Send + Sync>> {
+    key.verify(message, signature).map_err(|_| "peer's handshake signature is invalid".into())
+}
+
+/// Result of looking a key up in `known_hosts` or `known_peers`.
+pub enum Trust {
+    /// The key matches the one recorded for this name.
+    Known,
+    /// First time this name was seen; its key has now been recorded.
+    New,
+    /// The name comes with a different key than last time.
+    Changed { recorded: VerifyingKey },
+}
+
+/// Trust-on-first-use record of keys, one `name ed25519 <hex key>` line per
+/// entry: `host:port` for servers, `username@host:port` for other users.
+pub struct KnownHosts {
+    path: PathBuf,
+}
+
+impl KnownHosts {
+    pub fn new(path: PathBuf) -> KnownHosts {
+        KnownHosts { path }
+    }
+
+    /// The default store, `known_hosts` in the [`config_dir`].
+    pub fn default_store() -> KnownHosts {
+        KnownHosts::new(config_dir().join("known_hosts"))
+    }
+
+    /// The store of other users' identity keys, `known_peers` in the [`config_dir`].
+    pub fn peer_store() -> KnownHosts {
+        KnownHosts::new(config_dir().join("known_peers"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Compare `key` with the one recorded for `host`, recording it if there is none.
+    pub fn check(&self, host: &str, key: &VerifyingKey) -> Result<Trust, Box<dyn Error + Send + Sync>> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        for line in contents.lines() {
+            let mut fields = line.split_whitespace();
+            let (Some(name), Some("ed25519"), Some(hex)) = (fields.next(), fields.next(), fields.next()) else { continue };
+            if name != host {
+                continue;
+            }
+            let recorded = from_hex(hex)
+                .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+                .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+                .ok_or_else(|| format!("corrupt entry for {} in '{}'", host, self.path.display()))?;
+            return Ok(if recorded == *key { Trust::Known } else { Trust::Changed { recorded } });
+        }
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{} ed25519 {}", host, to_hex(key.as_bytes()))?;
+        Ok(Trust::New)
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
@@ -0,0 +1,779 @@
+mod accounts;
+mod client;
+mod e2e;
+mod history;
+mod identity;
+mod offline;
+mod protocol;
+mod rooms;
+mod tui;
+
+pub use client::chat_client;
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{
+        Mutex,
+        mpsc::{self, error::TrySendError},
+    },
+};
+use tokio_util::sync::CancellationToken;
+use chrono::Utc;
+use std::{
+    collections::HashMap,
+    error::Error,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use ed25519_dalek::{Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+use tracing::{info, error, warn};
+
+use crate::utils::encryption::{open_chunk, seal_chunk};
+use crate::utils::networking;
+use accounts::{Accounts, fold};
+use history::History;
+use identity::{Identity, fingerprint};
+use offline::Mailbox;
+use protocol::{ClientMessage, Envelope, Login, Member, Payload, ServerMessage, UserId, read_message, write_message};
+use rooms::{LOBBY, Rooms, room_name};
+
+const MAX_USERNAME_LEN: usize = 32;
+/// Wrong passwords one connection may send before it is closed.
+const MAX_PASSWORD_ATTEMPTS: u32 = 3;
+/// Pause after each wrong password, to slow down guessing.
+const WRONG_PASSWORD_DELAY: Duration = Duration::from_secs(1);
+/// Envelopes waiting for a client before it is disconnected for not keeping up.
+const OUTBOX_LEN: usize = 256;
+/// Longest chat message the server relays, in characters.
+const MAX_MESSAGE_LEN: usize = 4096;
+/// Most messages one `/history` request returns.
+const MAX_HISTORY: usize = 500;
+/// Most matches one `/search` request returns.
+const MAX_SEARCH_RESULTS: usize = 50;
+/// Longest end-to-end ciphertext the server relays: four UTF-8 bytes per
+/// character, plus room for the nonce and tag.
+const MAX_SEALED_LEN: usize = MAX_MESSAGE_LEN * 4 + 64;
+/// Domain separation for everything derived from a chat handshake.
+const HANDSHAKE_LABEL: &[u8] = b"nettool chat handshake v1";
+/// Associated data of every session frame, so they can't be passed off as any other sealed data.
+const FRAME_LABEL: &[u8] = b"nettool chat frame v1";
+
+#[derive(Clone)]
+struct Client {
+    id: UserId,
+    username: String,
+    identity: VerifyingKey,
+    /// Drained by the client's own writer task, so a slow reader never holds anyone else up.
+    outbox: mpsc::Sender<Envelope>,
+    /// Cancelled to disconnect the client.
+    kicked: CancellationToken,
+}
+
+/// Which end of the handshake we are. Each end signs under its own label,
+/// so a signature can never be reflected back at its author.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Client,
+    Server,
+}
+
+impl Role {
+    fn label(self) -> &'static [u8] {
+        match self {
+            Role::Client => b"client",
+            Role::Server => b"server",
+        }
+    }
+
+    fn peer(self) -> Role {
+        match self {
+            Role::Client => Role::Server,
+            Role::Server => Role::Client,
+        }
+    }
+}
+
+/// Key material of an authenticated connection.
+struct Session {
+    key: [u8; 32],
+    /// The other end's long-term identity key.
+    peer: VerifyingKey,
+    /// Short authentication string; both ends see the same one unless someone is in the middle.
+    code: String,
+}
+
+/// Ephemeral X25519 exchange, authenticated by both ends signing the
+/// transcript of ephemeral keys with their long-term Ed25519 identity.
+async fn perform_key_exchange(stream: &mut TcpStream, identity: &Identity, role: Role) -> Result<Session, Box<dyn Error + Send + Sync>> {
+    let private = EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+    let public = PublicKey::from(&private);
+
+    stream.write_all(public.as_bytes()).await?;
+    let mut peer_key = [0u8; 32];
+    stream.read_exact(&mut peer_key).await?;
+
+    let (client_ephemeral, server_ephemeral) = match role {
+        Role::Client => (*public.as_bytes(), peer_key),
+        Role::Server => (peer_key, *public.as_bytes()),
+    };
+    let transcript: [u8; 32] = Sha256::new()
+        .chain_update(HANDSHAKE_LABEL)
+        .chain_update(client_ephemeral)
+        .chain_update(server_ephemeral)
+        .finalize()
+        .into();
+
+    let signature = identity.sign(&[role.label(), &transcript].concat());
+    stream.write_all(identity.public().as_bytes()).await?;
+    stream.write_all(&signature.to_bytes()).await?;
+
+    let mut peer_identity = [0u8; 32];
+    stream.read_exact(&mut peer_identity).await?;
+    let mut peer_signature = [0u8; 64];
+    stream.read_exact(&mut peer_signature).await?;
+    let peer = VerifyingKey::from_bytes(&peer_identity).map_err(|_| "peer sent an invalid identity key")?;
+    identity::verify(&peer, &[role.peer().label(), &transcript].concat(), &Signature::from_bytes(&peer_signature))?;
+
+    let shared_secret = private.diffie_hellman(&PublicKey::from(peer_key));
+    if !shared_secret.was_contributory() {
+        return Err("peer sent a degenerate key".into());
+    }
+    let key = Sha256::new()
+        .chain_update(HANDSHAKE_LABEL)
+        .chain_update(b"key")
+        .chain_update(shared_secret.as_bytes())
+        .chain_update(transcript)
+        .finalize()
+        .into();
+
+    let (client_identity, server_identity) = match role {
+        Role::Client => (identity.public(), peer),
+        Role::Server => (peer, identity.public()),
+    };
+    let code = Sha256::new()
+        .chain_update(HANDSHAKE_LABEL)
+        .chain_update(b"code")
+        .chain_update(transcript)
+        .chain_update(client_identity.as_bytes())
+        .chain_update(server_identity.as_bytes())
+        .finalize();
+    let code = u64::from_be_bytes(code[..8].try_into().unwrap()) % 1_000_000_000_000_000;
+    let code = format!("{:05} {:05} {:05}", code / 10_000_000_000, code / 100_000 % 100_000, code % 100_000);
+
+    Ok(Session { key, peer, code })
+}
+
+/// Load the identity at `path` (or the default one for this end), creating it on first use.
+fn load_identity(path: Option<&Path>, server: bool) -> Result<Identity, Box<dyn Error + Send + Sync>> {
+    let path = path.map_or_else(|| identity::default_identity_path(server), Path::to_path_buf);
+    let (identity, created) = Identity::load_or_create(&path)?;
+    if created {
+        eprintln!("Created identity key '{}'", path.display());
+    }
+    Ok(identity)
+}
+
+/// Seal `plaintext` with `key` and write it as one length-prefixed frame.
+async fn write_frame<W>(writer: &mut W, key: &[u8], plaintext: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    W: AsyncWrite + Unpin,
+{
+    networking::write_frame(writer, &seal_chunk(plaintext, key, FRAME_LABEL)?).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Read one frame and open it with `key`. Returns `None` on a clean end of stream.
+///
+/// Anything that isn't a frame sealed under `key`, such as a plaintext line
+/// or a tampered frame, fails here rather than being passed on.
+async fn read_frame<R>(reader: &mut R, key: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>>
+where
+    R: AsyncRead + Unpin,
+{
+    match networking::read_frame(reader).await? {
+        Some(frame) => Ok(Some(open_chunk(&frame, key, FRAME_LABEL).map_err(|_| "received a frame that is not encrypted for this session")?)),
+        None => Ok(None),
+    }
+}
+
+/// Why `username` can't be used, if it can't.
+fn check_username(username: &str) -> Result<(), String> {
+    if username.is_empty() {
+        return Err("username must not be empty".to_string());
+    }
+    if username.chars().count() > MAX_USERNAME_LEN {
+        return Err(format!("username must be at most {} characters", MAX_USERNAME_LEN));
+    }
+    if username.chars().any(|c| c.is_control() || c.is_whitespace() || c == ':') {
+        return Err("username must not contain spaces, colons or control characters".to_string());
+    }
+    Ok(())
+}
+
+/// Trim a chat message and strip its control characters, so it can't carry
+/// terminal escape sequences, or say why it can't be sent. An `e2e` server
+/// takes only sealed messages, and can check little more than their size.
+fn check_message(payload: Payload, e2e: bool) -> Result<Payload, String> {
+    match payload {
+        Payload::Plain(_) if e2e => Err("this server only relays end-to-end encrypted messages".to_string()),
+        Payload::Plain(text) => {
+            let text: String = text.chars().filter(|c| !c.is_control()).collect();
+            let text = text.trim();
+            if text.is_empty() || text.chars().count() > MAX_MESSAGE_LEN {
+                return Err(format!("messages must be 1 to {} characters", MAX_MESSAGE_LEN));
+            }
+            Ok(Payload::Plain(text.to_string()))
+        }
+        Payload::Sealed { .. } if !e2e => Err("this server does not relay end-to-end encrypted messages".to_string()),
+        Payload::Sealed { ciphertext, signature, .. } if ciphertext.len() > MAX_SEALED_LEN || signature.len() != Signature::BYTE_SIZE => {
+            Err(format!("encrypted messages must be at most {} bytes and signed", MAX_SEALED_LEN))
+        }
+        sealed => Ok(sealed),
+    }
+}
+
+/// What became of a direct message.
+enum Delivery {
+    /// Delivered at once, in envelope `u64`.
+    Sent(u64),
+    /// Held for `to`, who is offline.
+    Queued { message: u64, to: String },
+}
+
+/// Why a client can't have the username it asked for.
+enum Refusal {
+    /// The name is registered and no password came with the request.
+    PasswordRequired,
+    /// The name is registered and the password is wrong.
+    WrongPassword(String),
+    Reason(String),
+}
+
+/// Count a wrong password on a connection and wait out the delay; `false`
+/// once the connection has used up its attempts and must be closed.
+async fn wrong_password(failures: &mut u32) -> bool {
+    *failures += 1;
+    tokio::time::sleep(WRONG_PASSWORD_DELAY).await;
+    *failures < MAX_PASSWORD_ATTEMPTS
+}
+
+/// Ask for a username until the client sends one it may have, then admit it as `user_id`.
+async fn login_client(stream: &mut TcpStream, key: &[u8], server: &Server, user_id: UserId) -> Result<String, Box<dyn Error + Send + Sync>> {
+    write_message(stream, key, &Login::Prompt).await?;
+    let mut failures = 0;
+    loop {
+        let Some(Login::Request { username, password }) = read_message(stream, key).await? else {
+            return Err("expected a login request".into());
+        };
+        let reply = match server.claim(user_id, &username, password).await {
+            Ok(()) => Login::Accepted { user_id, e2e: server.options.e2e },
+            Err(Refusal::PasswordRequired) => Login::PasswordRequired { username: username.clone() },
+            Err(Refusal::WrongPassword(reason)) => {
+                if !wrong_password(&mut failures).await {
+                    let reason = "too many wrong passwords".to_string();
+                    write_message(stream, key, &Login::Rejected { reason: reason.clone() }).await?;
+                    return Err(reason.into());
+                }
+                Login::Rejected { reason }
+            }
+            Err(Refusal::Reason(reason)) => Login::Rejected { reason },
+        };
+        let accepted = matches!(reply, Login::Accepted { .. });
+        if let Err(e) = write_message(stream, key, &reply).await {
+            if accepted {
+                server.release(&username).await;
+            }
+            return Err(e);
+        }
+        if accepted {
+            return Ok(username);
+        }
+    }
+}
+
+impl Client {
+    fn member(&self) -> Member {
+        Member { id: self.id, username: self.username.clone(), key: self.identity.to_bytes() }
+    }
+
+    /// Queue `envelope` for the client without waiting, disconnecting it if its queue is full.
+    fn send(&self, envelope: &Envelope) {
+        if let Err(TrySendError::Full(_)) = self.outbox.try_send(envelope.clone())
+            && !self.kicked.is_cancelled()
+        {
+            warn!(" Disconnecting {}: it stopped reading its messages", self.username);
+            self.kicked.cancel();
+        }
+    }
+}
+
+/// State shared by every connection to the chat server.
+struct Server {
+    options: ChatServerOptions,
+    accounts: Option<Accounts>,
+    history: Option<History>,
+    rooms: Mutex<Rooms>,
+    /// Who holds each username, by its [`fold`]ed form.
+    names: Mutex<HashMap<String, UserId>>,
+    /// Whoever last logged in under each username, by its [`fold`]ed form;
+    /// direct messages for them are queued while they are away.
+    seen: Mutex<HashMap<String, Member>>,
+    mailbox: Mutex<Mailbox>,
+    next_user: AtomicU64,
+    /// Starts at the time in microseconds, so message IDs keep growing across
+    /// restarts instead of repeating those already in the history.
+    next_message: AtomicU64,
+}
+
+impl Server {
+    fn envelope(&self, body: ServerMessage) -> Envelope {
+        Envelope {
+            id: self.next_message.fetch_add(1, Ordering::SeqCst),
+            timestamp: Utc::now().timestamp_millis(),
+            body,
+        }
+    }
+
+    /// Let `user` go by `username`, if nobody else does and it knows the
+    /// password of a registered name.
+    async fn claim(&self, user: UserId, username: &str, password: Option<String>) -> Result<(), Refusal> {
+        check_username(username).map_err(Refusal::Reason)?;
+        if let Some(accounts) = &self.accounts
+            && accounts.is_registered(username)
+        {
+            let Some(password) = password else { return Err(Refusal::PasswordRequired) };
+            if !accounts.verify(username, password).await {
+                return Err(Refusal::WrongPassword(format!("wrong password for {}", username)));
+            }
+        }
+        let mut names = self.names.lock().await;
+        match names.get(&fold(username)) {
+            Some(&holder) if holder != user => Err(Refusal::Reason(format!("{} is already taken", username))),
+            _ => {
+                names.insert(fold(username), user);
+                Ok(())
+            }
+        }
+    }
+
+    async fn release(&self, username: &str) {
+        self.names.lock().await.remove(&fold(username));
+    }
+
+    /// Send `body` to everyone in `room` and return the envelope it went out in.
+    async fn broadcast(&self, room: &str, body: ServerMessage) -> Envelope {
+        // Stamped and queued under the lock, so every member sees envelopes in ID
+        // order; queueing never waits on a member's connection.
+        let rooms = self.rooms.lock().await;
+        let envelope = self.envelope(body);
+        for client in rooms.members(room) {
+            client.send(&envelope);
+        }
+        envelope
+    }
+
+    /// The user called `username` if it is online, or else whoever had the
+    /// name last, if messages can be left for them.
+    async fn lookup(&self, username: &str) -> Option<Member> {
+        if let Some(online) = self.rooms.lock().await.find(username) {
+            return Some(online.member());
+        }
+        if self.options.offline_limit == 0 {
+            return None;
+        }
+        self.seen.lock().await.get(&fold(username)).cloned()
+    }
+
+    /// Send `payload` from `sender` to the user called `to` alone, echoing it
+    /// back to `sender`. If `to` is offline, hold it for their next login.
+    async fn direct(&self, sender: &Client, to: &str, payload: Payload) -> Result<Delivery, String> {
+        let rooms = self.rooms.lock().await;
+        if let Some(recipient) = rooms.find(to) {
+            let envelope = self.envelope(ServerMessage::Direct { from: sender.member(), to: recipient.member(), payload });
+            recipient.send(&envelope);
+            if recipient.id != sender.id {
+                sender.send(&envelope);
+            }
+            return Ok(Delivery::Sent(envelope.id));
+        }
+        drop(rooms);
+
+        let recipient = self.lookup(to).await.ok_or_else(|| format!("{} is not online", to))?;
+        let username = recipient.username.clone();
+        let envelope = self.envelope(ServerMessage::Direct { from: sender.member(), to: recipient, payload });
+        self.mailbox.lock().await.push(&username, envelope.clone())?;
+        sender.send(&envelope);
+        Ok(Delivery::Queued { message: envelope.id, to: username })
+    }
+
+    /// Pass `sender`'s sealed key for `room` on to member `to`, if it is still there.
+    async fn relay_room_key(&self, sender: &Client, room: &str, to: UserId, payload: Payload) {
+        let rooms = self.rooms.lock().await;
+        if let Some(recipient) = rooms.members(room).iter().find(|c| c.id == to) {
+            recipient.send(&self.envelope(ServerMessage::RoomKey { room: room.to_string(), sender: sender.id, payload }));
+        }
+    }
+
+    /// Tell `client` who else is in `room`, and tell the room it arrived.
+    async fn announce_join(&self, client: &Client, room: &str) {
+        let (members, lock) = {
+            let rooms = self.rooms.lock().await;
+            (rooms.roster(room), rooms.lock(room))
+        };
+        client.send(&self.envelope(ServerMessage::Joined { room: room.to_string(), members }));
+        if let Some(history) = &self.history
+            && self.options.history_on_join > 0
+        {
+            match history.recent(room, lock.as_ref(), self.options.history_on_join).await {
+                Ok(messages) if !messages.is_empty() => {
+                    client.send(&self.envelope(ServerMessage::History { room: room.to_string(), query: None, messages }));
+                }
+                Ok(_) => {}
+                Err(e) => warn!(" Can't read the history of {}: {}", room, e),
+            }
+        }
+        let join = ServerMessage::Join { room: room.to_string(), member: client.member() };
+        self.broadcast(room, join).await;
+    }
+
+    /// Move `client` from room `from` to room `to`, unless `to` is locked against it.
+    async fn change_room(&self, client: &Client, from: &str, to: &str, password: Option<&str>) -> Result<(), String> {
+        {
+            let mut rooms = self.rooms.lock().await;
+            rooms.enter(to, client.clone(), password)?;
+            rooms.leave(from, client.id);
+        }
+        let leave = ServerMessage::Leave { room: from.to_string(), user: client.id, username: client.username.clone() };
+        self.broadcast(from, leave).await;
+        self.announce_join(client, to).await;
+        Ok(())
+    }
+}
+
+/// How a chat server treats its users and the messages it relays.
+#[derive(Debug, Clone)]
+pub struct ChatServerOptions {
+    /// Relay only messages the clients encrypted end to end, so the server never sees their text.
+    pub e2e: bool,
+    /// File of registered usernames and password hashes; without one, nobody can register.
+    pub accounts: Option<PathBuf>,
+    /// Directory to keep each room's messages in, encrypted with the key in
+    /// `~/.nettool/chat_history_key`; without one, nothing is kept.
+    pub history: Option<PathBuf>,
+    /// How many recent messages a client is sent on joining a room.
+    pub history_on_join: usize,
+    /// How long direct messages for an offline user are kept.
+    pub offline_retention: Duration,
+    /// How many direct messages each offline user can have waiting; 0 turns queueing off.
+    pub offline_limit: usize,
+}
+
+impl Default for ChatServerOptions {
+    fn default() -> ChatServerOptions {
+        ChatServerOptions {
+            e2e: false,
+            accounts: None,
+            history: None,
+            history_on_join: 20,
+            offline_retention: Duration::from_secs(7 * 24 * 60 * 60),
+            offline_limit: 100,
+        }
+    }
+}
+
+/// Run the chat server, authenticating to clients with the identity key at `identity_path`.
+pub async fn chat_server(port: u16, identity_path: Option<&Path>, options: ChatServerOptions) -> Result<(), Box<dyn Error + Send + Sync>> {
+    tracing_subscriber::fmt::init();
+    let identity = Arc::new(load_identity(identity_path, true)?);
+    if options.e2e {
+        info!(" End-to-end mode: relaying only messages the clients encrypted");
+    }
+    let accounts = options.accounts.clone().map(Accounts::load).transpose()?;
+    let history = options.history.clone().map(|dir| History::open(dir, &history::default_key_path())).transpose()?;
+    let mailbox = Mailbox::new(options.offline_retention, options.offline_limit);
+    let server = Arc::new(Server {
+        options,
+        seen: Mutex::new(HashMap::new()),
+        mailbox: Mutex::new(mailbox),
+        accounts,
+        history,
+        names: Mutex::new(HashMap::new()),
+        rooms: Mutex::new(Rooms::default()),
+        next_user: AtomicU64::new(1),
+        next_message: AtomicU64::new(Utc::now().timestamp_micros().max(1) as u64),
+    });
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    info!(" Encrypted Chat Server running on port {}", port);
+    info!(" Server key fingerprint: {}", fingerprint(&identity.public()));
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let server = Arc::clone(&server);
+        let identity = Arc::clone(&identity);
+        tokio::spawn(async move { handle_connection(stream, addr, &server, &identity).await });
+    }
+}
+
+/// Authenticate and log in one client, then relay its messages until it leaves.
+async fn handle_connection(mut stream: TcpStream, addr: SocketAddr, server: &Server, identity: &Identity) {
+    let session = match perform_key_exchange(&mut stream, identity, Role::Server).await {
+        Ok(session) => {
+            info!(" {} authenticated as {}, session code {}", addr, fingerprint(&session.peer), session.code);
+            session
+        }
+        Err(e) => {
+            error!("Key exchange failed: {}", e);
+            return;
+        }
+    };
+
+    let id = server.next_user.fetch_add(1, Ordering::SeqCst);
+    let key = session.key;
+    let mut username = match login_client(&mut stream, &key, server, id).await {
+        Ok(username) => username,
+        Err(e) => {
+            warn!(" Rejected {}: {}", addr, e);
+            return;
+        }
+    };
+    info!(" {} Joined from {}", username, addr);
+
+    let (r, mut w) = stream.into_split();
+    let (outbox, mut queue) = mpsc::channel::<Envelope>(OUTBOX_LEN);
+    let mut client = Client {
+        id,
+        username: username.clone(),
+        identity: session.peer,
+        outbox,
+        kicked: CancellationToken::new(),
+    };
+    let kicked = client.kicked.clone();
+    tokio::spawn(async move {
+        loop {
+            let envelope = tokio::select! {
+                envelope = queue.recv() => envelope,
+                _ = kicked.cancelled() => None,
+            };
+            let Some(envelope) = envelope else { break };
+            if let Err(e) = write_message(&mut w, &key, &envelope).await {
+                warn!(" Failed to send to {}: {}", addr, e);
+                kicked.cancel();
+                break;
+            }
+        }
+    });
+    server.seen.lock().await.insert(fold(&username), client.member());
+    let welcome = format!("Welcome, {}! /rooms lists the open rooms and /join #room moves you to one.", username);
+    client.send(&server.envelope(ServerMessage::System { text: welcome }));
+    let waiting = server.mailbox.lock().await.take(&username, client.identity.as_bytes());
+    if !waiting.is_empty() {
+        let text = format!("{} direct message(s) arrived while you were away:", waiting.len());
+        client.send(&server.envelope(ServerMessage::System { text }));
+        for envelope in &waiting {
+            client.send(envelope);
+        }
+    }
+    let mut room = LOBBY.to_string();
+    // The lobby is never locked.
+    let _ = server.rooms.lock().await.enter(&room, client.clone(), None);
+    server.announce_join(&client, &room).await;
+
+    let mut reader = BufReader::new(r);
+    // Wrong passwords given to /nick, counted like those at login.
+    let mut failures = 0;
+    loop {
+        // A client sending faster than it reads its own replies is held back
+        // until half its queue is free, rather than disconnected.
+        let room_to_reply = tokio::select! {
+            permits = client.outbox.reserve_many(OUTBOX_LEN / 2) => permits.is_ok(),
+            _ = client.kicked.cancelled() => false,
+        };
+        if !room_to_reply {
+            break;
+        }
+        let read = tokio::select! {
+            read = read_message(&mut reader, &key) => read,
+            _ = client.kicked.cancelled() => break,
+        };
+        let message = match read {
+            Ok(Some(message)) => message,
+            Ok(None) => break,
+            Err(e) => {
+                warn!(" Dropping {}: {}", username, e);
+                break;
+            }
+        };
+
+        match message {
+            ClientMessage::Chat { seq, payload } => {
+                let payload = match check_message(payload, server.options.e2e) {
+                    Ok(payload) => payload,
+                    Err(reason) => {
+                        client.send(&server.envelope(ServerMessage::Error { seq: Some(seq), reason }));
+                        continue;
+                    }
+                };
+                match &payload {
+                    Payload::Plain(text) => info!(" Broadcasting from {} in {}: {}", username, room, text),
+                    Payload::Sealed { .. } => info!(" Relaying encrypted message from {} in {}", username, room),
+                }
+                let chat = ServerMessage::Chat { room: room.clone(), sender: id, username: username.clone(), payload };
+                let sent = server.broadcast(&room, chat).await;
+                client.send(&server.envelope(ServerMessage::Ack { seq, message: sent.id }));
+                let lock = server.rooms.lock().await.lock(&room);
+                if let Some(history) = &server.history
+                    && let Err(e) = history.append(&room, lock.as_ref(), &sent).await
+                {
+                    warn!(" Can't record history of {}: {}", room, e);
+                }
+            }
+            ClientMessage::Direct { seq, to, payload } => {
+                let sent = match check_message(payload, server.options.e2e) {
+                    Ok(payload) => server.direct(&client, &to, payload).await,
+                    Err(reason) => Err(reason),
+                };
+                let reply = match sent {
+                    Ok(Delivery::Sent(message)) => {
+                        info!(" Direct message from {} to {}", username, to);
+                        ServerMessage::Ack { seq, message }
+                    }
+                    Ok(Delivery::Queued { message, to }) => {
+                        info!(" Queued direct message from {} for {}", username, to);
+                        ServerMessage::Queued { seq, message, to }
+                    }
+                    Err(reason) => ServerMessage::Error { seq: Some(seq), reason },
+                };
+                client.send(&server.envelope(reply));
+            }
+            ClientMessage::RoomKey { to, room: target, payload } => {
+                // Only between members of the room the sender is in; stale keys are dropped.
+                if target == room && matches!(payload, Payload::Sealed { .. }) {
+                    server.relay_room_key(&client, &room, to, payload).await;
+                }
+            }
+            ClientMessage::Lookup { username: wanted } => {
+                let member = server.lookup(&wanted).await;
+                client.send(&server.envelope(ServerMessage::User { username: wanted, member }));
+            }
+            ClientMessage::Join { room: requested, password } => {
+                let moved = match room_name(&requested) {
+                    Ok(target) if target == room => Err(format!("you are already in {}", room)),
+                    Ok(target) => server.change_room(&client, &room, &target, password.as_deref()).await.map(|()| target),
+                    Err(reason) => Err(reason),
+                };
+                match moved {
+                    Ok(target) => {
+                        info!(" {} moved from {} to {}", username, room, target);
+                        room = target;
+                    }
+                    Err(reason) => client.send(&server.envelope(ServerMessage::Error { seq: None, reason })),
+                }
+            }
+            ClientMessage::Part => {
+                if room == LOBBY {
+                    let reason = "you are in the lobby, which can't be left".to_string();
+                    client.send(&server.envelope(ServerMessage::Error { seq: None, reason }));
+                } else if server.change_room(&client, &room, LOBBY, None).await.is_ok() {
+                    info!(" {} left {}", username, room);
+                    room = LOBBY.to_string();
+                }
+            }
+            ClientMessage::Nick { username: wanted, password } => {
+                let refusal = if wanted == username {
+                    Some(format!("you are already {}", username))
+                } else {
+                    match server.claim(id, &wanted, password).await {
+                        Ok(()) => None,
+                        Err(Refusal::PasswordRequired) => Some(format!("{} is registered; use /nick {} <password>", wanted, wanted)),
+                        Err(Refusal::WrongPassword(reason)) => {
+                            if !wrong_password(&mut failures).await {
+                                warn!(" Dropping {}: too many wrong passwords", username);
+                                let reason = "too many wrong passwords".to_string();
+                                client.send(&server.envelope(ServerMessage::Error { seq: None, reason }));
+                                break;
+                            }
+                            Some(reason)
+                        }
+                        Err(Refusal::Reason(reason)) => Some(reason),
+                    }
+                };
+                if let Some(reason) = refusal {
+                    client.send(&server.envelope(ServerMessage::Error { seq: None, reason }));
+                    continue;
+                }
+                // A change of case only keeps the same folded name.
+                if fold(&wanted) != fold(&username) {
+                    server.release(&username).await;
+                }
+                server.rooms.lock().await.rename(id, &wanted);
+                info!(" {} is now known as {}", username, wanted);
+                client.username = wanted.clone();
+                server.seen.lock().await.insert(fold(&wanted), client.member());
+                let from = std::mem::replace(&mut username, wanted.clone());
+                server.broadcast(&room, ServerMessage::Renamed { user: id, from, to: wanted }).await;
+            }
+            ClientMessage::Register { password } => {
+                let registered = match &server.accounts {
+                    Some(accounts) => accounts.register(&username, password).await,
+                    None => Err("this server does not keep accounts".to_string()),
+                };
+                let reply = match registered {
+                    Ok(()) => {
+                        info!(" {} registered", username);
+                        let text = format!("{} is registered; logging in as {} now needs the password", username, username);
+                        ServerMessage::System { text }
+                    }
+                    Err(reason) => ServerMessage::Error { seq: None, reason },
+                };
+                client.send(&server.envelope(reply));
+            }
+            ClientMessage::History { count } => {
+                let lock = server.rooms.lock().await.lock(&room);
+                let found = match &server.history {
+                    Some(history) => history.recent(&room, lock.as_ref(), count.min(MAX_HISTORY)).await.map_err(|e| e.to_string()),
+                    None => Err("this server does not keep history".to_string()),
+                };
+                let reply = match found {
+                    Ok(messages) => ServerMessage::History { room: room.clone(), query: None, messages },
+                    Err(reason) => ServerMessage::Error { seq: None, reason },
+                };
+                client.send(&server.envelope(reply));
+            }
+            ClientMessage::Search { text } => {
+                let lock = server.rooms.lock().await.lock(&room);
+                let found = match &server.history {
+                    _ if server.options.e2e => Err("messages are end-to-end encrypted, so the server can't search them".to_string()),
+                    Some(history) => history.search(&room, lock.as_ref(), &text, MAX_SEARCH_RESULTS).await.map_err(|e| e.to_string()),
+                    None => Err("this server does not keep history".to_string()),
+                };
+                let reply = match found {
+                    Ok(messages) => ServerMessage::History { room: room.clone(), query: Some(text), messages },
+                    Err(reason) => ServerMessage::Error { seq: None, reason },
+                };
+                client.send(&server.envelope(reply));
+            }
+            ClientMessage::Rooms => {
+                let rooms = server.rooms.lock().await.list();
+                client.send(&server.envelope(ServerMessage::Rooms { rooms }));
+            }
+            ClientMessage::Who => {
+                let members = server.rooms.lock().await.roster(&room);
+                client.send(&server.envelope(ServerMessage::Members { room: room.clone(), members }));
+            }
+        }
+    }
+
+    info!(" {} Disconnected.", username);
+    server.release(&username).await;
+    server.rooms.lock().await.leave(&room, id);
+    server.broadcast(&room, ServerMessage::Leave { room: room.clone(), user: id, username }).await;
+}
@@ -0,0 +1,96 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use chrono::Utc;
+
+use super::accounts::fold;
+use super::protocol::{Envelope, ServerMessage};
+
+/// Direct messages waiting for recipients who are offline, kept in memory.
+pub struct Mailbox {
+    retention: Duration,
+    limit: usize,
+    queues: HashMap<String, VecDeque<Envelope>>,
+}
+
+impl Mailbox {
+    /// Messages are dropped after `retention`; each user has room for `limit`.
+    pub fn new(retention: Duration, limit: usize) -> Mailbox {
+        Mailbox { retention, limit, queues: HashMap::new() }
+    }
+
+    fn prune(&mut self) {
+        let cutoff = Utc::now().timestamp_millis() - self.retention.as_millis().min(i64::MAX as u128) as i64;
+        for queue in self.queues.values_mut() {
+            queue.retain(|m| m.timestamp >= cutoff);
+        }
+        self.queues.retain(|_, queue| !queue.is_empty());
+    }
+
+    /// Hold `envelope` until `username` next logs in.
+    pub fn push(&mut self, username: &str, envelope: Envelope) -> Result<(), String> {
+        self.prune();
+        let queue = self.queues.entry(fold(username)).or_default();
+        if queue.len() >= self.limit {
+            return Err(format!("{} is offline and has {} messages waiting already", username, queue.len()));
+        }
+        queue.push_back(envelope);
+        Ok(())
+    }
+
+    /// Take what is waiting for `username`, as long as it was addressed to the
+    /// same identity key; messages for anyone else who had the name stay put.
+    pub fn take(&mut self, username: &str, key: &[u8; 32]) -> Vec<Envelope> {
+        self.prune();
+        let Some(queue) = self.queues.get_mut(&fold(username)) else {
+            return Vec::new();
+        };
+        let (mine, others): (VecDeque<_>, VecDeque<_>) = queue
+            .drain(..)
+            .partition(|m| matches!(&m.body, ServerMessage::Direct { to, .. } if to.key == *key));
+        *queue = others;
+        mine.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::protocol::{Member, Payload};
+    use super::*;
+
+    fn direct(to: &str, key: [u8; 32], age: Duration) -> Envelope {
+        let from = Member { id: 1, username: "alice".to_string(), key: [1; 32] };
+        let to = Member { id: 0, username: to.to_string(), key };
+        Envelope {
+            id: 0,
+            timestamp: Utc::now().timestamp_millis() - age.as_millis() as i64,
+            body: ServerMessage::Direct { from, to, payload: Payload::Plain("hi".to_string()) },
+        }
+    }
+
+    #[test]
+    fn each_user_has_a_limited_mailbox() {
+        let mut mailbox = Mailbox::new(Duration::from_secs(60), 2);
+        mailbox.push("Bob", direct("Bob", [2; 32], Duration::ZERO)).unwrap();
+        mailbox.push("bob", direct("bob", [2; 32], Duration::ZERO)).unwrap();
+        assert!(mailbox.push("BOB", direct("BOB", [2; 32], Duration::ZERO)).is_err());
+        mailbox.push("carol", direct("carol", [3; 32], Duration::ZERO)).unwrap();
+
+        assert_eq!(mailbox.take("bob", &[2; 32]).len(), 2);
+        mailbox.push("bob", direct("bob", [2; 32], Duration::ZERO)).unwrap();
+    }
+
+    #[test]
+    fn messages_expire_and_stay_with_their_key() {
+        let mut mailbox = Mailbox::new(Duration::from_secs(60), 10);
+        mailbox.push("bob", direct("bob", [2; 32], Duration::from_secs(120))).unwrap();
+        mailbox.push("bob", direct("bob", [2; 32], Duration::ZERO)).unwrap();
+        mailbox.push("bob", direct("bob", [9; 32], Duration::ZERO)).unwrap();
+
+        assert_eq!(mailbox.take("bob", &[2; 32]).len(), 1);
+        assert!(mailbox.take("bob", &[2; 32]).is_empty());
+        assert_eq!(mailbox.take("Bob", &[9; 32]).len(), 1);
+    }
+}
@@ -0,0 +1,143 @@
+use std::error::Error;
+
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::{read_frame, write_frame};
+
+/// Identifies a connected user for as long as the server runs.
+pub type UserId = u64;
+
+/// First exchange after the handshake, before any chat traffic. Like
+/// everything else on the connection, it travels in encrypted frames.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Login {
+    /// Server: asks the client to pick a username.
+    Prompt,
+    /// Client: the username to join as, and its password if it is registered.
+    Request { username: String, password: Option<String> },
+    /// Server: the username is registered; ask again with its password.
+    PasswordRequired { username: String },
+    /// Server: the client is in, and its messages will carry `user_id`. With
+    /// `e2e` set, the server only relays [`Payload::Sealed`] text.
+    Accepted { user_id: UserId, e2e: bool },
+    /// Server: the username can't be used; the client may try again.
+    Rejected { reason: String },
+}
+
+/// A connected user as other clients see it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Member {
+    pub id: UserId,
+    pub username: String,
+    /// Ed25519 identity key the user authenticated to the server with.
+    pub key: [u8; 32],
+}
+
+/// Message text, either readable by the server or encrypted end to end.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Payload {
+    Plain(String),
+    /// Encrypted by the sending client and signed with its identity key.
+    /// `key_id` names the sender key of a room message and is 0 otherwise.
+    Sealed { key_id: u32, ciphertext: Vec<u8>, signature: Vec<u8> },
+}
+
+/// Sent by a client once logged in.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ClientMessage {
+    /// A line for everyone in the sender's room; `seq` is echoed back in the [`ServerMessage::Ack`].
+    Chat { seq: u64, payload: Payload },
+    /// A line for user `to` alone, wherever they are; acknowledged like [`ClientMessage::Chat`].
+    Direct { seq: u64, to: String, payload: Payload },
+    /// Hand the sender's key for `room` to member `to`, sealed for them alone.
+    RoomKey { to: UserId, room: String, payload: Payload },
+    /// Ask who is online as `username`, to encrypt a direct message for them.
+    Lookup { username: String },
+    /// Move to `room`, opening it (locked with `password`, if given) when it isn't open yet.
+    Join { room: String, password: Option<String> },
+    /// Leave the current room for the lobby.
+    Part,
+    /// List the open rooms.
+    Rooms,
+    /// List who is in the current room.
+    Who,
+    /// Change username; `password` is needed to take a registered one.
+    Nick { username: String, password: Option<String> },
+    /// Reserve the current username, so taking it needs `password` from now on.
+    Register { password: String },
+    /// Ask for the last `count` messages of the current room.
+    History { count: usize },
+    /// Ask for messages of the current room containing `text`.
+    Search { text: String },
+}
+
+/// One line of a [`ServerMessage::Rooms`] listing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub name: String,
+    pub members: usize,
+    pub locked: bool,
+}
+
+/// Everything the server sends after login, stamped by the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    /// Increases by one with every envelope the server sends out.
+    pub id: u64,
+    /// Server time in milliseconds since the Unix epoch.
+    pub timestamp: i64,
+    pub body: ServerMessage,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ServerMessage {
+    Chat { room: String, sender: UserId, username: String, payload: Payload },
+    Join { room: String, member: Member },
+    Leave { room: String, user: UserId, username: String },
+    /// Someone in the room changed username.
+    Renamed { user: UserId, from: String, to: String },
+    /// A private message, delivered to its recipient and echoed to its sender.
+    Direct { from: Member, to: Member, payload: Payload },
+    /// Another member's sender key for `room`, sealed for this client.
+    RoomKey { room: String, sender: UserId, payload: Payload },
+    /// Answer to [`ClientMessage::Lookup`]; `member` is `None` when nobody by
+    /// that name is online or can be left a message.
+    User { username: String, member: Option<Member> },
+    /// The client is now in `room`, along with `members` (itself included).
+    Joined { room: String, members: Vec<Member> },
+    Rooms { rooms: Vec<RoomInfo> },
+    Members { room: String, members: Vec<Member> },
+    /// Earlier chat messages of `room`, oldest first: recent ones on joining,
+    /// or those asked for by [`ClientMessage::History`] or, with `query`, [`ClientMessage::Search`].
+    History { room: String, query: Option<String>, messages: Vec<Envelope> },
+    /// Informational text from the server itself.
+    System { text: String },
+    /// The client's message `seq` was accepted and sent out as envelope `message`.
+    Ack { seq: u64, message: u64 },
+    /// The client's direct message `seq` is held as envelope `message` until `to` logs in.
+    Queued { seq: u64, message: u64, to: String },
+    /// The client's message `seq` was refused.
+    Error { seq: Option<u64>, reason: String },
+}
+
+/// Serialize `message` with bincode and send it as one encrypted frame.
+pub async fn write_message<W, T>(writer: &mut W, key: &[u8], message: &T) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    write_frame(writer, key, &bincode::serialize(message)?).await
+}
+
+/// Read one encrypted frame and decode it. Returns `None` on a clean end of stream.
+pub async fn read_message<R, T>(reader: &mut R, key: &[u8]) -> Result<Option<T>, Box<dyn Error + Send + Sync>>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    match read_frame(reader, key).await? {
+        Some(frame) => Ok(Some(bincode::deserialize(&frame).map_err(|e| format!("malformed message: {}", e))?)),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+
+use sha2::{Digest, Sha256};
+
+use super::Client;
+use super::accounts::fold;
+use super::protocol::{Member, RoomInfo, UserId};
+
+/// Where every client starts out; it can't be locked and never closes.
+pub const LOBBY: &str = "#lobby";
+const MAX_ROOM_LEN: usize = 32;
+
+/// Turn user input like `dev` or `#dev` into a room name, or say why it can't be one.
+pub fn room_name(input: &str) -> Result<String, String> {
+    let name = if input.starts_with('#') { input.to_string() } else { format!("#{}", input) };
+    if name.len() < 2 || name.chars().count() > MAX_ROOM_LEN {
+        return Err(format!("room names must be 1 to {} characters after the '#'", MAX_ROOM_LEN - 1));
+    }
+    if name[1..].chars().any(|c| c.is_control() || c.is_whitespace() || c == '#' || c == ':') {
+        return Err("room names must not contain spaces, '#', colons or control characters".to_string());
+    }
+    Ok(name)
+}
+
+struct Room {
+    /// SHA-256 of the password set by whoever opened the room.
+    password: Option<[u8; 32]>,
+    members: Vec<Client>,
+}
+
+/// Every open room. Rooms other than the lobby close when their last member leaves.
+pub struct Rooms {
+    rooms: HashMap<String, Room>,
+}
+
+impl Default for Rooms {
+    fn default() -> Rooms {
+        let lobby = Room { password: None, members: Vec::new() };
+        Rooms { rooms: HashMap::from([(LOBBY.to_string(), lobby)]) }
+    }
+}
+
+impl Rooms {
+    pub fn members(&self, room: &str) -> &[Client] {
+        self.rooms.get(room).map_or(&[], |r| &r.members)
+    }
+
+    pub fn roster(&self, room: &str) -> Vec<Member> {
+        self.members(room).iter().map(Client::member).collect()
+    }
+
+    /// Hash of the password `room` was opened with; `None` if it isn't locked.
+    pub fn lock(&self, room: &str) -> Option<[u8; 32]> {
+        self.rooms.get(room).and_then(|r| r.password)
+    }
+
+    /// The connected client called `username`, whichever room it is in.
+    pub fn find(&self, username: &str) -> Option<&Client> {
+        let wanted = fold(username);
+        self.rooms.values().flat_map(|r| &r.members).find(|c| fold(&c.username) == wanted)
+    }
+
+    pub fn rename(&mut self, user: UserId, username: &str) {
+        for client in self.rooms.values_mut().flat_map(|r| &mut r.members).filter(|c| c.id == user) {
+            client.username = username.to_string();
+        }
+    }
+
+    /// Add `client` to `room`, opening it with `password` if it isn't open yet.
+    pub fn enter(&mut self, room: &str, client: Client, password: Option<&str>) -> Result<(), String> {
+        let hash = password.filter(|p| !p.is_empty()).map(|p| <[u8; 32]>::from(Sha256::digest(p.as_bytes())));
+        match self.rooms.get_mut(room) {
+            Some(existing) => {
+                if existing.password.is_some() && existing.password != hash {
+                    return Err(format!("{} is locked; wrong or missing password", room));
+                }
+                existing.members.push(client);
+            }
+            None => {
+                self.rooms.insert(room.to_string(), Room { password: hash, members: vec![client] });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn leave(&mut self, room: &str, user: UserId) {
+        if let Some(existing) = self.rooms.get_mut(room) {
+            existing.members.retain(|c| c.id != user);
+            if existing.members.is_empty() && room != LOBBY {
+                self.rooms.remove(room);
+            }
+        }
+    }
+
+    /// Every open room, sorted by name.
+    pub fn list(&self) -> Vec<RoomInfo> {
+        let mut rooms: Vec<RoomInfo> = self
+            .rooms
+            .iter()
+            .map(|(name, room)| RoomInfo { name: name.clone(), members: room.members.len(), locked: room.password.is_some() })
+            .collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        rooms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SigningKey;
+    use tokio::sync::mpsc;
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+
+    fn client(id: UserId, username: &str) -> Client {
+        let (outbox, _) = mpsc::channel(1);
+        let identity = SigningKey::from_bytes(&[id as u8; 32]).verifying_key();
+        Client { id, username: username.to_string(), identity, outbox, kicked: CancellationToken::new() }
+    }
+
+    #[test]
+    fn locked_rooms_refuse_wrong_and_missing_passwords() {
+        let mut rooms = Rooms::default();
+        rooms.enter("#ops", client(1, "alice"), Some("hunter2")).unwrap();
+        assert!(rooms.enter("#ops", client(2, "bob"), Some("guess")).is_err());
+        assert!(rooms.enter("#ops", client(2, "bob"), None).is_err());
+        assert!(rooms.enter("#ops", client(2, "bob"), Some("")).is_err());
+        assert_eq!(rooms.members("#ops").len(), 1);
+        rooms.enter("#ops", client(2, "bob"), Some("hunter2")).unwrap();
+        assert_eq!(rooms.members("#ops").len(), 2);
+        assert!(rooms.list().iter().any(|r| r.name == "#ops" && r.locked));
+    }
+
+    #[test]
+    fn rooms_close_with_their_last_member_and_reopen_unlocked() {
+        let mut rooms = Rooms::default();
+        rooms.enter("#ops", client(1, "alice"), Some("hunter2")).unwrap();
+        rooms.leave("#ops", 1);
+        assert!(rooms.lock("#ops").is_none());
+        rooms.enter("#ops", client(2, "bob"), None).unwrap();
+        assert!(rooms.lock("#ops").is_none());
+        rooms.leave(LOBBY, 3);
+        assert!(rooms.list().iter().any(|r| r.name == LOBBY));
+    }
+
+    #[test]
+    fn room_names_are_checked() {
+        assert_eq!(room_name("dev").unwrap(), "#dev");
+        assert_eq!(room_name("#dev").unwrap(), "#dev");
+        assert!(room_name("#").is_err());
+        assert!(room_name("two words").is_err());
+        assert!(room_name("a:b").is_err());
+        assert!(room_name(&"x".repeat(MAX_ROOM_LEN)).is_err());
+    }
+}
@@ -0,0 +1,546 @@
+use std::error::Error;
+
+use ansi_to_tui::IntoText;
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use futures::StreamExt;
+use ratatui::{
+    DefaultTerminal, Frame,
+    layout::{Constraint, Layout, Position},
+    style::{Style, Stylize},
+    text::{Line, Text},
+    widgets::{Block, List, ListItem, Paragraph, Tabs, Wrap},
+};
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use super::client::Chat;
+use super::protocol::{Envelope, ServerMessage, UserId};
+use super::rooms::LOBBY;
+
+/// Lines kept per tab; older ones are dropped.
+const MAX_SCROLLBACK: usize = 2000;
+/// Lines kept in the input history.
+const MAX_INPUT_HISTORY: usize = 100;
+const MEMBERS_WIDTH: u16 = 24;
+
+#[derive(PartialEq)]
+enum Conversation {
+    Room(String),
+    /// Direct messages with one user.
+    Direct(String),
+}
+
+struct Tab {
+    conversation: Conversation,
+    lines: Vec<Line<'static>>,
+    /// Messages from others that arrived while another tab was shown.
+    unread: usize,
+    /// How far the view is scrolled up from the newest line, in screen rows.
+    scroll: usize,
+}
+
+impl Tab {
+    fn new(conversation: Conversation) -> Tab {
+        Tab { conversation, lines: Vec::new(), unread: 0, scroll: 0 }
+    }
+
+    fn title(&self) -> String {
+        let name = match &self.conversation {
+            Conversation::Room(room) => room.clone(),
+            Conversation::Direct(user) => format!("@{}", user),
+        };
+        match self.unread {
+            0 => name,
+            n => format!("{} ({})", name, n),
+        }
+    }
+}
+
+/// The line being typed, with readline-style editing and a history of sent lines.
+#[derive(Default)]
+struct Input {
+    text: String,
+    /// Cursor position, in characters.
+    cursor: usize,
+    history: Vec<String>,
+    /// Index into `history` while recalling earlier lines.
+    recalled: Option<usize>,
+    /// What was being typed before recalling history.
+    draft: String,
+}
+
+impl Input {
+    fn byte_index(&self) -> usize {
+        self.text.char_indices().nth(self.cursor).map_or(self.text.len(), |(i, _)| i)
+    }
+
+    fn len(&self) -> usize {
+        self.text.chars().count()
+    }
+
+    fn left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    fn right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.len());
+    }
+
+    fn insert(&mut self, c: char) {
+        let at = self.byte_index();
+        self.text.insert(at, c);
+        self.cursor += 1;
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            let at = self.byte_index();
+            self.text.remove(at);
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.cursor < self.len() {
+            let at = self.byte_index();
+            self.text.remove(at);
+        }
+    }
+
+    /// Delete back to the start of the word before the cursor.
+    fn delete_word(&mut self) {
+        let chars: Vec<char> = self.text.chars().collect();
+        let mut start = self.cursor;
+        while start > 0 && chars[start - 1].is_whitespace() {
+            start -= 1;
+        }
+        while start > 0 && !chars[start - 1].is_whitespace() {
+            start -= 1;
+        }
+        self.text = chars[..start].iter().chain(&chars[self.cursor..]).collect();
+        self.cursor = start;
+    }
+
+    fn delete_to_start(&mut self) {
+        let at = self.byte_index();
+        self.text.drain(..at);
+        self.cursor = 0;
+    }
+
+    fn delete_to_end(&mut self) {
+        let at = self.byte_index();
+        self.text.truncate(at);
+    }
+
+    fn recall(&mut self, text: String) {
+        self.text = text;
+        self.cursor = self.len();
+    }
+
+    fn older(&mut self) {
+        let index = match self.recalled {
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = std::mem::take(&mut self.text);
+                self.history.len() - 1
+            }
+            Some(0) => return,
+            Some(i) => i - 1,
+        };
+        self.recalled = Some(index);
+        self.recall(self.history[index].clone());
+    }
+
+    fn newer(&mut self) {
+        let Some(index) = self.recalled else { return };
+        if index + 1 < self.history.len() {
+            self.recalled = Some(index + 1);
+            self.recall(self.history[index + 1].clone());
+        } else {
+            self.recalled = None;
+            let draft = std::mem::take(&mut self.draft);
+            self.recall(draft);
+        }
+    }
+
+    /// Take the typed line, remembering it in the history.
+    fn submit(&mut self) -> String {
+        let line = std::mem::take(&mut self.text).trim().to_string();
+        self.cursor = 0;
+        self.recalled = None;
+        self.draft.clear();
+        if !line.is_empty() && self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+            self.history.drain(..self.history.len().saturating_sub(MAX_INPUT_HISTORY));
+        }
+        line
+    }
+}
+
+/// The full-screen chat: a tab per room and per direct conversation, the
+/// members of the current room, and the input line.
+struct Screen {
+    me: UserId,
+    tabs: Vec<Tab>,
+    active: usize,
+    /// The room this client is in; other room tabs keep what was said before it left.
+    room: String,
+    /// Who is in `room`, this client included.
+    members: Vec<(UserId, String)>,
+    input: Input,
+    /// Size of the message pane at the last draw, for paging and keeping a scrolled view still.
+    width: u16,
+    height: u16,
+    connected: bool,
+}
+
+impl Screen {
+    fn new(me: UserId) -> Screen {
+        Screen {
+            me,
+            tabs: vec![Tab::new(Conversation::Room(LOBBY.to_string()))],
+            active: 0,
+            room: LOBBY.to_string(),
+            members: Vec::new(),
+            input: Input::default(),
+            width: 80,
+            height: 20,
+            connected: true,
+        }
+    }
+
+    /// Index of the tab for `conversation`, opening one if needed.
+    fn tab(&mut self, conversation: Conversation) -> usize {
+        if let Some(index) = self.tabs.iter().position(|t| t.conversation == conversation) {
+            return index;
+        }
+        self.tabs.push(Tab::new(conversation));
+        self.tabs.len() - 1
+    }
+
+    fn show(&mut self, index: usize) {
+        self.active = index;
+        self.tabs[index].unread = 0;
+    }
+
+    /// Add `text`, which may hold several lines and terminal colours, to tab `index`.
+    fn push(&mut self, index: usize, text: &str, unread: bool) {
+        let text = text.into_text().unwrap_or_else(|_| Text::raw(text.to_string()));
+        let tab = &mut self.tabs[index];
+        if tab.scroll > 0 {
+            tab.scroll += Paragraph::new(text.clone()).wrap(Wrap { trim: false }).line_count(self.width);
+        }
+        tab.lines.extend(text.lines);
+        tab.lines.drain(..tab.lines.len().saturating_sub(MAX_SCROLLBACK));
+        if unread && index != self.active {
+            tab.unread += 1;
+        }
+    }
+
+    fn receive(&mut self, chat: &mut Chat, mut envelope: Envelope) {
+        let text = chat.receive(&mut envelope);
+        let mut unread = false;
+        let index = match &envelope.body {
+            ServerMessage::Joined { room, members } => {
+                self.room = room.clone();
+                self.members = members.iter().map(|m| (m.id, m.username.clone())).collect();
+                let index = self.tab(Conversation::Room(room.clone()));
+                self.show(index);
+                index
+            }
+            ServerMessage::Join { room, member } => {
+                if *room == self.room && !self.members.iter().any(|(id, _)| *id == member.id) {
+                    self.members.push((member.id, member.username.clone()));
+                }
+                self.tab(Conversation::Room(room.clone()))
+            }
+            ServerMessage::Leave { room, user, .. } => {
+                if *room == self.room {
+                    self.members.retain(|(id, _)| id != user);
+                }
+                self.tab(Conversation::Room(room.clone()))
+            }
+            ServerMessage::Renamed { user, to, .. } => {
+                for (id, username) in &mut self.members {
+                    if id == user {
+                        *username = to.clone();
+                    }
+                }
+                self.tab(Conversation::Room(self.room.clone()))
+            }
+            ServerMessage::Members { room, members } => {
+                if *room == self.room {
+                    self.members = members.iter().map(|m| (m.id, m.username.clone())).collect();
+                }
+                self.tab(Conversation::Room(room.clone()))
+            }
+            ServerMessage::Chat { room, sender, .. } => {
+                unread = *sender != self.me;
+                self.tab(Conversation::Room(room.clone()))
+            }
+            ServerMessage::History { room, .. } => self.tab(Conversation::Room(room.clone())),
+            ServerMessage::Direct { from, to, .. } => {
+                unread = from.id != self.me;
+                let other = if from.id == self.me { &to.username } else { &from.username };
+                self.tab(Conversation::Direct(other.clone()))
+            }
+            ServerMessage::Queued { to, .. } => self.tab(Conversation::Direct(to.clone())),
+            // The echoed message already shows it went out.
+            ServerMessage::Ack { .. } => return,
+            _ => self.active,
+        };
+        if let Some(text) = text {
+            self.push(index, &text, unread);
+        }
+    }
+
+    /// Show why something didn't happen, in the tab on show.
+    fn refuse(&mut self, reason: &str) {
+        self.push(self.active, &colored::Colorize::red(reason).to_string(), false);
+    }
+
+    fn disconnected(&mut self, reason: &str) {
+        self.connected = false;
+        self.refuse(reason);
+    }
+
+    /// Handle a key press; returns `false` to quit.
+    fn key(&mut self, chat: &mut Chat, key: KeyEvent) -> bool {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let alt = key.modifiers.contains(KeyModifiers::ALT);
+        let page = self.height.saturating_sub(1).max(1) as usize;
+        match key.code {
+            KeyCode::Char('c') if ctrl => return false,
+            KeyCode::Char('d') if ctrl && self.input.text.is_empty() => return false,
+            KeyCode::Enter => return self.submit(chat),
+            KeyCode::Tab => self.show((self.active + 1) % self.tabs.len()),
+            KeyCode::BackTab => self.show((self.active + self.tabs.len() - 1) % self.tabs.len()),
+            KeyCode::Char(c @ '1'..='9') if alt => {
+                let index = c as usize - '1' as usize;
+                if index < self.tabs.len() {
+                    self.show(index);
+                }
+            }
+            KeyCode::PageUp => self.tabs[self.active].scroll += page,
+            KeyCode::PageDown => {
+                let tab = &mut self.tabs[self.active];
+                tab.scroll = tab.scroll.saturating_sub(page);
+            }
+            KeyCode::Esc => self.tabs[self.active].scroll = 0,
+            KeyCode::Up => self.input.older(),
+            KeyCode::Down => self.input.newer(),
+            KeyCode::Left => self.input.left(),
+            KeyCode::Right => self.input.right(),
+            KeyCode::Home => self.input.cursor = 0,
+            KeyCode::End => self.input.cursor = self.input.len(),
+            KeyCode::Backspace => self.input.backspace(),
+            KeyCode::Delete => self.input.delete(),
+            KeyCode::Char('a') if ctrl => self.input.cursor = 0,
+            KeyCode::Char('e') if ctrl => self.input.cursor = self.input.len(),
+            KeyCode::Char('u') if ctrl => self.input.delete_to_start(),
+            KeyCode::Char('k') if ctrl => self.input.delete_to_end(),
+            KeyCode::Char('w') if ctrl => self.input.delete_word(),
+            KeyCode::Char(c) if !ctrl && !alt => self.input.insert(c),
+            _ => {}
+        }
+        true
+    }
+
+    /// Send the typed line to the conversation on show; returns `false` to quit.
+    fn submit(&mut self, chat: &mut Chat) -> bool {
+        let line = self.input.submit();
+        match line.as_str() {
+            "" => return true,
+            "/quit" => return false,
+            "/close" => {
+                self.close();
+                return true;
+            }
+            _ => {}
+        }
+        if !self.connected {
+            self.refuse("✖ Not connected");
+            return true;
+        }
+        let line = match &self.tabs[self.active].conversation {
+            _ if line.starts_with('/') => line,
+            Conversation::Direct(user) => format!("/msg {} {}", user, line),
+            Conversation::Room(room) if *room != self.room => {
+                let refusal = format!("✖ You are not in {}; /join {} to talk here", room, room);
+                self.refuse(&refusal);
+                return true;
+            }
+            Conversation::Room(_) => line,
+        };
+        if let Some(output) = chat.input(&line) {
+            self.push(self.active, &output, false);
+        }
+        self.tabs[self.active].scroll = 0;
+        true
+    }
+
+    /// Close the tab on show, unless it is the room this client is in.
+    fn close(&mut self) {
+        if self.tabs[self.active].conversation == Conversation::Room(self.room.clone()) {
+            let refusal = format!("✖ You are in {}; /join another room before closing it", self.room);
+            self.refuse(&refusal);
+            return;
+        }
+        self.tabs.remove(self.active);
+        self.show(self.active.min(self.tabs.len() - 1));
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [tabs_area, main_area, input_area] =
+            Layout::vertical([Constraint::Length(1), Constraint::Min(3), Constraint::Length(3)]).areas(frame.area());
+        let [messages_area, members_area] = Layout::horizontal([Constraint::Min(10), Constraint::Length(MEMBERS_WIDTH)]).areas(main_area);
+
+        let titles = self.tabs.iter().map(|tab| {
+            let style = match &tab.conversation {
+                _ if tab.unread > 0 => Style::new().yellow().bold(),
+                Conversation::Room(room) if *room != self.room => Style::new().dim(),
+                _ => Style::new(),
+            };
+            Line::styled(tab.title(), style)
+        });
+        frame.render_widget(Tabs::new(titles).select(self.active).highlight_style(Style::new().reversed()), tabs_area);
+
+        let tab = &self.tabs[self.active];
+        let (heading, target) = match &tab.conversation {
+            Conversation::Room(room) if *room == self.room => (format!(" {} ", room), format!(" to {} ", room)),
+            Conversation::Room(room) => (format!(" {} (left) ", room), " commands only ".to_string()),
+            Conversation::Direct(user) => (format!(" private with {} ", user), format!(" to {} (private) ", user)),
+        };
+        let mut block = Block::bordered().title(heading);
+        let inner = block.inner(messages_area);
+        self.width = inner.width;
+        self.height = inner.height;
+        let tab = &mut self.tabs[self.active];
+        let messages = Paragraph::new(tab.lines.clone()).wrap(Wrap { trim: false });
+        let bottom = messages.line_count(inner.width).saturating_sub(inner.height as usize);
+        tab.scroll = tab.scroll.min(bottom);
+        if tab.scroll > 0 {
+            block = block.title_bottom(Line::from(format!(" ↓ {} more (Esc) ", tab.scroll)).right_aligned());
+        }
+        let top = u16::try_from(bottom - tab.scroll).unwrap_or(u16::MAX);
+        frame.render_widget(messages.block(block).scroll((top, 0)), messages_area);
+
+        let mut members: Vec<&(UserId, String)> = self.members.iter().collect();
+        members.sort_by_key(|(_, name)| name.to_lowercase());
+        let items = members.into_iter().map(|(id, name)| match *id == self.me {
+            true => ListItem::new(name.as_str()).blue().bold(),
+            false => ListItem::new(name.as_str()),
+        });
+        let roster = Block::bordered().title(format!(" {} ({}) ", self.room, self.members.len()));
+        frame.render_widget(List::new(items).block(roster), members_area);
+
+        let hints = if self.connected { " Enter send · Tab next tab · PgUp/PgDn scroll · /quit " } else { " disconnected · /quit " };
+        let block = Block::bordered().title(target).title(Line::from(hints).right_aligned());
+        let inner = block.inner(input_area);
+        let before = Line::from(self.input.text.chars().take(self.input.cursor).collect::<String>()).width();
+        let offset = before.saturating_sub(inner.width.saturating_sub(1) as usize);
+        let input = Paragraph::new(self.input.text.as_str()).scroll((0, u16::try_from(offset).unwrap_or(u16::MAX))).block(block);
+        frame.render_widget(input, input_area);
+        frame.set_cursor_position(Position::new(inner.x + (before - offset) as u16, inner.y));
+    }
+
+    async fn event_loop(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        chat: &mut Chat,
+        incoming: &mut UnboundedReceiver<Result<Envelope, String>>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut events = EventStream::new();
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            tokio::select! {
+                received = incoming.recv(), if self.connected => match received {
+                    Some(Ok(envelope)) => self.receive(chat, envelope),
+                    Some(Err(reason)) => self.disconnected(&reason),
+                    None => self.disconnected("Connection closed."),
+                },
+                event = events.next() => match event {
+                    Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+                        if !self.key(chat, key) {
+                            return Ok(());
+                        }
+                    }
+                    // Anything else, such as a resize, just needs a redraw.
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => return Err(e.into()),
+                    None => return Ok(()),
+                },
+            }
+        }
+    }
+}
+
+/// Run the full-screen chat until the user quits, starting with `banner` in the lobby tab.
+pub async fn run(
+    mut chat: Chat,
+    mut incoming: UnboundedReceiver<Result<Envelope, String>>,
+    banner: String,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut screen = Screen::new(chat.me);
+    screen.push(0, &banner, false);
+    let mut terminal = ratatui::try_init()?;
+    let result = screen.event_loop(&mut terminal, &mut chat, &mut incoming).await;
+    ratatui::restore();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typed(text: &str) -> Input {
+        let mut input = Input::default();
+        text.chars().for_each(|c| input.insert(c));
+        input
+    }
+
+    #[test]
+    fn cursor_stops_at_both_ends() {
+        let mut input = typed("héllo");
+        input.right();
+        assert_eq!(input.cursor, 5);
+        for _ in 0..7 {
+            input.left();
+        }
+        assert_eq!(input.cursor, 0);
+        input.right();
+        input.right();
+        input.insert('X');
+        assert_eq!(input.text, "héXllo");
+    }
+
+    #[test]
+    fn backspace_and_delete_do_nothing_past_the_ends() {
+        let mut input = typed("ab");
+        input.delete();
+        assert_eq!(input.text, "ab");
+        input.cursor = 0;
+        input.backspace();
+        assert_eq!((input.text.as_str(), input.cursor), ("ab", 0));
+        input.delete();
+        assert_eq!(input.text, "b");
+
+        let mut input = typed("né");
+        input.backspace();
+        input.backspace();
+        input.backspace();
+        assert_eq!((input.text.as_str(), input.cursor), ("", 0));
+    }
+
+    #[test]
+    fn history_recall_keeps_the_draft() {
+        let mut input = typed("first");
+        input.submit();
+        input.insert('x');
+        input.older();
+        assert_eq!(input.text, "first");
+        input.older();
+        assert_eq!(input.text, "first");
+        input.newer();
+        assert_eq!((input.text.as_str(), input.cursor), ("x", 1));
+    }
+}
@@ -0,0 +1,639 @@
+use std::{
+    io::IsTerminal,
+    path::{Path, PathBuf},
+};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{File, OpenOptions, create_dir_all},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+};
+use tracing::warn;
+
+use super::compression::{self, Chunk};
+use super::error;
+use super::manifest::{EntryKind, Manifest};
+use super::{
+    Compression, ExistingFilePolicy, Partials, SPECIAL_FILE, Sink, SkippedEntry, TransferError, ZEROS, apply_metadata, cancel_on_ctrl_c,
+    commit_partial, overall_bar, paths, prepare_entry, resolve_destination, skip_reason,
+};
+use crate::utils::encryption::{open_chunk, seal_chunk};
+use crate::utils::networking::{read_frame, write_frame};
+
+/// Identifies a nettool archive before anything else is parsed.
+const MAGIC: [u8; 4] = *b"NTAR";
+/// Archive format version written by this build.
+const FORMAT_VERSION: u16 = 1;
+/// Plaintext bytes per archive chunk.
+const ARCHIVE_CHUNK_SIZE: usize = 256 * 1024;
+/// Read for the passphrase when no `--passphrase-file` is given, before prompting.
+pub const PASSPHRASE_ENV: &str = "NETTOOL_PASSPHRASE";
+/// Highest Argon2 memory cost an archive header may ask for, in KiB (1 GiB).
+const MAX_M_COST: u32 = 1 << 20;
+/// Highest Argon2 iteration count an archive header may ask for.
+const MAX_T_COST: u32 = 10;
+/// Highest Argon2 parallelism an archive header may ask for.
+const MAX_P_COST: u32 = 16;
+
+/// How `pack` builds an archive.
+#[derive(Debug, Clone, Default)]
+pub struct PackOptions {
+    pub compression: Compression,
+    /// Also record atime, ownership and extended attributes.
+    pub preserve: bool,
+    /// File whose first line is the passphrase.
+    pub passphrase_file: Option<PathBuf>,
+}
+
+/// How `unpack` extracts an archive.
+#[derive(Debug, Clone, Default)]
+pub struct UnpackOptions {
+    pub policy: ExistingFilePolicy,
+    /// Leave the hidden `.name.ntpart` files of a failed extraction in place instead of deleting them.
+    pub keep_partial: bool,
+    /// Check every chunk and digest without writing anything.
+    pub verify_only: bool,
+    /// Also apply the ownership, extended attributes and setuid, setgid and
+    /// sticky bits stored with `--preserve`. Only safe for a trusted archive.
+    pub restore_privileged: bool,
+    /// File whose first line is the passphrase.
+    pub passphrase_file: Option<PathBuf>,
+}
+
+/// Argon2id settings for deriving the archive key, stored in the clear after the magic.
+#[derive(Debug, Serialize, Deserialize)]
+struct KdfParams {
+    salt: [u8; 16],
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+}
+
+impl KdfParams {
+    /// A fresh random salt with the current default costs.
+    fn generate() -> KdfParams {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        KdfParams {
+            salt,
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+
+    /// The costs come from an untrusted header, so anything beyond the maximums
+    /// is refused rather than letting an archive tie up gigabytes of memory.
+    fn derive_key(&self, passphrase: &str) -> Result<[u8; 32], TransferError> {
+        if self.m_cost > MAX_M_COST || self.t_cost > MAX_T_COST || self.p_cost > MAX_P_COST {
+            return Err(TransferError::Integrity(format!(
+                "archive asks for key derivation costs m={} KiB, t={}, p={}, above the limits of m={} KiB, t={}, p={}",
+                self.m_cost, self.t_cost, self.p_cost, MAX_M_COST, MAX_T_COST, MAX_P_COST
+            )));
+        }
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(32))
+            .map_err(|e| TransferError::Crypto(format!("invalid key derivation settings: {}", e)))?;
+        let mut key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &self.salt, &mut key)
+            .map_err(|e| TransferError::Crypto(format!("key derivation failed: {}", e)))?;
+        Ok(key)
+    }
+}
+
+/// Get the passphrase from `file`, then [`PASSPHRASE_ENV`], then by prompting on the terminal.
+///
+/// A prompted passphrase for a new archive is asked for twice.
+fn read_passphrase(file: Option<&Path>, confirm: bool) -> Result<String, TransferError> {
+    let passphrase = if let Some(file) = file {
+        let contents = std::fs::read_to_string(file)
+            .map_err(|e| TransferError::Local(format!("could not read passphrase file '{}': {}", file.display(), e)))?;
+        contents.lines().next().unwrap_or_default().to_string()
+    } else if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        passphrase
+    } else {
+        let prompt = |text: &str| {
+            rpassword::prompt_password(text).map_err(|e| TransferError::Local(format!("could not read passphrase: {}", e)))
+        };
+        let passphrase = prompt("Passphrase: ")?;
+        if confirm && prompt("Repeat passphrase: ")? != passphrase {
+            return Err(TransferError::Local("passphrases do not match".to_string()));
+        }
+        passphrase
+    };
+
+    if passphrase.is_empty() {
+        return Err(TransferError::Local("the passphrase must not be empty".to_string()));
+    }
+    Ok(passphrase)
+}
+
+/// Writes the sealed records of an archive, each bound to its position so
+/// records cannot be dropped, repeated or reordered without detection.
+struct ArchiveWriter<W> {
+    out: W,
+    key: [u8; 32],
+    index: u64,
+}
+
+impl<W: AsyncWrite + Unpin> ArchiveWriter<W> {
+    async fn record(&mut self, data: &[u8]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let sealed = seal_chunk(data, &self.key, &self.index.to_be_bytes())?;
+        write_frame(&mut self.out, &sealed).await?;
+        self.index += 1;
+        Ok(())
+    }
+}
+
+/// Reads back what [`ArchiveWriter`] wrote, authenticating every record.
+struct ArchiveReader<R> {
+    input: R,
+    key: [u8; 32],
+    index: u64,
+}
+
+impl<R: AsyncRead + Unpin> ArchiveReader<R> {
+    async fn record(&mut self) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        let frame = read_frame(&mut self.input)
+            .await?
+            .ok_or_else(|| TransferError::Integrity("archive is truncated".to_string()))?;
+        let opened = open_chunk(&frame, &self.key, &self.index.to_be_bytes()).map_err(|_| {
+            // The first record is the only one a wrong key can trip over on its own.
+            if self.index == 0 {
+                TransferError::Crypto("wrong passphrase, or the archive is corrupt".to_string())
+            } else {
+                TransferError::Crypto(format!("record {} failed authentication, the archive is corrupt", self.index))
+            }
+        })?;
+        self.index += 1;
+        Ok(opened)
+    }
+}
+
+/// Overall progress bar for `total` bytes, drawn only when stderr is a terminal.
+fn archive_bar(total: u64) -> ProgressBar {
+    let multi = MultiProgress::new();
+    if !std::io::stderr().is_terminal() {
+        multi.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    overall_bar(&multi, total, "cyan/blue")
+}
+
+/// Command-line `pack`: write `inputs` to the encrypted archive `archive` and print a summary.
+///
+/// Ctrl-C stops packing and removes the unfinished archive.
+pub async fn pack(inputs: &[String], archive: &str, options: PackOptions) -> Result<(), TransferError> {
+    let cancel = cancel_on_ctrl_c();
+    let packed = tokio::select! {
+        packed = pack_archive(inputs, Path::new(archive), &options) => packed,
+        _ = cancel.cancelled() => Err(TransferError::Cancelled),
+    };
+    match packed {
+        Ok(summary) => {
+            for skipped in &summary.skipped {
+                println!("Skipped '{}': {}", skipped.path, skipped.reason);
+            }
+            println!("Packed {} file(s), {} bytes into '{}'", summary.files, summary.bytes, archive);
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Build the manifest of `inputs` and write it, then every file's chunks and
+/// digest, as sealed records after the archive header.
+///
+/// The archive is written to a hidden partial file and renamed into place
+/// once complete.
+async fn pack_archive(inputs: &[String], archive: &Path, options: &PackOptions) -> Result<ArchiveSummary, TransferError> {
+    if inputs.iter().any(|p| p == "-") {
+        return Err(TransferError::Local("stdin cannot be packed into an archive".to_string()));
+    }
+    let passphrase = read_passphrase(options.passphrase_file.as_deref(), true)?;
+    let (mut manifest, sources) =
+        Manifest::build(inputs, options.preserve).map_err(|e| TransferError::Local(format!("Could not prepare files: {}", e)))?;
+    let skipped: Vec<_> = std::mem::take(&mut manifest.special)
+        .iter()
+        .map(|path| SkippedEntry { path: path.display().to_string(), reason: SPECIAL_FILE })
+        .collect();
+    for entry in &skipped {
+        warn!("Skipping special file '{}'", entry.path);
+    }
+
+    let partial = paths::partial_path(archive);
+    let mut partials = Partials { paths: vec![partial.clone()], keep: false };
+    let file = File::create(&partial)
+        .await
+        .map_err(|e| TransferError::Local(format!("could not create '{}': {}", archive.display(), e)))?;
+
+    let kdf = KdfParams::generate();
+    let key = kdf.derive_key(&passphrase)?;
+    let mut writer = ArchiveWriter { out: BufWriter::new(file), key, index: 0 };
+    write_records(&mut writer, &kdf, &manifest, &sources, options.compression).await?;
+
+    writer.out.flush().await.map_err(|e| TransferError::Io(e.to_string()))?;
+    commit_partial(&partial, archive, None)
+        .await
+        .map_err(|e| TransferError::from(e).context(format!("failed to finish '{}'", archive.display())))?;
+    partials.paths.clear();
+    Ok(ArchiveSummary { files: manifest.file_count(), bytes: manifest.total_size(), skipped })
+}
+
+async fn write_records<W: AsyncWrite + Unpin>(
+    writer: &mut ArchiveWriter<W>,
+    kdf: &KdfParams,
+    manifest: &Manifest,
+    sources: &[PathBuf],
+    compression: Compression,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    writer.out.write_all(&MAGIC).await?;
+    writer.out.write_all(&FORMAT_VERSION.to_be_bytes()).await?;
+    write_frame(&mut writer.out, &bincode::serialize(kdf)?).await?;
+    writer.record(&bincode::serialize(manifest)?).await?;
+
+    let overall = archive_bar(manifest.total_size());
+    let files = manifest.entries.iter().filter(|e| e.kind == EntryKind::File);
+    let mut buffer = vec![0u8; ARCHIVE_CHUNK_SIZE];
+    for (entry, source) in files.zip(sources) {
+        let mut file = File::open(source)
+            .await
+            .map_err(|e| TransferError::Local(format!("could not open '{}': {}", source.display(), e)))?;
+        let mut hasher = Sha256::new();
+        let mut total = 0u64;
+        // Consecutive all-zero chunks are coalesced and stored as a single hole.
+        let mut hole = 0u64;
+        loop {
+            let n = file.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+            if buffer[..n].iter().all(|&b| b == 0) {
+                hole += n as u64;
+            } else {
+                if hole > 0 {
+                    writer.record(&compression::encode_hole(hole)).await?;
+                    hole = 0;
+                }
+                writer.record(&compression::encode_chunk(&buffer[..n], compression)?).await?;
+            }
+            total += n as u64;
+            overall.inc(n as u64);
+        }
+        if hole > 0 {
+            writer.record(&compression::encode_hole(hole)).await?;
+        }
+        if total != entry.size {
+            return Err(TransferError::Local(format!("'{}' changed size while being packed", source.display())).into());
+        }
+        writer.record(&hasher.finalize()).await?;
+    }
+
+    // An empty record marks the end, so a cut-off archive is never mistaken for a complete one.
+    writer.record(&[]).await?;
+    overall.finish_and_clear();
+    Ok(())
+}
+
+/// Command-line `unpack`: check `archive` and extract it under `output_dir`, then print a summary.
+///
+/// Ctrl-C stops extraction and removes the partial files written so far.
+pub async fn unpack(archive: &str, output_dir: &str, options: UnpackOptions) -> Result<(), TransferError> {
+    let cancel = cancel_on_ctrl_c();
+    let unpacked = tokio::select! {
+        unpacked = unpack_archive(Path::new(archive), Path::new(output_dir), &options) => unpacked,
+        _ = cancel.cancelled() => Err(TransferError::Cancelled),
+    };
+    match unpacked {
+        Ok(summary) => {
+            for skipped in &summary.skipped {
+                println!("Skipped '{}': {}", skipped.path, skipped.reason);
+            }
+            if options.verify_only {
+                println!("Verified {} file(s), {} bytes in '{}'", summary.files, summary.bytes, archive);
+            } else {
+                println!("Unpacked {} file(s), {} bytes into '{}'", summary.files, summary.bytes, output_dir);
+            }
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            Err(e)
+        }
+    }
+}
+
+/// What [`pack_archive`] or [`unpack_archive`] did.
+struct ArchiveSummary {
+    files: usize,
+    bytes: u64,
+    skipped: Vec<SkippedEntry>,
+}
+
+/// Authenticate and extract `archive` into `output_dir`.
+///
+/// Destinations are resolved like those of a network upload, and every file
+/// is written to a hidden partial file that is only renamed into place once
+/// the whole archive, including its end marker, has been verified.
+async fn unpack_archive(archive: &Path, output_dir: &Path, options: &UnpackOptions) -> Result<ArchiveSummary, TransferError> {
+    let file = File::open(archive)
+        .await
+        .map_err(|e| TransferError::Local(format!("could not open '{}': {}", archive.display(), e)))?;
+    let mut input = BufReader::new(file);
+
+    let mut preamble = [0u8; 6];
+    input
+        .read_exact(&mut preamble)
+        .await
+        .map_err(|_| TransferError::Local(format!("'{}' is not a nettool archive", archive.display())))?;
+    if preamble[..4] != MAGIC {
+        return Err(TransferError::Local(format!("'{}' is not a nettool archive", archive.display())));
+    }
+    let version = u16::from_be_bytes([preamble[4], preamble[5]]);
+    if version != FORMAT_VERSION {
+        return Err(TransferError::Local(format!(
+            "'{}' is archive format v{}, this build reads v{}",
+            archive.display(),
+            version,
+            FORMAT_VERSION
+        )));
+    }
+    let kdf = read_frame(&mut input)
+        .await?
+        .ok_or_else(|| TransferError::Integrity("archive is truncated".to_string()))?;
+    let kdf: KdfParams =
+        bincode::deserialize(&kdf).map_err(|e| TransferError::Integrity(format!("malformed archive header: {}", e)))?;
+
+    let passphrase = read_passphrase(options.passphrase_file.as_deref(), false)?;
+    let key = kdf.derive_key(&passphrase)?;
+    let mut reader = ArchiveReader { input, key, index: 0 };
+
+    let mut partials = Partials { paths: Vec::new(), keep: options.keep_partial };
+    let summary = extract_records(&mut reader, output_dir, options, &mut partials.paths).await?;
+    partials.paths.clear();
+    Ok(summary)
+}
+
+async fn extract_records<R: AsyncRead + Unpin>(
+    reader: &mut ArchiveReader<R>,
+    root: &Path,
+    options: &UnpackOptions,
+    partials: &mut Vec<PathBuf>,
+) -> Result<ArchiveSummary, Box<dyn std::error::Error + Send + Sync>> {
+    let manifest: Manifest = bincode::deserialize(&reader.record().await?)
+        .map_err(|e| TransferError::Integrity(format!("malformed archive manifest: {}", e)))?;
+    if manifest.has_stream() {
+        return Err(TransferError::Integrity("archive contains a stream of unknown length".to_string()).into());
+    }
+
+    // With --verify-only nothing is resolved or created, and all data is discarded.
+    let mut destinations = Vec::with_capacity(manifest.entries.len());
+    let mut targets = Vec::with_capacity(manifest.entries.len());
+    let mut created = Vec::with_capacity(manifest.entries.len());
+    if !options.verify_only {
+        create_dir_all(root)
+            .await
+            .map_err(|e| TransferError::Local(format!("could not create '{}': {}", root.display(), e)))?;
+        for entry in &manifest.entries {
+            let destination = resolve_destination(root, entry, options.policy)
+                .map_err(|e| TransferError::Local(format!("rejected '{}': {}", entry.path.escape_debug(), e)))?;
+            let target = match (&entry.kind, &destination) {
+                (EntryKind::File, Some(path)) => {
+                    let partial = paths::partial_path(path);
+                    partials.push(partial.clone());
+                    Some(partial)
+                }
+                _ => destination.clone(),
+            };
+            let new = prepare_entry(entry, target.as_deref())
+                .await
+                .map_err(|e| TransferError::Local(format!("failed to create '{}': {}", entry.path, e)))?;
+            destinations.push(destination);
+            targets.push(target);
+            created.push(new);
+        }
+    }
+
+    let overall = archive_bar(manifest.total_size());
+    for (index, entry) in manifest.entries.iter().enumerate() {
+        if entry.kind != EntryKind::File {
+            continue;
+        }
+        let mut file = match targets.get(index) {
+            Some(Some(path)) => Some(OpenOptions::new().write(true).open(path).await?),
+            _ => None,
+        };
+        let sink = file.as_mut().map_or(Sink::Discard, Sink::File);
+        extract_file(reader, sink, entry.size, &overall)
+            .await
+            .map_err(|e| error::context(e, format!("failed to extract '{}'", entry.path)))?;
+    }
+
+    if !reader.record().await?.is_empty() {
+        return Err(TransferError::Integrity("archive has more data than its manifest lists".to_string()).into());
+    }
+    if read_frame(&mut reader.input).await?.is_some() {
+        return Err(TransferError::Integrity("archive has trailing data after its end marker".to_string()).into());
+    }
+    overall.finish_and_clear();
+
+    let mut summary = ArchiveSummary { files: manifest.file_count(), bytes: manifest.total_size(), skipped: Vec::new() };
+    if options.verify_only {
+        return Ok(summary);
+    }
+
+    // Everything verified: move the partial files over their real names.
+    for ((entry, destination), target) in manifest.entries.iter().zip(&destinations).zip(&targets) {
+        let (EntryKind::File, Some(destination), Some(partial)) = (&entry.kind, destination, target) else { continue };
+        commit_partial(partial, destination, Some(entry.size))
+            .await
+            .map_err(|e| error::context(e, format!("failed to finish '{}'", entry.path)))?;
+    }
+    for (entry, destination) in manifest.entries.iter().zip(&destinations) {
+        if destination.is_none() {
+            summary.skipped.push(SkippedEntry { path: entry.path.clone(), reason: skip_reason(entry) });
+        }
+    }
+    // Directories are finalised last so writing their children doesn't bump the mtime again.
+    // Only what this extraction created: a directory that was already there keeps its mode and times.
+    for ((entry, destination), new) in manifest.entries.iter().zip(&destinations).zip(&created).rev() {
+        let (Some(destination), true) = (destination, *new) else { continue };
+        if let Err(e) = apply_metadata(destination, entry, options.restore_privileged) {
+            warn!("Could not set metadata on '{}': {}", entry.path, e);
+        }
+    }
+    Ok(summary)
+}
+
+/// Read the chunks of one `len`-byte file into `sink` and check them against the digest record that follows.
+async fn extract_file<R: AsyncRead + Unpin>(
+    reader: &mut ArchiveReader<R>,
+    mut sink: Sink<'_>,
+    len: u64,
+    overall: &ProgressBar,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut hasher = Sha256::new();
+    let mut total = 0u64;
+    while total < len {
+        let chunk = compression::decode_chunk(&reader.record().await?)?;
+        let n = match &chunk {
+            Chunk::Data(data) => data.len() as u64,
+            Chunk::Hole(n) => *n,
+        };
+        // `total < len` here, so this can't overflow the way `total + n` could.
+        if n == 0 || n > len - total {
+            return Err(TransferError::Integrity("file data does not match its size in the manifest".to_string()).into());
+        }
+
+        match chunk {
+            Chunk::Data(data) => {
+                hasher.update(&data);
+                sink.write(&data).await?;
+            }
+            Chunk::Hole(n) => {
+                let mut remaining = n;
+                while remaining > 0 {
+                    let step = remaining.min(ZEROS.len() as u64) as usize;
+                    hasher.update(&ZEROS[..step]);
+                    remaining -= step as u64;
+                }
+                sink.skip(n).await?;
+            }
+        }
+        total += n;
+        overall.inc(n);
+    }
+    sink.flush().await?;
+
+    if reader.record().await?.as_slice() != hasher.finalize().as_slice() {
+        return Err(TransferError::Integrity("SHA-256 digest mismatch, file is corrupt".to_string()).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    use super::*;
+
+    /// A directory holding `data/` with a random file and a hole-y file, and a passphrase file.
+    fn fixture() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("data");
+        std::fs::create_dir_all(data.join("nested")).unwrap();
+        let mut rng = StdRng::seed_from_u64(40);
+        let random: Vec<u8> = (0..300_000).map(|_| rng.r#gen()).collect();
+        std::fs::write(data.join("random.bin"), &random).unwrap();
+        let mut sparse = vec![0u8; ARCHIVE_CHUNK_SIZE * 2];
+        sparse.extend_from_slice(b"tail");
+        std::fs::write(data.join("nested/sparse.bin"), &sparse).unwrap();
+        std::fs::write(dir.path().join("passphrase"), "correct horse\n").unwrap();
+        dir
+    }
+
+    async fn pack_fixture(dir: &Path) -> PathBuf {
+        let archive = dir.join("data.ntar");
+        let options = PackOptions { passphrase_file: Some(dir.join("passphrase")), ..Default::default() };
+        let inputs = [dir.join("data").to_string_lossy().into_owned()];
+        pack_archive(&inputs, &archive, &options).await.unwrap();
+        archive
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn special_files_are_reported_not_packed() {
+        let dir = fixture();
+        let _socket = std::os::unix::net::UnixListener::bind(dir.path().join("data/control.sock")).unwrap();
+        let archive = dir.path().join("data.ntar");
+        let options = PackOptions { passphrase_file: Some(dir.path().join("passphrase")), ..Default::default() };
+        let inputs = [dir.path().join("data").to_string_lossy().into_owned()];
+        let summary = pack_archive(&inputs, &archive, &options).await.unwrap();
+        assert_eq!(summary.files, 2);
+        assert_eq!(summary.skipped.len(), 1);
+        assert!(summary.skipped[0].path.ends_with("control.sock"));
+        assert_eq!(summary.skipped[0].reason, SPECIAL_FILE);
+    }
+
+    fn unpack_options(passphrase_file: PathBuf) -> UnpackOptions {
+        UnpackOptions { passphrase_file: Some(passphrase_file), ..Default::default() }
+    }
+
+    #[tokio::test]
+    async fn pack_then_unpack_restores_every_file() {
+        let dir = fixture();
+        let archive = pack_fixture(dir.path()).await;
+        let output = dir.path().join("out");
+        std::fs::create_dir(&output).unwrap();
+
+        let summary = unpack_archive(&archive, &output, &unpack_options(dir.path().join("passphrase"))).await.unwrap();
+        assert_eq!(summary.files, 2);
+        for file in ["random.bin", "nested/sparse.bin"] {
+            let original = std::fs::read(dir.path().join("data").join(file)).unwrap();
+            assert_eq!(std::fs::read(output.join("data").join(file)).unwrap(), original, "{}", file);
+        }
+    }
+
+    #[tokio::test]
+    async fn existing_directories_keep_their_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = fixture();
+        std::fs::set_permissions(dir.path().join("data"), std::fs::Permissions::from_mode(0o777)).unwrap();
+        std::fs::set_permissions(dir.path().join("data/nested"), std::fs::Permissions::from_mode(0o750)).unwrap();
+        let archive = pack_fixture(dir.path()).await;
+        let output = dir.path().join("out");
+        std::fs::create_dir_all(output.join("data")).unwrap();
+        std::fs::set_permissions(output.join("data"), std::fs::Permissions::from_mode(0o700)).unwrap();
+
+        let options = UnpackOptions { policy: ExistingFilePolicy::Overwrite, ..unpack_options(dir.path().join("passphrase")) };
+        unpack_archive(&archive, &output, &options).await.unwrap();
+        let mode = |path: &str| std::fs::metadata(output.join(path)).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode("data"), 0o700);
+        assert_eq!(mode("data/nested"), 0o750);
+    }
+
+    #[tokio::test]
+    async fn tampered_archive_is_rejected_without_leaving_files() {
+        let dir = fixture();
+        let archive = pack_fixture(dir.path()).await;
+        let mut bytes = std::fs::read(&archive).unwrap();
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0x01;
+        std::fs::write(&archive, &bytes).unwrap();
+        let output = dir.path().join("out");
+        std::fs::create_dir(&output).unwrap();
+
+        let result = unpack_archive(&archive, &output, &unpack_options(dir.path().join("passphrase"))).await;
+        assert!(matches!(result, Err(TransferError::Crypto(_))), "{:?}", result.err());
+        assert!(!output.join("data/random.bin").exists());
+    }
+
+    #[tokio::test]
+    async fn wrong_passphrase_is_rejected() {
+        let dir = fixture();
+        let archive = pack_fixture(dir.path()).await;
+        std::fs::write(dir.path().join("wrong"), "battery staple\n").unwrap();
+
+        let options = UnpackOptions { verify_only: true, ..unpack_options(dir.path().join("wrong")) };
+        match unpack_archive(&archive, dir.path(), &options).await {
+            Err(TransferError::Crypto(message)) => assert!(message.contains("wrong passphrase"), "{}", message),
+            other => panic!("expected a passphrase error, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn excessive_kdf_costs_are_refused() {
+        let kdf = KdfParams { salt: [0; 16], m_cost: u32::MAX, t_cost: 1, p_cost: 1 };
+        assert!(matches!(kdf.derive_key("passphrase"), Err(TransferError::Integrity(_))));
+        let kdf = KdfParams { salt: [0; 16], m_cost: Params::DEFAULT_M_COST, t_cost: MAX_T_COST + 1, p_cost: 1 };
+        assert!(matches!(kdf.derive_key("passphrase"), Err(TransferError::Integrity(_))));
+    }
+}
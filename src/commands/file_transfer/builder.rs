@@ -0,0 +1,271 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use tokio_util::sync::CancellationToken;
+
+use super::events::Reporter;
+use super::{
+    Compression, Event, ExistingFilePolicy, ProgressCallback, ProgressMode, ReceiveOptions, SendOptions, TransferError, TransferReport, run_receiver, send_files,
+};
+use crate::utils::rate_limit::RateSchedule;
+
+/// Report a failure through the `error` event before handing it back to the caller.
+fn emit_failure(events: &Reporter, error: &TransferError) {
+    events.emit(&Event::Error { class: error.class(), exit_code: error.exit_code(), message: &error.to_string() });
+}
+
+/// Sends files to a receiver.
+///
+/// ```no_run
+/// # use nettool_rust::commands::file_transfer::{Compression, FileSender};
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// let report = FileSender::new("192.168.1.20", 9000)
+///     .file("photos")
+///     .compression(Compression::Zstd)
+///     .on_progress(|event| println!("{:?}", event))
+///     .send()
+///     .await?;
+/// println!("{}", report);
+/// # Ok(())
+/// # }
+/// ```
+pub struct FileSender {
+    host: String,
+    port: u16,
+    files: Vec<String>,
+    options: SendOptions,
+    on_progress: Option<ProgressCallback>,
+    cancel: CancellationToken,
+    console: bool,
+}
+
+impl FileSender {
+    /// A sender for the receiver at `host:port`, with default options and no progress output.
+    pub fn new(host: impl Into<String>, port: u16) -> FileSender {
+        FileSender {
+            host: host.into(),
+            port,
+            files: Vec::new(),
+            options: SendOptions { progress: ProgressMode::None, ..Default::default() },
+            on_progress: None,
+            cancel: CancellationToken::new(),
+            console: false,
+        }
+    }
+
+    /// Add a file or directory tree; `-` sends stdin.
+    pub fn file(mut self, path: impl Into<String>) -> FileSender {
+        self.files.push(path.into());
+        self
+    }
+
+    pub fn files<I, P>(mut self, paths: I) -> FileSender
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<String>,
+    {
+        self.files.extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// Replace every option at once.
+    pub fn options(mut self, options: SendOptions) -> FileSender {
+        self.options = options;
+        self
+    }
+
+    pub fn compression(mut self, compression: Compression) -> FileSender {
+        self.options.compression = compression;
+        self
+    }
+
+    pub fn streams(mut self, streams: u16) -> FileSender {
+        self.options.streams = streams;
+        self
+    }
+
+    pub fn chunk_size(mut self, chunk_size: usize) -> FileSender {
+        self.options.chunk_size = chunk_size;
+        self
+    }
+
+    pub fn preserve(mut self, preserve: bool) -> FileSender {
+        self.options.preserve = preserve;
+        self
+    }
+
+    pub fn stdin_name(mut self, name: impl Into<String>) -> FileSender {
+        self.options.stdin_name = name.into();
+        self
+    }
+
+    pub fn limit_rate(mut self, schedule: RateSchedule) -> FileSender {
+        self.options.limit_rate = Some(schedule);
+        self
+    }
+
+    /// How progress is shown on stderr; nothing by default.
+    pub fn progress(mut self, mode: ProgressMode) -> FileSender {
+        self.options.progress = mode;
+        self
+    }
+
+    /// Called with every [`Event`] of the transfer, including periodic progress.
+    pub fn on_progress(mut self, callback: impl Fn(&Event<'_>) + Send + Sync + 'static) -> FileSender {
+        self.on_progress = Some(Arc::new(callback));
+        self
+    }
+
+    /// Cancelling `token` stops the transfer with [`TransferError::Cancelled`].
+    pub fn cancel_token(mut self, token: CancellationToken) -> FileSender {
+        self.cancel = token;
+        self
+    }
+
+    /// Print status lines for a person at a terminal, as the command line does.
+    pub(super) fn console(mut self) -> FileSender {
+        self.console = true;
+        self
+    }
+
+    pub async fn send(self) -> Result<TransferReport, TransferError> {
+        let events = Reporter::new(self.options.progress, self.on_progress, self.console);
+        let sent = tokio::select! {
+            sent = send_files(&self.files, &self.host, self.port, &self.options, &events) => sent,
+            _ = self.cancel.cancelled() => Err(TransferError::Cancelled),
+        };
+        if let Err(e) = &sent {
+            emit_failure(&events, e);
+        }
+        sent
+    }
+}
+
+/// Receives files from senders into a directory.
+///
+/// Partial files are removed when the transfer fails or is cancelled, unless
+/// [`ReceiveOptions::keep_partial`] is set.
+pub struct FileReceiver {
+    port: u16,
+    output_dir: PathBuf,
+    options: ReceiveOptions,
+    on_progress: Option<ProgressCallback>,
+    cancel: CancellationToken,
+    console: bool,
+}
+
+impl FileReceiver {
+    /// A receiver listening on `port` and writing under `output_dir` (`-` for stdout).
+    pub fn new(port: u16, output_dir: impl Into<PathBuf>) -> FileReceiver {
+        FileReceiver {
+            port,
+            output_dir: output_dir.into(),
+            options: ReceiveOptions { progress: ProgressMode::None, ..Default::default() },
+            on_progress: None,
+            cancel: CancellationToken::new(),
+            console: false,
+        }
+    }
+
+    /// Replace every option at once.
+    pub fn options(mut self, options: ReceiveOptions) -> FileReceiver {
+        self.options = options;
+        self
+    }
+
+    /// How the receiver treats files that already exist.
+    pub fn policy(mut self, policy: ExistingFilePolicy) -> FileReceiver {
+        self.options.policy = policy;
+        self
+    }
+
+    pub fn per_sender_dirs(mut self, enabled: bool) -> FileReceiver {
+        self.options.per_sender_dirs = enabled;
+        self
+    }
+
+    pub fn max_file_size(mut self, bytes: u64) -> FileReceiver {
+        self.options.max_file_size = Some(bytes);
+        self
+    }
+
+    pub fn quota(mut self, bytes: u64) -> FileReceiver {
+        self.options.quota = Some(bytes);
+        self
+    }
+
+    pub fn limit_rate(mut self, schedule: RateSchedule) -> FileReceiver {
+        self.options.limit_rate = Some(schedule);
+        self
+    }
+
+    pub fn keep_partial(mut self, keep: bool) -> FileReceiver {
+        self.options.keep_partial = keep;
+        self
+    }
+
+    /// Apply ownership, extended attributes and setuid, setgid and sticky bits
+    /// from senders that preserve them. Off by default; only for trusted senders.
+    pub fn restore_privileged(mut self, enabled: bool) -> FileReceiver {
+        self.options.restore_privileged = enabled;
+        self
+    }
+
+    /// How progress is shown on stderr; nothing by default.
+    pub fn progress(mut self, mode: ProgressMode) -> FileReceiver {
+        self.options.progress = mode;
+        self
+    }
+
+    /// Called with every [`Event`] of the transfer, including periodic progress.
+    pub fn on_progress(mut self, callback: impl Fn(&Event<'_>) + Send + Sync + 'static) -> FileReceiver {
+        self.on_progress = Some(Arc::new(callback));
+        self
+    }
+
+    /// Cancelling `token` stops the receiver and removes the partial files of any upload in progress.
+    pub fn cancel_token(mut self, token: CancellationToken) -> FileReceiver {
+        self.cancel = token;
+        self
+    }
+
+    /// Print status lines for a person at a terminal, as the command line does.
+    pub(super) fn console(mut self) -> FileReceiver {
+        self.console = true;
+        self
+    }
+
+    fn reporter(&self) -> Reporter {
+        Reporter::new(self.options.progress, self.on_progress.clone(), self.console)
+    }
+
+    /// Wait for one upload and return its report.
+    pub async fn receive(mut self) -> Result<TransferReport, TransferError> {
+        self.options.serve = false;
+        let events = self.reporter();
+        let output_dir = self.output_dir.to_string_lossy();
+        let received = run_receiver(self.port, &output_dir, self.options, events.clone(), self.cancel).await;
+        match received {
+            Ok(Some(report)) => Ok(report),
+            Ok(None) => unreachable!("a single-upload receiver always reports its upload"),
+            Err(e) => {
+                emit_failure(&events, &e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Accept concurrent uploads, logging each through `tracing`, until the cancel token fires.
+    pub async fn serve(mut self) -> Result<(), TransferError> {
+        self.options.serve = true;
+        let events = self.reporter();
+        let output_dir = self.output_dir.to_string_lossy();
+        match run_receiver(self.port, &output_dir, self.options, events.clone(), self.cancel).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                emit_failure(&events, &e);
+                Err(e)
+            }
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use serde::{Deserialize, Serialize};
+
+use crate::utils::networking::MAX_FRAME_SIZE;
+
+const TAG_RAW: u8 = 0;
+const TAG_ZSTD: u8 = 1;
+const TAG_LZ4: u8 = 2;
+const TAG_HOLE: u8 = 3;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Per-chunk compression applied before encryption.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Better ratio, moderate CPU cost.
+    Zstd,
+    /// Lower ratio, very fast.
+    Lz4,
+}
+
+/// Compress `data` with `algorithm` and prefix it with a one-byte tag.
+///
+/// Chunks that do not shrink (already-compressed media, random data) are sent
+/// raw so the receiver never pays to decompress them.
+pub fn encode_chunk(data: &[u8], algorithm: Compression) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let compressed = match algorithm {
+        Compression::None => None,
+        Compression::Zstd => Some((TAG_ZSTD, zstd::bulk::compress(data, ZSTD_LEVEL)?)),
+        Compression::Lz4 => Some((TAG_LZ4, lz4_flex::compress_prepend_size(data))),
+    };
+
+    let (tag, body) = match compressed {
+        Some((tag, body)) if body.len() < data.len() => (tag, body),
+        _ => (TAG_RAW, data.to_vec()),
+    };
+
+    let mut out = Vec::with_capacity(body.len() + 1);
+    out.push(tag);
+    out.extend_from_slice(&body);
+    Ok(out)
+}
+
+/// A run of `len` zero bytes, sent in place of the data when skipping holes in sparse files.
+pub fn encode_hole(len: u64) -> Vec<u8> {
+    let mut out = Vec::with_capacity(9);
+    out.push(TAG_HOLE);
+    out.extend_from_slice(&len.to_le_bytes());
+    out
+}
+
+/// A decoded chunk of file data.
+#[derive(Debug)]
+pub enum Chunk {
+    Data(Vec<u8>),
+    /// This many zero bytes, which the receiver skips over rather than writes.
+    Hole(u64),
+}
+
+/// Reverse [`encode_chunk`] and [`encode_hole`], refusing to inflate past the frame size limit.
+pub fn decode_chunk(data: &[u8]) -> Result<Chunk, Box<dyn std::error::Error + Send + Sync>> {
+    let (&tag, body) = data.split_first().ok_or("empty chunk")?;
+    let data = match tag {
+        TAG_RAW => body.to_vec(),
+        TAG_ZSTD => zstd::bulk::decompress(body, MAX_FRAME_SIZE)?,
+        TAG_LZ4 => {
+            let declared = body
+                .get(..4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+                .ok_or("truncated lz4 chunk")?;
+            if declared > MAX_FRAME_SIZE {
+                return Err(format!("lz4 chunk claims {} bytes, over the limit", declared).into());
+            }
+            lz4_flex::decompress_size_prepended(body)?
+        }
+        TAG_HOLE => {
+            let len: [u8; 8] = body.try_into().map_err(|_| "malformed hole chunk")?;
+            return Ok(Chunk::Hole(u64::from_le_bytes(len)));
+        }
+        other => return Err(format!("unknown chunk compression tag {}", other).into()),
+    };
+    Ok(Chunk::Data(data))
+}
+
+/// Byte counters used for the end-of-transfer summary.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TransferStats {
+    /// Plaintext file bytes.
+    pub file_bytes: u64,
+    /// Bytes of file data frames as they went over the socket.
+    pub wire_bytes: u64,
+    /// File bytes rebuilt from blocks the receiver already had.
+    pub reused_bytes: u64,
+}
+
+impl TransferStats {
+    /// Combine the counters of two streams of the same transfer.
+    pub fn merge(self, other: TransferStats) -> TransferStats {
+        TransferStats {
+            file_bytes: self.file_bytes + other.file_bytes,
+            wire_bytes: self.wire_bytes + other.wire_bytes,
+            reused_bytes: self.reused_bytes + other.reused_bytes,
+        }
+    }
+
+    pub fn summary(&self, algorithm: Compression) -> String {
+        let ratio = if self.wire_bytes == 0 {
+            1.0
+        } else {
+            self.file_bytes as f64 / self.wire_bytes as f64
+        };
+        let mut summary = format!(
+            "Compression {:?}: {} file bytes, {} wire bytes (ratio {:.2}x)",
+            algorithm, self.file_bytes, self.wire_bytes, ratio
+        );
+        if self.reused_bytes > 0 {
+            summary.push_str(&format!(", {} bytes reused from existing files", self.reused_bytes));
+        }
+        summary
+    }
+}
@@ -0,0 +1,469 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom},
+    net::TcpStream,
+};
+
+use super::compression::{self, Chunk};
+use super::manifest::{EntryKind, ManifestEntry};
+use super::protocol;
+use super::{AES_KEY, ExistingFilePolicy, StreamContext, TransferError, paths};
+use crate::utils::encryption::decrypt_chunk;
+use crate::utils::networking::{read_frame, write_encrypted_frame};
+
+/// Smallest block the receiver will checksum; files below this are always sent whole.
+pub const MIN_BLOCK_SIZE: u32 = 1024;
+/// Largest block a sender accepts in a signature.
+const MAX_BLOCK_SIZE: u32 = 1 << 20;
+/// Keeps a signature comfortably inside a single frame.
+const MAX_BLOCKS: u64 = 400_000;
+
+/// Checksums of one block of the receiver's existing file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: [u8; 16],
+}
+
+/// Block checksums of the receiver's copy of file `index` (counted among regular files in the manifest).
+///
+/// The receiver sends one `Some(Signature)` per file it already has and
+/// waits for that file's delta before sending the next; `None` ends the phase.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Signature {
+    pub index: u32,
+    pub block_size: u32,
+    /// Only whole blocks; a trailing partial block is never matched.
+    pub blocks: Vec<BlockSignature>,
+}
+
+/// Instructions for rebuilding a file from the receiver's old copy.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DeltaOp {
+    /// Copy `count` consecutive blocks starting at `block` from the old file.
+    Copy { block: u64, count: u64 },
+    /// New bytes not found in the old file.
+    Literal(Vec<u8>),
+    /// SHA-256 of the complete new file.
+    End { digest: Vec<u8> },
+}
+
+/// rsync-style rolling checksum over a fixed-size window.
+#[derive(Debug, Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Rolling {
+        let len = window.len() as u32;
+        let mut a = 0u32;
+        let mut b = 0u32;
+        for (i, &byte) in window.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(byte as u32));
+        }
+        Rolling { a, b, len }
+    }
+
+    /// Slide the window one byte: drop `out` from the front, append `incoming`.
+    fn roll(self, out: u8, incoming: u8) -> Rolling {
+        let a = self.a.wrapping_sub(out as u32).wrapping_add(incoming as u32);
+        let b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(a);
+        Rolling { a, b, len: self.len }
+    }
+
+    fn digest(self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong_hash(block: &[u8]) -> [u8; 16] {
+    let mut strong = [0u8; 16];
+    strong.copy_from_slice(&Sha256::digest(block)[..16]);
+    strong
+}
+
+/// Block size for a basis file of `size` bytes: about `sqrt(size)`, as rsync does.
+fn block_size_for(size: u64) -> u32 {
+    let by_sqrt = ((size as f64).sqrt() as u64).next_power_of_two();
+    let by_count = size.div_ceil(MAX_BLOCKS);
+    by_sqrt.max(by_count).clamp(MIN_BLOCK_SIZE as u64, MAX_BLOCK_SIZE as u64) as u32
+}
+
+/// The existing file that `entry` would replace, if it is worth delta-updating.
+///
+/// Only plain files overwritten in place qualify; anything unusual falls back
+/// to [`super::resolve_destination`], which reports it properly.
+pub fn basis(root: &Path, entry: &ManifestEntry, policy: ExistingFilePolicy) -> Option<PathBuf> {
+    if policy != ExistingFilePolicy::Overwrite || entry.kind != EntryKind::File || entry.size < MIN_BLOCK_SIZE as u64 {
+        return None;
+    }
+    let relative = paths::sanitize_relative(&entry.path).ok()?;
+    paths::check_no_symlink_ancestors(root, &relative).ok()?;
+    let target = root.join(relative);
+    let metadata = target.symlink_metadata().ok()?;
+    (metadata.is_file() && metadata.len() >= MIN_BLOCK_SIZE as u64).then_some(target)
+}
+
+async fn signature_of(path: &Path, index: u32) -> Result<Signature, Box<dyn std::error::Error + Send + Sync>> {
+    let mut file = File::open(path).await?;
+    let block_size = block_size_for(file.metadata().await?.len());
+    let mut buffer = vec![0u8; block_size as usize];
+    let mut blocks = Vec::new();
+    loop {
+        match file.read_exact(&mut buffer).await {
+            Ok(_) => blocks.push(BlockSignature { weak: Rolling::new(&buffer).digest(), strong: strong_hash(&buffer) }),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(Signature { index, block_size, blocks })
+}
+
+async fn write_op(stream: &mut TcpStream, context: &mut StreamContext, op: &DeltaOp) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let encoded = bincode::serialize(op)?;
+    let payload = compression::encode_chunk(&encoded, context.negotiated.compression)?;
+    let wire = write_encrypted_frame(stream, &payload, AES_KEY).await?;
+    context.wire(wire).await;
+    Ok(())
+}
+
+async fn read_op(socket: &mut TcpStream, context: &mut StreamContext) -> Result<DeltaOp, Box<dyn std::error::Error + Send + Sync>> {
+    let frame = read_frame(socket).await?.ok_or("connection closed mid-delta")?;
+    context.wire(frame.len() + 4).await;
+    let Chunk::Data(encoded) = compression::decode_chunk(&decrypt_chunk(&frame, AES_KEY)?)? else {
+        return Err("unexpected hole in delta stream".into());
+    };
+    bincode::deserialize(&encoded).map_err(|e| format!("malformed delta instruction: {}", e).into())
+}
+
+/// Sender side: emit the ops that turn the receiver's old file (described by `signature`)
+/// into the `size`-byte file at `source`.
+pub async fn send_file(
+    stream: &mut TcpStream,
+    context: &mut StreamContext,
+    source: &Path,
+    size: u64,
+    signature: &Signature,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&signature.block_size) {
+        return Err(format!("receiver sent an invalid block size of {}", signature.block_size).into());
+    }
+    let block = signature.block_size as usize;
+    let mut lookup: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, sig) in signature.blocks.iter().enumerate() {
+        lookup.entry(sig.weak).or_default().push(i);
+    }
+
+    let mut file = File::open(source).await?.take(size);
+    let mut hasher = Sha256::new();
+    let mut read_buffer = vec![0u8; block.max(64 * 1024)];
+    // `buffer[literal..pos]` is unmatched data, `buffer[pos..pos + block]` the window being checked.
+    let mut buffer = Vec::new();
+    let mut literal = 0;
+    let mut pos = 0;
+    let mut eof = false;
+    let mut rolling: Option<Rolling> = None;
+    let mut copy: Option<(u64, u64)> = None;
+    let mut total = 0u64;
+
+    loop {
+        if buffer.len() - pos < block && !eof {
+            buffer.drain(..literal);
+            pos -= literal;
+            literal = 0;
+            let n = file.read(&mut read_buffer).await?;
+            if n == 0 {
+                eof = true;
+            } else {
+                hasher.update(&read_buffer[..n]);
+                buffer.extend_from_slice(&read_buffer[..n]);
+                total += n as u64;
+            }
+            continue;
+        }
+        if buffer.len() - pos < block {
+            break;
+        }
+
+        let window = &buffer[pos..pos + block];
+        let weak = rolling.unwrap_or_else(|| Rolling::new(window));
+        let matched = lookup.get(&weak.digest()).and_then(|candidates| {
+            let strong = strong_hash(window);
+            candidates.iter().copied().find(|&i| signature.blocks[i].strong == strong)
+        });
+
+        if let Some(index) = matched {
+            flush_literal(stream, context, &mut copy, &buffer[literal..pos]).await?;
+            copy = match copy {
+                Some((start, count)) if start + count == index as u64 => Some((start, count + 1)),
+                Some((start, count)) => {
+                    write_op(stream, context, &DeltaOp::Copy { block: start, count }).await?;
+                    Some((index as u64, 1))
+                }
+                None => Some((index as u64, 1)),
+            };
+            context.stats.reused_bytes += block as u64;
+            pos += block;
+            literal = pos;
+            rolling = None;
+            continue;
+        }
+
+        rolling = (pos + block < buffer.len()).then(|| weak.roll(buffer[pos], buffer[pos + block]));
+        pos += 1;
+        if pos - literal >= context.chunk_size {
+            flush_literal(stream, context, &mut copy, &buffer[literal..pos]).await?;
+            literal = pos;
+        }
+    }
+
+    let tail = buffer[literal..].to_vec();
+    flush_literal(stream, context, &mut copy, &tail).await?;
+    if let Some((start, count)) = copy {
+        write_op(stream, context, &DeltaOp::Copy { block: start, count }).await?;
+    }
+    if total != size {
+        return Err(format!("file shrank while sending ({} of {} bytes)", total, size).into());
+    }
+    write_op(stream, context, &DeltaOp::End { digest: hasher.finalize().to_vec() }).await?;
+
+    context.stats.file_bytes += size;
+    context.current.set_position(size);
+    context.overall.inc(size);
+    Ok(())
+}
+
+/// Send pending unmatched bytes, after any run of copied blocks that precedes them.
+async fn flush_literal(
+    stream: &mut TcpStream,
+    context: &mut StreamContext,
+    copy: &mut Option<(u64, u64)>,
+    data: &[u8],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    if let Some((start, count)) = copy.take() {
+        write_op(stream, context, &DeltaOp::Copy { block: start, count }).await?;
+    }
+    for chunk in data.chunks(context.chunk_size) {
+        write_op(stream, context, &DeltaOp::Literal(chunk.to_vec())).await?;
+    }
+    Ok(())
+}
+
+/// Receiver side: offer the signature of the existing file at `basis` and rebuild it from
+/// the sender's delta.
+///
+/// The new contents go to a hidden file in the same directory, which replaces
+/// `basis` only once its digest has been verified. That file is recorded in
+/// `partials` while it exists, so it is cleaned up like any partial file.
+/// Returns the hex SHA-256.
+pub async fn receive_file(
+    socket: &mut TcpStream,
+    context: &mut StreamContext,
+    index: u32,
+    basis: &Path,
+    size: u64,
+    partials: &mut Vec<PathBuf>,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let signature = signature_of(basis, index).await?;
+    protocol::write_message(socket, &Some(&signature), AES_KEY).await?;
+
+    let name = basis.file_name().ok_or("destination has no file name")?.to_string_lossy();
+    let temp = basis.with_file_name(format!(".{}.{}.ntdelta", name, protocol::to_hex(&protocol::new_session_id()[..4])));
+    // Recorded before creation so a cancelled update is cleaned up too.
+    partials.push(temp.clone());
+    let digest = rebuild(socket, context, &signature, basis, &temp, size).await?;
+    tokio::fs::rename(&temp, basis).await?;
+    partials.retain(|path| *path != temp);
+    Ok(digest)
+}
+
+async fn rebuild(
+    socket: &mut TcpStream,
+    context: &mut StreamContext,
+    signature: &Signature,
+    basis: &Path,
+    temp: &Path,
+    size: u64,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut old = File::open(basis).await?;
+    let mut new = OpenOptions::new().write(true).create_new(true).open(temp).await?;
+    let block_size = signature.block_size as u64;
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut hasher = Sha256::new();
+    let mut written = 0u64;
+
+    loop {
+        match read_op(socket, context).await? {
+            DeltaOp::Copy { block, count } => {
+                let end = block.checked_add(count).ok_or("delta copies past the end of the old file")?;
+                if end > signature.blocks.len() as u64 || written + count * block_size > size {
+                    return Err("delta copies past the end of the old file".into());
+                }
+                old.seek(SeekFrom::Start(block * block_size)).await?;
+                let mut remaining = count * block_size;
+                while remaining > 0 {
+                    let n = remaining.min(buffer.len() as u64) as usize;
+                    old.read_exact(&mut buffer[..n]).await?;
+                    hasher.update(&buffer[..n]);
+                    new.write_all(&buffer[..n]).await?;
+                    remaining -= n as u64;
+                }
+                written += count * block_size;
+                context.stats.reused_bytes += count * block_size;
+                context.overall.inc(count * block_size);
+            }
+            DeltaOp::Literal(data) => {
+                if written + data.len() as u64 > size {
+                    return Err("sender sent more data than announced".into());
+                }
+                hasher.update(&data);
+                new.write_all(&data).await?;
+                written += data.len() as u64;
+                context.overall.inc(data.len() as u64);
+            }
+            DeltaOp::End { digest } => {
+                let actual = hasher.finalize();
+                if written != size {
+                    return Err(format!("delta produced {} of {} bytes", written, size).into());
+                }
+                if digest.as_slice() != actual.as_slice() {
+                    return Err(TransferError::Integrity("SHA-256 digest mismatch, file is corrupt".to_string()).into());
+                }
+                new.flush().await?;
+                new.sync_all().await?;
+                context.stats.file_bytes += size;
+                return Ok(protocol::to_hex(&actual));
+            }
+        }
+        context.current.set_position(written);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+    use tokio::net::TcpListener;
+
+    use super::super::events::Reporter;
+    use super::super::protocol::{Capabilities, Negotiated};
+    use super::super::{CHUNK_SIZE, Compression, Partials};
+    use super::*;
+
+    fn random_bytes(rng: &mut StdRng, len: usize) -> Vec<u8> {
+        (0..len).map(|_| rng.r#gen()).collect()
+    }
+
+    fn context() -> StreamContext {
+        let negotiated = Negotiated { capabilities: Capabilities::DELTA, compression: Compression::None, streams: 1 };
+        StreamContext::new(negotiated, CHUNK_SIZE, None, "green/white", &Reporter::silent())
+    }
+
+    #[test]
+    fn rolling_matches_a_full_recompute() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let data = random_bytes(&mut rng, 4096);
+        for window in [1, 16, 1000] {
+            let mut rolling = Rolling::new(&data[..window]);
+            for start in 1..data.len() - window {
+                rolling = rolling.roll(data[start - 1], data[start + window - 1]);
+                let full = Rolling::new(&data[start..start + window]);
+                assert_eq!((rolling.a, rolling.b, rolling.digest()), (full.a, full.b, full.digest()), "window {} at {}", window, start);
+            }
+        }
+    }
+
+    /// Send `new` as a delta against `old`, returning the rebuilt file, the digest and the bytes reused.
+    async fn round_trip(old: &[u8], new: &[u8]) -> (Vec<u8>, String, u64) {
+        let dir = tempfile::tempdir().unwrap();
+        let basis = dir.path().join("file.bin");
+        let source = dir.path().join("source.bin");
+        std::fs::write(&basis, old).unwrap();
+        std::fs::write(&source, new).unwrap();
+        let size = new.len() as u64;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let sender = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            let signature: Option<Signature> = protocol::read_message(&mut stream, AES_KEY).await.unwrap();
+            send_file(&mut stream, &mut context(), &source, size, &signature.unwrap()).await.unwrap();
+        });
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut context = context();
+        let mut partials = Vec::new();
+        let digest = receive_file(&mut socket, &mut context, 0, &basis, size, &mut partials).await.unwrap();
+        sender.await.unwrap();
+
+        assert!(partials.is_empty(), "temporary file still recorded: {:?}", partials);
+        let names: Vec<_> = std::fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(names.len(), 2, "left behind: {:?}", names);
+        (std::fs::read(&basis).unwrap(), digest, context.stats.reused_bytes)
+    }
+
+    #[tokio::test]
+    async fn delta_round_trip_rebuilds_the_new_file() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let old = random_bytes(&mut rng, 300_000);
+        let mut new = old.clone();
+        new.splice(100_000..100_000, random_bytes(&mut rng, 777));
+        new[200_000..201_000].copy_from_slice(&random_bytes(&mut rng, 1000));
+        new.truncate(280_000);
+        new.extend(random_bytes(&mut rng, 5000));
+
+        let (rebuilt, digest, reused) = round_trip(&old, &new).await;
+        assert!(rebuilt == new, "rebuilt file differs");
+        assert_eq!(digest, protocol::to_hex(&Sha256::digest(&new)));
+        assert!(reused > 200_000, "only {} bytes reused", reused);
+    }
+
+    #[tokio::test]
+    async fn delta_round_trip_handles_unrelated_contents() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let old = random_bytes(&mut rng, 50_000);
+        let new = random_bytes(&mut rng, 70_001);
+        let (rebuilt, _, reused) = round_trip(&old, &new).await;
+        assert!(rebuilt == new, "rebuilt file differs");
+        assert_eq!(reused, 0);
+    }
+
+    #[tokio::test]
+    async fn failed_delta_leaves_its_temporary_file_to_the_guard() {
+        let dir = tempfile::tempdir().unwrap();
+        let basis = dir.path().join("file.bin");
+        std::fs::write(&basis, vec![1u8; 10_000]).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let sender = tokio::spawn(async move {
+            // Take the signature, then hang up without sending a delta.
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            let _: Option<Signature> = protocol::read_message(&mut stream, AES_KEY).await.unwrap();
+        });
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut partials = Partials { paths: Vec::new(), keep: false };
+        let result = receive_file(&mut socket, &mut context(), 0, &basis, 10_000, &mut partials.paths).await;
+        sender.await.unwrap();
+
+        assert!(result.is_err());
+        let [temp] = partials.paths.as_slice() else { panic!("expected one temporary file, got {:?}", partials.paths) };
+        assert!(temp.exists());
+        let temp = temp.clone();
+        drop(partials);
+        assert!(!temp.exists());
+        assert_eq!(std::fs::read(&basis).unwrap(), vec![1u8; 10_000]);
+    }
+}
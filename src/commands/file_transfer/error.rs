@@ -0,0 +1,116 @@
+use std::{error::Error, fmt};
+
+/// Why a file transfer failed.
+///
+/// Each variant has its own process exit code, see [`TransferError::exit_code`]:
+///
+/// | code | variant |
+/// |------|---------|
+/// | 0 | success |
+/// | 1 | [`TransferError::Local`] |
+/// | 2 | invalid command line (reported by the argument parser before any transfer starts) |
+/// | 3 | [`TransferError::Connect`] |
+/// | 4 | [`TransferError::Protocol`] |
+/// | 5 | [`TransferError::Io`] |
+/// | 6 | [`TransferError::Integrity`] |
+/// | 7 | [`TransferError::Crypto`] |
+/// | 130 | [`TransferError::Cancelled`] |
+#[derive(Debug)]
+pub enum TransferError {
+    /// Invalid options or unusable local paths, found before any data moved.
+    Local(String),
+    /// Could not connect to the peer or listen on the port.
+    Connect(String),
+    /// The handshake failed, or the peer refused the transfer or broke the protocol.
+    Protocol(String),
+    /// Reading or writing a file or the socket failed, including the connection dropping mid-transfer.
+    Io(String),
+    /// Received data did not match the sender's SHA-256 digest.
+    Integrity(String),
+    /// A frame could not be decrypted.
+    Crypto(String),
+    /// Stopped through the transfer's cancellation token.
+    Cancelled,
+}
+
+impl TransferError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            TransferError::Local(_) => 1,
+            TransferError::Connect(_) => 3,
+            TransferError::Protocol(_) => 4,
+            TransferError::Io(_) => 5,
+            TransferError::Integrity(_) => 6,
+            TransferError::Crypto(_) => 7,
+            TransferError::Cancelled => 130,
+        }
+    }
+
+    /// Lowercase name of the variant, as used in `error` events.
+    pub fn class(&self) -> &'static str {
+        match self {
+            TransferError::Local(_) => "local",
+            TransferError::Connect(_) => "connect",
+            TransferError::Protocol(_) => "protocol",
+            TransferError::Io(_) => "io",
+            TransferError::Integrity(_) => "integrity",
+            TransferError::Crypto(_) => "crypto",
+            TransferError::Cancelled => "cancelled",
+        }
+    }
+
+    /// Prefix the message with `context`, keeping the variant.
+    pub(super) fn context(self, context: impl fmt::Display) -> TransferError {
+        let prefix = |message: String| format!("{}: {}", context, message);
+        match self {
+            TransferError::Local(m) => TransferError::Local(prefix(m)),
+            TransferError::Connect(m) => TransferError::Connect(prefix(m)),
+            TransferError::Protocol(m) => TransferError::Protocol(prefix(m)),
+            TransferError::Io(m) => TransferError::Io(prefix(m)),
+            TransferError::Integrity(m) => TransferError::Integrity(prefix(m)),
+            TransferError::Crypto(m) => TransferError::Crypto(prefix(m)),
+            TransferError::Cancelled => TransferError::Cancelled,
+        }
+    }
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::Local(m)
+            | TransferError::Connect(m)
+            | TransferError::Protocol(m)
+            | TransferError::Io(m)
+            | TransferError::Integrity(m)
+            | TransferError::Crypto(m) => f.write_str(m),
+            TransferError::Cancelled => f.write_str("transfer cancelled"),
+        }
+    }
+}
+
+impl Error for TransferError {}
+
+/// Recover the variant an internal error was raised as. Untagged errors come
+/// from the socket or the filesystem part-way through, so they count as I/O.
+impl From<Box<dyn Error + Send + Sync>> for TransferError {
+    fn from(error: Box<dyn Error + Send + Sync>) -> Self {
+        match error.downcast::<TransferError>() {
+            Ok(error) => *error,
+            Err(error) => TransferError::Io(error.to_string()),
+        }
+    }
+}
+
+/// Prefix an internal `error` with `context`, keeping its variant.
+pub(super) fn context(error: Box<dyn Error + Send + Sync>, context: impl fmt::Display) -> Box<dyn Error + Send + Sync> {
+    Box::new(TransferError::from(error).context(context))
+}
+
+/// Prefix a handshake `error` with `context`. Untagged errors become [`TransferError::Protocol`]
+/// here, since until the handshake is over the peer may not be speaking our protocol at all.
+pub(super) fn handshake(error: Box<dyn Error + Send + Sync>, context: impl fmt::Display) -> TransferError {
+    match error.downcast::<TransferError>() {
+        Ok(error) => error.context(context),
+        Err(error) => TransferError::Protocol(format!("{}: {}", context, error)),
+    }
+}
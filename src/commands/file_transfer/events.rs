@@ -0,0 +1,135 @@
+use std::{
+    io::{IsTerminal, Write},
+    sync::Arc,
+    time::Duration,
+};
+
+use indicatif::ProgressBar;
+use serde::Serialize;
+use tokio::task::JoinHandle;
+
+/// How often the running byte count is reported.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How transfer progress is reported on stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ProgressMode {
+    /// Progress bars, drawn only when stderr is a terminal.
+    #[default]
+    Bar,
+    /// JSON Lines events, one object per line.
+    Json,
+    /// Nothing but the final summary.
+    None,
+}
+
+/// Called with every [`Event`] of a transfer, from whichever task produced it.
+pub type ProgressCallback = Arc<dyn Fn(&Event<'_>) + Send + Sync>;
+
+/// Something that happened during a transfer; also one line of `--progress json` output,
+/// tagged by its `event` field.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    Started {
+        files: usize,
+        /// `None` when sending or receiving a stream of unknown length.
+        total_bytes: Option<u64>,
+    },
+    /// Sent periodically while data is moving, and once more at the end.
+    Progress {
+        bytes: u64,
+        total_bytes: Option<u64>,
+        bytes_per_sec: u64,
+        eta_secs: Option<u64>,
+    },
+    FileCompleted {
+        path: &'a str,
+        size: u64,
+    },
+    /// An entry was left out of the transfer.
+    Skipped {
+        path: &'a str,
+        reason: &'a str,
+    },
+    /// The receiver's digest of a file matched the one the sender computed.
+    Verified {
+        path: &'a str,
+        sha256: &'a str,
+    },
+    Error {
+        /// [`TransferError::class`](super::TransferError::class) of the failure.
+        class: &'a str,
+        exit_code: i32,
+        message: &'a str,
+    },
+}
+
+impl Event<'_> {
+    /// A snapshot of `overall`, which keeps counting (and estimating the rate) even while hidden.
+    fn progress(overall: &ProgressBar) -> Event<'static> {
+        let total = overall.length();
+        Event::Progress {
+            bytes: overall.position(),
+            total_bytes: total,
+            bytes_per_sec: overall.per_sec() as u64,
+            eta_secs: total.map(|_| overall.eta().as_secs()),
+        }
+    }
+}
+
+/// Where the progress of one transfer goes: bars, JSON lines and/or a callback.
+#[derive(Clone)]
+pub(super) struct Reporter {
+    mode: ProgressMode,
+    callback: Option<ProgressCallback>,
+    /// Print status lines and summaries for a person at a terminal (the CLI), not for a library caller.
+    pub console: bool,
+}
+
+impl Reporter {
+    pub fn new(mode: ProgressMode, callback: Option<ProgressCallback>, console: bool) -> Reporter {
+        Reporter { mode, callback, console }
+    }
+
+    /// Reports nothing at all.
+    pub fn silent() -> Reporter {
+        Reporter::new(ProgressMode::None, None, false)
+    }
+
+    pub fn draws_bars(&self) -> bool {
+        self.mode == ProgressMode::Bar && std::io::stderr().is_terminal()
+    }
+
+    /// Pass `event` to the callback, and to stderr as a JSON line in `Json` mode.
+    pub fn emit(&self, event: &Event<'_>) {
+        if let Some(callback) = &self.callback {
+            callback(event);
+        }
+        if self.mode == ProgressMode::Json
+            && let Ok(line) = serde_json::to_string(event)
+        {
+            let _ = writeln!(std::io::stderr().lock(), "{}", line);
+        }
+    }
+
+    /// Emit a progress snapshot of `overall` now.
+    pub fn progress(&self, overall: &ProgressBar) {
+        self.emit(&Event::progress(overall));
+    }
+
+    /// Report `overall`'s position every [`PROGRESS_INTERVAL`] until the returned task is aborted.
+    pub fn spawn_ticker(&self, overall: ProgressBar) -> Option<JoinHandle<()>> {
+        if self.mode != ProgressMode::Json && self.callback.is_none() {
+            return None;
+        }
+        let reporter = self.clone();
+        Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PROGRESS_INTERVAL);
+            loop {
+                interval.tick().await;
+                reporter.progress(&overall);
+            }
+        }))
+    }
+}
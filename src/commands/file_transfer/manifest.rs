@@ -0,0 +1,248 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// What kind of filesystem object a manifest entry describes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink { target: String },
+    /// File of unknown length read from a pipe; `size` is 0 and the data ends with an empty chunk.
+    Stream,
+}
+
+/// A single file, directory or symlink being transferred.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Path relative to the transfer root, `/`-separated.
+    pub path: String,
+    pub kind: EntryKind,
+    pub size: u64,
+    pub mode: u32,
+    /// Modification time in seconds since the Unix epoch.
+    pub mtime: i64,
+    /// Collected only when the sender asked for metadata to be preserved.
+    pub extra: Option<ExtraMetadata>,
+}
+
+/// Metadata beyond mode and mtime, carried with `--preserve`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExtraMetadata {
+    /// Access time in seconds since the Unix epoch.
+    pub atime: i64,
+    pub uid: u32,
+    pub gid: u32,
+    /// Extended attributes as `(name, value)` pairs.
+    pub xattrs: Vec<(String, Vec<u8>)>,
+}
+
+/// List of everything that follows on the wire. File contents are streamed
+/// afterwards in manifest order, one file at a time.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+    /// Sockets, FIFOs and devices that [`Manifest::build`] came across and left out.
+    /// Only known to the sender; never sent.
+    #[serde(skip)]
+    pub special: Vec<PathBuf>,
+}
+
+impl Manifest {
+    /// Walk every path in `paths`, recursing into directories without following symlinks.
+    /// Special files are listed in `special` instead of `entries`.
+    ///
+    /// With `preserve`, each entry also records atime, ownership and extended attributes.
+    pub fn build<P: AsRef<Path>>(paths: &[P], preserve: bool) -> Result<(Self, Vec<PathBuf>), Box<dyn std::error::Error + Send + Sync>> {
+        let mut manifest = Manifest::default();
+        let mut sources = Vec::new();
+
+        for path in paths {
+            let path = path.as_ref();
+            let name = path
+                .file_name()
+                .ok_or_else(|| format!("Cannot determine a name for '{}'", path.display()))?
+                .to_string_lossy()
+                .into_owned();
+            manifest.add(path, name, preserve, &mut sources)?;
+        }
+
+        Ok((manifest, sources))
+    }
+
+    fn add(
+        &mut self,
+        path: &Path,
+        relative: String,
+        preserve: bool,
+        sources: &mut Vec<PathBuf>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let Some(mut entry) = describe(path, relative)? else {
+            self.special.push(path.to_path_buf());
+            return Ok(());
+        };
+
+        if preserve {
+            entry.extra = Some(extra_of(path)?);
+        }
+        let kind = entry.kind.clone();
+        let relative = entry.path.clone();
+        self.entries.push(entry);
+
+        match kind {
+            EntryKind::File => sources.push(path.to_path_buf()),
+            EntryKind::Dir => {
+                let mut children: Vec<_> = fs::read_dir(path)?.collect::<Result<_, _>>()?;
+                children.sort_by_key(|c| c.file_name());
+                for child in children {
+                    let child_relative = format!("{}/{}", relative, child.file_name().to_string_lossy());
+                    self.add(&child.path(), child_relative, preserve, sources)?;
+                }
+            }
+            EntryKind::Symlink { .. } | EntryKind::Stream => {}
+        }
+
+        Ok(())
+    }
+
+    /// A single stream entry named `name`, for data piped in on stdin.
+    pub fn stdin(name: &str) -> Self {
+        let mtime = std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+        Manifest {
+            entries: vec![ManifestEntry {
+                path: name.to_string(),
+                kind: EntryKind::Stream,
+                size: 0,
+                mode: 0o644,
+                mtime,
+                extra: None,
+            }],
+            special: Vec::new(),
+        }
+    }
+
+    /// Whether any entry has an unknown length.
+    pub fn has_stream(&self) -> bool {
+        self.entries.iter().any(|e| e.kind == EntryKind::Stream)
+    }
+
+    /// Describe the immediate children of `dir` (or `dir` itself if it is not a directory).
+    pub fn list(dir: &Path) -> Result<Vec<ManifestEntry>, Box<dyn std::error::Error + Send + Sync>> {
+        let name = dir.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        if !fs::symlink_metadata(dir)?.is_dir() {
+            return Ok(describe(dir, name)?.into_iter().collect());
+        }
+
+        let mut children: Vec<_> = fs::read_dir(dir)?.collect::<Result<_, _>>()?;
+        children.sort_by_key(|c| c.file_name());
+        let mut entries = Vec::new();
+        for child in children {
+            if let Some(entry) = describe(&child.path(), child.file_name().to_string_lossy().into_owned())? {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Combined size of all regular files.
+    pub fn total_size(&self) -> u64 {
+        self.entries.iter().map(|e| e.size).sum()
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|e| matches!(e.kind, EntryKind::File | EntryKind::Stream))
+            .count()
+    }
+}
+
+/// Build the manifest entry for `path`, or `None` for sockets, devices and other special files.
+fn describe(path: &Path, relative: String) -> Result<Option<ManifestEntry>, Box<dyn std::error::Error + Send + Sync>> {
+    let metadata = fs::symlink_metadata(path)
+        .map_err(|e| format!("Could not read '{}': {}", path.display(), e))?;
+    let file_type = metadata.file_type();
+
+    let kind = if file_type.is_symlink() {
+        let target = fs::read_link(path)?;
+        EntryKind::Symlink { target: target.to_string_lossy().into_owned() }
+    } else if file_type.is_dir() {
+        EntryKind::Dir
+    } else if file_type.is_file() {
+        EntryKind::File
+    } else {
+        return Ok(None);
+    };
+
+    let size = if kind == EntryKind::File { metadata.len() } else { 0 };
+    Ok(Some(ManifestEntry {
+        path: relative,
+        kind,
+        size,
+        mode: mode_of(&metadata),
+        mtime: mtime_of(&metadata),
+        extra: None,
+    }))
+}
+
+#[cfg(unix)]
+fn extra_of(path: &Path) -> Result<ExtraMetadata, Box<dyn std::error::Error + Send + Sync>> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = fs::symlink_metadata(path)?;
+
+    let mut xattrs = Vec::new();
+    // Filesystems without xattr support simply have none to carry.
+    if let Ok(names) = xattr::list(path) {
+        for name in names {
+            if let Ok(Some(value)) = xattr::get(path, &name) {
+                xattrs.push((name.to_string_lossy().into_owned(), value));
+            }
+        }
+    }
+
+    Ok(ExtraMetadata {
+        atime: metadata.atime(),
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        xattrs,
+    })
+}
+
+#[cfg(not(unix))]
+fn extra_of(path: &Path) -> Result<ExtraMetadata, Box<dyn std::error::Error + Send + Sync>> {
+    let metadata = fs::symlink_metadata(path)?;
+    let atime = metadata
+        .accessed()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    Ok(ExtraMetadata { atime, ..ExtraMetadata::default() })
+}
+
+#[cfg(unix)]
+fn mode_of(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode_of(metadata: &fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() { 0o444 } else { 0o644 }
+}
+
+fn mtime_of(metadata: &fs::Metadata) -> i64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
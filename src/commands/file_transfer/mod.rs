@@ -0,0 +1,1538 @@
+mod archive;
+mod builder;
+mod compression;
+mod delta;
+mod error;
+mod events;
+mod manifest;
+mod parallel;
+mod paths;
+mod protocol;
+mod pull;
+
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{FileTimes, create_dir_all},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom, Stdout},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::{JoinHandle, JoinSet},
+};
+use tokio_util::sync::CancellationToken;
+
+use sha2::{Digest, Sha256};
+use tracing::{error, info, warn};
+
+use crate::utils::encryption::decrypt_chunk;
+use crate::utils::networking::{read_frame, write_encrypted_frame};
+use crate::utils::rate_limit::{RateLimiter, RateSchedule};
+use manifest::{EntryKind, ExtraMetadata, Manifest, ManifestEntry};
+use compression::Chunk;
+use events::Reporter;
+use parallel::JoinedStreams;
+use protocol::{Capabilities, Negotiated, Opening, SessionId, TransferHeader};
+pub use archive::{PASSPHRASE_ENV, PackOptions, UnpackOptions, pack, unpack};
+pub use builder::{FileReceiver, FileSender};
+pub use compression::{Compression, TransferStats};
+pub use error::TransferError;
+pub use events::{Event, ProgressCallback, ProgressMode};
+pub use paths::ExistingFilePolicy;
+pub use protocol::MAX_STREAMS;
+pub use pull::{get, list, serve};
+
+const CHUNK_SIZE: usize = 8192;
+/// Largest chunk size accepted for `--chunk-size`, leaving headroom under the frame limit.
+pub const MAX_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+/// Source of zeros for hashing holes in sparse files.
+static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+const AES_KEY: &[u8; 32] = b"This_is_32_byte_long_aes_key_!!!";
+/// Reasons given for a [`SkippedEntry`].
+const SPECIAL_FILE: &str = "special file";
+const ALREADY_EXISTS: &str = "already exists";
+const NO_SYMLINKS: &str = "symlinks are not supported on this platform";
+
+fn overall_bar(multi: &MultiProgress, total: u64, color: &str) -> ProgressBar {
+    let bar = multi.add(ProgressBar::new(total));
+    bar.set_style(
+        ProgressStyle::with_template(&format!(
+            "Total [{{elapsed_precise}}] [{{bar:40.{}}}] {{bytes}}/{{total_bytes}} ({{eta}})",
+            color
+        ))
+        .unwrap()
+        .progress_chars("=> "),
+    );
+    bar
+}
+
+fn counter_bar(multi: &MultiProgress) -> ProgressBar {
+    let bar = multi.add(ProgressBar::no_length());
+    bar.set_style(
+        ProgressStyle::with_template("{spinner} [{elapsed_precise}] {bytes} ({binary_bytes_per_sec})").unwrap(),
+    );
+    bar
+}
+
+fn file_bar(multi: &MultiProgress, color: &str) -> ProgressBar {
+    let bar = multi.add(ProgressBar::new(0));
+    bar.set_style(
+        ProgressStyle::with_template(&format!(
+            "{{msg:30!}} [{{bar:40.{}}}] {{bytes}}/{{total_bytes}}",
+            color
+        ))
+        .unwrap()
+        .progress_chars("=> "),
+    );
+    bar
+}
+
+/// How the sender splits and encodes file data.
+#[derive(Debug, Clone)]
+pub struct SendOptions {
+    pub compression: Compression,
+    /// Parallel connections to request; each carries a byte range of every file.
+    pub streams: u16,
+    /// Plaintext bytes per encrypted chunk.
+    pub chunk_size: usize,
+    /// Also carry atime, ownership and extended attributes.
+    pub preserve: bool,
+    /// Name the receiver gives to data sent from stdin.
+    pub stdin_name: String,
+    /// Bandwidth cap for the whole transfer, across all streams.
+    pub limit_rate: Option<RateSchedule>,
+    pub progress: ProgressMode,
+}
+
+impl Default for SendOptions {
+    fn default() -> Self {
+        SendOptions {
+            compression: Compression::None,
+            streams: 1,
+            chunk_size: CHUNK_SIZE,
+            preserve: false,
+            stdin_name: "stdin".to_string(),
+            limit_rate: None,
+            progress: ProgressMode::Bar,
+        }
+    }
+}
+
+/// Per-connection state shared by the chunk streaming helpers.
+struct StreamContext {
+    negotiated: Negotiated,
+    chunk_size: usize,
+    current: ProgressBar,
+    overall: ProgressBar,
+    stats: TransferStats,
+    /// Shared by every stream (and, when serving, every connection) on the same link.
+    limiter: Option<Arc<RateLimiter>>,
+    events: Reporter,
+    /// Emits `progress` events from `overall` for JSON output or a callback; stopped on drop.
+    ticker: Option<JoinHandle<()>>,
+}
+
+impl StreamContext {
+    /// Context for a whole transfer, reporting its progress through `events`.
+    ///
+    /// A `None` total (data piped through stdin) shows a byte counter instead of a bar.
+    fn new(negotiated: Negotiated, chunk_size: usize, total: Option<u64>, color: &str, events: &Reporter) -> StreamContext {
+        let multi = MultiProgress::new();
+        if !events.draws_bars() {
+            multi.set_draw_target(ProgressDrawTarget::hidden());
+        }
+        let overall = match total {
+            Some(total) => overall_bar(&multi, total, color),
+            None => counter_bar(&multi),
+        };
+        StreamContext {
+            negotiated,
+            chunk_size,
+            ticker: events.spawn_ticker(overall.clone()),
+            overall,
+            // Ranges of several files are in flight at once with parallel streams.
+            current: if negotiated.streams > 1 { ProgressBar::hidden() } else { file_bar(&multi, color) },
+            stats: TransferStats::default(),
+            limiter: None,
+            events: events.clone(),
+        }
+    }
+
+    fn with_limiter(mut self, limiter: Option<Arc<RateLimiter>>) -> StreamContext {
+        self.limiter = limiter;
+        self
+    }
+
+    /// Count `bytes` that crossed the socket, waiting if a rate limit is in force.
+    async fn wire(&mut self, bytes: usize) {
+        self.stats.wire_bytes += bytes as u64;
+        if let Some(limiter) = &self.limiter {
+            limiter.consume(bytes).await;
+        }
+    }
+
+    /// A fresh context for one of several parallel streams, sharing the overall bar.
+    fn for_stream(&self) -> StreamContext {
+        StreamContext {
+            negotiated: self.negotiated,
+            chunk_size: self.chunk_size,
+            current: ProgressBar::hidden(),
+            overall: self.overall.clone(),
+            stats: TransferStats::default(),
+            limiter: self.limiter.clone(),
+            events: self.events.clone(),
+            ticker: None,
+        }
+    }
+}
+
+impl Drop for StreamContext {
+    fn drop(&mut self) {
+        if let Some(ticker) = self.ticker.take() {
+            ticker.abort();
+            self.events.progress(&self.overall);
+        }
+    }
+}
+
+/// A token cancelled by Ctrl-C, so an interrupted command still cleans up after itself.
+fn cancel_on_ctrl_c() -> CancellationToken {
+    let token = CancellationToken::new();
+    let cancel = token.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            cancel.cancel();
+        }
+    });
+    token
+}
+
+/// Print a failed command's error, unless it already went out as a JSON `error` event.
+fn print_failure(progress: ProgressMode, error: &TransferError) {
+    if progress != ProgressMode::Json {
+        eprintln!("{}", error);
+    }
+}
+
+/// Command-line `send`: transfer `file_paths` with [`FileSender`] and print the outcome.
+pub async fn send(file_paths: &[String], host: &str, port: u16, options: SendOptions) -> Result<(), TransferError> {
+    let progress = options.progress;
+    let sent = FileSender::new(host, port)
+        .files(file_paths)
+        .options(options)
+        .cancel_token(cancel_on_ctrl_c())
+        .console()
+        .send()
+        .await;
+    match sent {
+        Ok(report) => {
+            println!("{}", report);
+            Ok(())
+        }
+        Err(e) => {
+            print_failure(progress, &e);
+            Err(e)
+        }
+    }
+}
+
+/// Send files and directory trees to the receiver over TCP with AES-256 encryption.
+///
+/// After the version handshake a header carrying the manifest is sent,
+/// followed by the contents of each regular file in manifest order. Chunks
+/// are compressed when the receiver agrees to it, and with more than one
+/// stream every file is split into byte ranges sent over parallel connections.
+///
+/// A single path of `-` sends stdin instead, as one file of unknown length.
+async fn send_files(
+    file_paths: &[String],
+    host: &str,
+    port: u16,
+    options: &SendOptions,
+    events: &Reporter,
+) -> Result<TransferReport, TransferError> {
+    let started = Instant::now();
+    let from_stdin = file_paths.iter().any(|p| p == "-");
+    if from_stdin && file_paths.len() > 1 {
+        return Err(TransferError::Local("'-' (stdin) cannot be combined with other paths".to_string()));
+    }
+    if file_paths.is_empty() {
+        return Err(TransferError::Local("nothing to send".to_string()));
+    }
+    if !(1..=MAX_CHUNK_SIZE).contains(&options.chunk_size) {
+        return Err(TransferError::Local(format!(
+            "chunk size must be between 1 byte and {} bytes",
+            MAX_CHUNK_SIZE
+        )));
+    }
+    let prepared = if from_stdin {
+        Ok((Manifest::stdin(&options.stdin_name), Vec::new()))
+    } else {
+        Manifest::build(file_paths, options.preserve)
+    };
+    let (mut manifest, sources) = prepared.map_err(|e| TransferError::Local(format!("Could not prepare files: {}", e)))?;
+    let skipped: Vec<_> = std::mem::take(&mut manifest.special)
+        .iter()
+        .map(|path| SkippedEntry { path: path.display().to_string(), reason: SPECIAL_FILE })
+        .collect();
+    for entry in &skipped {
+        warn!("Skipping special file '{}'", entry.path);
+        events.emit(&Event::Skipped { path: &entry.path, reason: entry.reason });
+    }
+
+    let address = format!("{}:{}", host, port);
+    let mut stream = TcpStream::connect(&address)
+        .await
+        .map_err(|e| TransferError::Connect(format!("Failed to connect to receiver at {}: {}", address, e)))?;
+
+    let mut capabilities = Capabilities::SUPPORTED;
+    if from_stdin {
+        // A pipe can't be split into ranges, diffed against an old copy or seeked over.
+        capabilities = capabilities
+            .without(Capabilities::PARALLEL)
+            .without(Capabilities::DELTA)
+            .without(Capabilities::SPARSE);
+    }
+    let mut header = TransferHeader {
+        version: protocol::PROTOCOL_VERSION,
+        capabilities,
+        session_id: protocol::new_session_id(),
+        compression: options.compression,
+        streams: options.streams.clamp(1, MAX_STREAMS),
+        manifest,
+    };
+    let negotiated = protocol::offer(&mut stream, &mut header, AES_KEY)
+        .await
+        .map_err(|e| error::handshake(e, format!("Handshake with {} failed", address)))?;
+    let manifest = header.manifest;
+
+    let total = (!from_stdin).then(|| manifest.total_size());
+    let limiter = options.limit_rate.clone().map(|schedule| Arc::new(RateLimiter::new(schedule)));
+    events.emit(&Event::Started { files: manifest.file_count(), total_bytes: total });
+    let mut context = StreamContext::new(negotiated, options.chunk_size, total, "cyan/blue", events).with_limiter(limiter);
+    if from_stdin {
+        send_stream(&mut stream, &mut context, &mut tokio::io::stdin()).await?;
+        events.emit(&Event::FileCompleted { path: &options.stdin_name, size: context.stats.file_bytes });
+    } else {
+        let join = (address.as_str(), header.session_id);
+        send_entries(&mut stream, &manifest, &sources, &mut context, Some(join)).await?;
+    }
+
+    Ok(TransferReport {
+        session_id: protocol::to_hex(&header.session_id),
+        peer: address,
+        root: None,
+        files: manifest
+            .entries
+            .iter()
+            .filter(|e| matches!(e.kind, EntryKind::File | EntryKind::Stream))
+            .map(|e| TransferredFile {
+                path: e.path.clone(),
+                // A stream's length is only known once it has been sent.
+                size: if e.kind == EntryKind::Stream { context.stats.file_bytes } else { e.size },
+                sha256: None,
+            })
+            .collect(),
+        skipped,
+        stats: context.stats,
+        compression: negotiated.compression,
+        streams: negotiated.streams,
+        duration: started.elapsed(),
+    })
+}
+
+/// Stream the contents of every regular file in `manifest`, read from the matching `sources`.
+///
+/// Files the receiver already has an old copy of are sent first as deltas
+/// against its block signatures; the rest follow whole, split across the
+/// extra connections opened through `join` when parallel streams were granted.
+async fn send_entries(
+    stream: &mut TcpStream,
+    manifest: &Manifest,
+    sources: &[PathBuf],
+    context: &mut StreamContext,
+    join: Option<(&str, SessionId)>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let files: Vec<(&ManifestEntry, &PathBuf)> = manifest
+        .entries
+        .iter()
+        .filter(|e| e.kind == EntryKind::File)
+        .zip(sources)
+        .collect();
+    let mut whole = vec![true; files.len()];
+
+    if context.negotiated.capabilities.contains(Capabilities::DELTA) {
+        while let Some(signature) = protocol::read_message::<_, Option<delta::Signature>>(stream, AES_KEY).await? {
+            let index = signature.index as usize;
+            let (entry, source) = files
+                .get(index)
+                .filter(|_| whole[index])
+                .ok_or("receiver asked for a delta of an unknown file")?;
+            context.current.set_message(entry.path.clone());
+            context.current.set_length(entry.size);
+            context.current.set_position(0);
+
+            delta::send_file(stream, context, source, entry.size, &signature)
+                .await
+                .map_err(|e| format!("Failed to send '{}': {}", entry.path, e))?;
+            context.events.emit(&Event::FileCompleted { path: &entry.path, size: entry.size });
+            whole[index] = false;
+        }
+    }
+    let files: Vec<_> = files.into_iter().zip(whole).filter(|(_, whole)| *whole).map(|(f, _)| f).collect();
+
+    if context.negotiated.streams > 1 {
+        let (address, session_id) = join.ok_or("parallel streams are not available for this transfer")?;
+        let mut extra = parallel::open_extra_streams(address, session_id, context.negotiated.streams)
+            .await
+            .map_err(|e| TransferError::Connect(format!("Failed to open data streams: {}", e)))?;
+        let ranges: Vec<(PathBuf, u64)> = files.iter().map(|(entry, source)| ((*source).clone(), entry.size)).collect();
+        let streams = std::iter::once(stream).chain(extra.iter_mut()).collect();
+        let stats = parallel::send_parallel(streams, &ranges, context).await?;
+        context.stats = context.stats.merge(stats);
+        for (entry, _) in files {
+            context.events.emit(&Event::FileCompleted { path: &entry.path, size: entry.size });
+        }
+    } else {
+        for (entry, source) in files {
+            context.current.set_message(entry.path.clone());
+            context.current.set_length(entry.size);
+            context.current.set_position(0);
+
+            send_range(stream, context, source, 0, entry.size)
+                .await
+                .map_err(|e| format!("Failed to send '{}': {}", entry.path, e))?;
+            context.events.emit(&Event::FileCompleted { path: &entry.path, size: entry.size });
+        }
+    }
+
+    context.current.finish_and_clear();
+    context.overall.finish_with_message("Files sent");
+    Ok(())
+}
+
+/// Stream `len` bytes of `source` starting at `offset` as encrypted chunks,
+/// followed by their SHA-256 digest when hashing was negotiated.
+async fn send_range(
+    stream: &mut TcpStream,
+    context: &mut StreamContext,
+    source: &Path,
+    offset: u64,
+    len: u64,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut file = File::open(source).await?;
+    if offset > 0 {
+        file.seek(SeekFrom::Start(offset)).await?;
+    }
+    let mut file = file.take(len);
+    let mut buffer = vec![0u8; context.chunk_size];
+    let mut hasher = Sha256::new();
+    let mut total_sent = 0u64;
+    let negotiated = context.negotiated;
+    let sparse = negotiated.capabilities.contains(Capabilities::SPARSE);
+    // Consecutive all-zero chunks are coalesced and sent as a single hole.
+    let mut hole = 0u64;
+
+    loop {
+        let n = file.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..n]);
+        if sparse && buffer[..n].iter().all(|&b| b == 0) {
+            hole += n as u64;
+        } else {
+            if hole > 0 {
+                let wire = write_encrypted_frame(stream, &compression::encode_hole(hole), AES_KEY).await?;
+                context.wire(wire).await;
+                hole = 0;
+            }
+            let payload = compression::encode_chunk(&buffer[..n], negotiated.compression)?;
+            let wire = write_encrypted_frame(stream, &payload, AES_KEY).await?;
+            context.wire(wire).await;
+        }
+        context.stats.file_bytes += n as u64;
+
+        total_sent += n as u64;
+        context.current.set_position(total_sent);
+        context.overall.inc(n as u64);
+    }
+    if hole > 0 {
+        let wire = write_encrypted_frame(stream, &compression::encode_hole(hole), AES_KEY).await?;
+        context.wire(wire).await;
+    }
+
+    if total_sent != len {
+        return Err(format!("file shrank while sending ({} of {} bytes)", offset + total_sent, offset + len).into());
+    }
+    if negotiated.capabilities.contains(Capabilities::HASHING) {
+        write_encrypted_frame(stream, &hasher.finalize(), AES_KEY).await?;
+    }
+    Ok(())
+}
+
+/// Stream `reader` until it ends, then send an empty chunk to mark the end,
+/// followed by the SHA-256 digest when hashing was negotiated.
+async fn send_stream<R>(
+    stream: &mut TcpStream,
+    context: &mut StreamContext,
+    reader: &mut R,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buffer = vec![0u8; context.chunk_size];
+    let mut hasher = Sha256::new();
+    loop {
+        let n = reader.read(&mut buffer).await?;
+        let payload = compression::encode_chunk(&buffer[..n], context.negotiated.compression)?;
+        let wire = write_encrypted_frame(stream, &payload, AES_KEY).await?;
+        context.wire(wire).await;
+        if n == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..n]);
+        context.stats.file_bytes += n as u64;
+        context.overall.inc(n as u64);
+    }
+
+    if context.negotiated.capabilities.contains(Capabilities::HASHING) {
+        write_encrypted_frame(stream, &hasher.finalize(), AES_KEY).await?;
+    }
+    context.overall.finish_with_message("Stream sent");
+    Ok(())
+}
+
+/// How the receiver treats incoming uploads.
+#[derive(Debug, Clone, Default)]
+pub struct ReceiveOptions {
+    pub policy: ExistingFilePolicy,
+    /// Keep accepting senders instead of exiting after the first upload.
+    pub serve: bool,
+    /// Put each upload under a subdirectory named after the sender's IP address.
+    pub per_sender_dirs: bool,
+    /// Reject uploads containing a file larger than this many bytes.
+    pub max_file_size: Option<u64>,
+    /// Reject uploads once this many bytes have been accepted since the receiver started.
+    pub quota: Option<u64>,
+    /// Bandwidth cap shared by all incoming connections.
+    pub limit_rate: Option<RateSchedule>,
+    /// Leave the hidden `.name.ntpart` files of a failed transfer in place instead of deleting them.
+    pub keep_partial: bool,
+    /// Also apply the ownership, extended attributes and setuid, setgid and
+    /// sticky bits a sender preserved. Only safe with a trusted sender.
+    pub restore_privileged: bool,
+    /// Ignored with `serve`, which logs each upload instead.
+    pub progress: ProgressMode,
+}
+
+/// How [`receive_entries`] writes an upload.
+struct WriteOptions {
+    policy: ExistingFilePolicy,
+    events: Reporter,
+    limiter: Option<Arc<RateLimiter>>,
+    keep_partial: bool,
+    restore_privileged: bool,
+}
+
+/// Bytes accepted so far against the `--quota` limit, shared by all connections.
+struct Quota {
+    limit: u64,
+    used: AtomicU64,
+}
+
+impl Quota {
+    fn reserve(&self, bytes: u64) -> bool {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(bytes).filter(|total| *total <= self.limit)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, bytes: u64) {
+        self.used.fetch_sub(bytes, Ordering::SeqCst);
+    }
+}
+
+/// A single file sent or received.
+#[derive(Debug, Clone)]
+pub struct TransferredFile {
+    pub path: String,
+    pub size: u64,
+    /// Hex SHA-256 of the contents, computed by the receiver.
+    pub sha256: Option<String>,
+}
+
+/// An entry left out of a transfer, and why.
+#[derive(Debug, Clone)]
+pub struct SkippedEntry {
+    pub path: String,
+    pub reason: &'static str,
+}
+
+/// Outcome of a completed transfer, from either end.
+#[derive(Debug, Clone)]
+pub struct TransferReport {
+    pub session_id: String,
+    /// The receiver's `host:port` for a send, the sender's address for a receive.
+    pub peer: String,
+    /// Where the files were written (`-` for stdout); `None` for a send.
+    pub root: Option<PathBuf>,
+    pub files: Vec<TransferredFile>,
+    /// Entries left out: special files on the sender, or ones the receiver already
+    /// had or cannot create.
+    pub skipped: Vec<SkippedEntry>,
+    pub stats: TransferStats,
+    pub compression: Compression,
+    /// Data connections used, including the control connection.
+    pub streams: u16,
+    pub duration: Duration,
+}
+
+/// Human-readable summary for the interactive (non-serve) modes.
+impl fmt::Display for TransferReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for skipped in &self.skipped {
+            writeln!(f, "Skipped '{}': {}", skipped.path, skipped.reason)?;
+        }
+        match &self.root {
+            Some(root) => writeln!(
+                f,
+                "Received {} file(s), {} bytes into '{}'",
+                self.files.len(),
+                self.stats.file_bytes,
+                root.display()
+            )?,
+            None => writeln!(
+                f,
+                "Sent {} file(s), {} bytes to {} over {} stream(s)",
+                self.files.len(),
+                self.stats.file_bytes,
+                self.peer,
+                self.streams
+            )?,
+        }
+        write!(f, "{}", self.stats.summary(self.compression))
+    }
+}
+
+impl TransferReport {
+    /// Structured log lines for `--serve` mode.
+    fn log(&self) {
+        let root = self.root.as_deref().unwrap_or(Path::new(""));
+        for file in &self.files {
+            info!(
+                peer = %self.peer,
+                session = %self.session_id,
+                size = file.size,
+                sha256 = %file.sha256.as_deref().unwrap_or_default(),
+                "Received '{}'",
+                file.path
+            );
+        }
+        for skipped in &self.skipped {
+            info!(peer = %self.peer, session = %self.session_id, "Skipped '{}': {}", skipped.path, skipped.reason);
+        }
+        info!(
+            peer = %self.peer,
+            session = %self.session_id,
+            files = self.files.len(),
+            bytes = self.stats.file_bytes,
+            duration_ms = self.duration.as_millis() as u64,
+            "Upload complete into '{}'",
+            root.display()
+        );
+    }
+}
+
+/// Per-session channels through which the accept loop hands over extra data connections.
+type JoinRegistry = Mutex<HashMap<SessionId, mpsc::UnboundedSender<(u16, TcpStream)>>>;
+
+/// State shared by every connection accepted by [`run_receiver`].
+struct Receiver {
+    output_dir: PathBuf,
+    options: ReceiveOptions,
+    quota: Option<Quota>,
+    joins: JoinRegistry,
+    /// Set once the single upload of a non-serve receiver has started.
+    claimed: AtomicBool,
+    /// `--output -`: the single incoming file goes to stdout.
+    to_stdout: bool,
+    limiter: Option<Arc<RateLimiter>>,
+    events: Reporter,
+}
+
+impl Receiver {
+    /// Print a status line for the CLI, keeping stdout clean when it carries the received data.
+    fn status(&self, message: &str) {
+        if !self.events.console {
+            return;
+        }
+        if self.to_stdout {
+            eprintln!("{}", message);
+        } else {
+            println!("{}", message);
+        }
+    }
+}
+
+/// Command-line `receive`: run a [`FileReceiver`] and print the outcome.
+pub async fn receive(port: u16, output_dir: &str, options: ReceiveOptions) -> Result<(), TransferError> {
+    let progress = options.progress;
+    let serve = options.serve;
+    if serve {
+        tracing_subscriber::fmt::init();
+    }
+    let receiver = FileReceiver::new(port, output_dir)
+        .options(options)
+        .cancel_token(cancel_on_ctrl_c())
+        .console();
+    let received = if serve { receiver.serve().await.map(|_| None) } else { receiver.receive().await.map(Some) };
+    match received {
+        Ok(Some(report)) if output_dir == "-" => eprintln!("{}", report),
+        Ok(Some(report)) => println!("{}", report),
+        Ok(None) => {}
+        Err(e) => {
+            print_failure(progress, &e);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Receive encrypted files and recreate their tree under `output_dir`.
+///
+/// Every sender-supplied path is sanitised and confined to `output_dir`;
+/// `options.policy` decides what happens to files that already exist there.
+/// With `options.serve` the receiver keeps accepting concurrent senders and
+/// logs each completed upload instead of drawing progress bars, until `cancel`
+/// fires; otherwise it returns the report of the first upload.
+///
+/// An `output_dir` of `-` writes a single incoming file or stream to stdout.
+///
+/// Files are written to hidden `.name.ntpart` siblings and only renamed into
+/// place once their contents are synced and verified, so a failed upload
+/// never leaves a truncated file under its real name.
+async fn run_receiver(
+    port: u16,
+    output_dir: &str,
+    options: ReceiveOptions,
+    events: Reporter,
+    cancel: CancellationToken,
+) -> Result<Option<TransferReport>, TransferError> {
+    let to_stdout = output_dir == "-";
+    if to_stdout && options.serve {
+        return Err(TransferError::Local("--output - cannot be combined with --serve".to_string()));
+    }
+    if !to_stdout && let Err(e) = create_dir_all(output_dir) {
+        return Err(TransferError::Local(format!("Could not create output dir: {}", e)));
+    }
+
+    let listener = TcpListener::bind(("0.0.0.0", port))
+        .await
+        .map_err(|e| TransferError::Connect(format!("Could not listen on port {}: {}", port, e)))?;
+
+    let limiter = options.limit_rate.clone().map(|schedule| Arc::new(RateLimiter::new(schedule)));
+    let receiver = Arc::new(Receiver {
+        output_dir: PathBuf::from(output_dir),
+        quota: options.quota.map(|limit| Quota { limit, used: AtomicU64::new(0) }),
+        options,
+        joins: Mutex::new(HashMap::new()),
+        claimed: AtomicBool::new(false),
+        to_stdout,
+        limiter,
+        events,
+    });
+    receiver.status(&format!("Receiver listening on port {}", port));
+    // Dropping or shutting down the set aborts every connection, which removes their partial files.
+    let mut connections = JoinSet::new();
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, addr) = match accepted {
+                    Ok(c) => c,
+                    Err(e) => {
+                        warn!("Accept failed: {}", e);
+                        continue;
+                    }
+                };
+                let receiver = Arc::clone(&receiver);
+                connections.spawn(async move { handle_connection(socket, addr, &receiver).await });
+            }
+            Some(joined) = connections.join_next(), if !connections.is_empty() => {
+                // Without --serve, the first upload (successful or not) ends the receiver;
+                // connections that never got as far as starting one don't.
+                if let Ok(Some(outcome)) = joined
+                    && !receiver.options.serve
+                {
+                    return outcome.map(Some);
+                }
+            }
+            _ = cancel.cancelled() => {
+                connections.shutdown().await;
+                return if receiver.options.serve { Ok(None) } else { Err(TransferError::Cancelled) };
+            }
+        }
+    }
+}
+
+/// Dispatch one accepted connection. Returns the outcome once an upload has started.
+async fn handle_connection(
+    mut socket: TcpStream,
+    addr: SocketAddr,
+    receiver: &Receiver,
+) -> Option<Result<TransferReport, TransferError>> {
+    let serve = receiver.options.serve;
+    let opening = async {
+        let version = protocol::exchange_versions(&mut socket).await?;
+        Ok::<_, Box<dyn std::error::Error + Send + Sync>>((version, protocol::read_opening(&mut socket, AES_KEY).await?))
+    }
+    .await;
+
+    let (version, header) = match opening {
+        Ok((version, Opening::Transfer(header))) => (version, header),
+        Ok((_, Opening::Join { session_id, index })) => {
+            let sender = receiver.joins.lock().unwrap().get(&session_id).cloned();
+            match sender {
+                Some(sender) => {
+                    let _ = sender.send((index, socket));
+                }
+                None => warn!(peer = %addr, "Data stream for unknown session {}", protocol::to_hex(&session_id)),
+            }
+            return None;
+        }
+        // A port scan, health check or stale client: not an upload, so keep waiting for one.
+        Err(e) => {
+            error!(peer = %addr, "Handshake failed: {}", e);
+            if !serve {
+                receiver.status(&format!("Handshake with {} failed: {}", addr, e));
+            }
+            return None;
+        }
+    };
+
+    if serve {
+        info!("Connection from {}", addr);
+    } else {
+        if receiver.claimed.swap(true, Ordering::SeqCst) {
+            let _ = protocol::answer_header(&mut socket, AES_KEY, version, &header, |_| Err("receiver is busy".to_string())).await;
+            return None;
+        }
+        receiver.status(&format!("Connection from {}", addr));
+    }
+
+    match handle_upload(socket, addr, version, header, receiver).await {
+        Ok(report) if serve => {
+            report.log();
+            None
+        }
+        Err(e) if serve => {
+            error!(peer = %addr, "Upload failed: {}", e);
+            None
+        }
+        Ok(report) => Some(Ok(report)),
+        Err(e) => Some(Err(TransferError::from(e).context(format!("Upload from {} failed", addr)))),
+    }
+}
+
+/// Answer a sender's header and write its files.
+async fn handle_upload(
+    mut socket: TcpStream,
+    addr: SocketAddr,
+    version: u16,
+    mut header: TransferHeader,
+    receiver: &Receiver,
+) -> Result<TransferReport, Box<dyn std::error::Error + Send + Sync>> {
+    let started = Instant::now();
+    let options = &receiver.options;
+    let quota = receiver.quota.as_ref();
+    let session_id = header.session_id;
+    if receiver.to_stdout {
+        // stdout is written strictly in order and can't be seeked over or used as a delta basis.
+        header.capabilities = header
+            .capabilities
+            .without(Capabilities::PARALLEL)
+            .without(Capabilities::DELTA)
+            .without(Capabilities::SPARSE);
+    }
+
+    // Registered before answering, since the sender connects its extra streams right after.
+    let (join_tx, join_rx) = mpsc::unbounded_channel();
+    receiver.joins.lock().unwrap().insert(session_id, join_tx);
+
+    let mut reserved = 0;
+    let answered = protocol::answer_header(&mut socket, AES_KEY, version, &header, |header| {
+        let manifest = &header.manifest;
+        if receiver.to_stdout
+            && !matches!(manifest.entries.as_slice(), [entry] if matches!(entry.kind, EntryKind::File | EntryKind::Stream))
+        {
+            return Err("receiver writes to stdout and only accepts a single file".to_string());
+        }
+        if manifest.has_stream() && (options.max_file_size.is_some() || quota.is_some()) {
+            return Err("streams of unknown length are not accepted by a receiver with size limits".to_string());
+        }
+        if let Some(limit) = options.max_file_size
+            && let Some(entry) = manifest.entries.iter().find(|e| e.size > limit)
+        {
+            return Err(format!("'{}' is {} bytes, over the {} byte limit", entry.path, entry.size, limit));
+        }
+        if let Some(quota) = quota {
+            if !quota.reserve(manifest.total_size()) {
+                return Err(format!("upload of {} bytes would exceed the receiver's quota", manifest.total_size()));
+            }
+            reserved = manifest.total_size();
+        }
+        Ok(())
+    })
+    .await
+    .map_err(|e| -> Box<dyn std::error::Error + Send + Sync> { Box::new(error::handshake(e, "handshake failed")) });
+
+    // Serve mode logs each upload instead.
+    let events = if options.serve { Reporter::silent() } else { receiver.events.clone() };
+    let result = match answered {
+        Ok(negotiated) if receiver.to_stdout => {
+            receive_to_stdout(&mut socket, header, negotiated, &events, receiver.limiter.clone()).await
+        }
+        Ok(negotiated) => {
+            let root = if options.per_sender_dirs {
+                receiver.output_dir.join(addr.ip().to_string().replace(':', "_"))
+            } else {
+                receiver.output_dir.clone()
+            };
+            let write = WriteOptions {
+                policy: options.policy,
+                events,
+                limiter: receiver.limiter.clone(),
+                keep_partial: options.keep_partial,
+                restore_privileged: options.restore_privileged,
+            };
+            receive_entries(&mut socket, root, &write, header, negotiated, Some(join_rx)).await
+        }
+        Err(e) => Err(e),
+    };
+
+    receiver.joins.lock().unwrap().remove(&session_id);
+    if result.is_err()
+        && let Some(quota) = quota
+    {
+        quota.release(reserved);
+    }
+
+    let mut report = result?;
+    report.peer = addr.to_string();
+    report.duration = started.elapsed();
+    Ok(report)
+}
+
+/// Partial files of an upload in progress, deleted when dropped unless `keep` is set.
+///
+/// Being a drop guard, this also cleans up after a transfer whose future is
+/// dropped part-way, as happens when it is cancelled.
+struct Partials {
+    paths: Vec<PathBuf>,
+    keep: bool,
+}
+
+impl Drop for Partials {
+    fn drop(&mut self) {
+        if !self.keep {
+            for path in &self.paths {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// Create everything listed in the manifest under `root`, then stream file contents off the socket.
+///
+/// If anything fails, the partial files written so far are deleted (or left
+/// in place with `keep_partial`); files already in place are never touched.
+async fn receive_entries(
+    socket: &mut TcpStream,
+    root: PathBuf,
+    write: &WriteOptions,
+    header: TransferHeader,
+    negotiated: Negotiated,
+    joins: Option<JoinedStreams>,
+) -> Result<TransferReport, Box<dyn std::error::Error + Send + Sync>> {
+    let mut partials = Partials { paths: Vec::new(), keep: write.keep_partial };
+    let report = write_entries(socket, root, write, header, negotiated, joins, &mut partials.paths).await?;
+    // Every partial file has been renamed into place.
+    partials.paths.clear();
+    Ok(report)
+}
+
+/// The body of [`receive_entries`], recording every partial file it creates in `partials`.
+///
+/// All destinations are resolved (and empty partial files created) before any
+/// data is read, so a rejected path aborts the transfer before bytes are written.
+/// Each file is synced and renamed into place only once all data has arrived
+/// and passed its digest check.
+async fn write_entries(
+    socket: &mut TcpStream,
+    root: PathBuf,
+    write: &WriteOptions,
+    header: TransferHeader,
+    negotiated: Negotiated,
+    joins: Option<JoinedStreams>,
+    partials: &mut Vec<PathBuf>,
+) -> Result<TransferReport, Box<dyn std::error::Error + Send + Sync>> {
+    let policy = write.policy;
+    let manifest = header.manifest;
+    create_dir_all(&root).map_err(|e| TransferError::Local(format!("could not create '{}': {}", root.display(), e)))?;
+
+    // With delta transfers, plain files about to be overwritten are kept as the basis for their replacement.
+    let use_delta = negotiated.capabilities.contains(Capabilities::DELTA);
+    let mut destinations = Vec::with_capacity(manifest.entries.len());
+    // Where each entry's data is written: its partial file, or the delta basis it replaces.
+    let mut targets = Vec::with_capacity(manifest.entries.len());
+    let mut bases = Vec::with_capacity(manifest.entries.len());
+    // Whether this transfer created each entry, and so may set its metadata.
+    let mut created = Vec::with_capacity(manifest.entries.len());
+    for entry in &manifest.entries {
+        let basis = if use_delta { delta::basis(&root, entry, policy) } else { None };
+        let (destination, target, new) = match &basis {
+            // Rebuilt into a new file that replaces the basis.
+            Some(path) => (Some(path.clone()), Some(path.clone()), true),
+            None => {
+                let destination = resolve_destination(&root, entry, policy)
+                    .map_err(|e| TransferError::Protocol(format!("rejected '{}': {}", entry.path.escape_debug(), e)))?;
+                let target = match (&entry.kind, &destination) {
+                    (EntryKind::File | EntryKind::Stream, Some(path)) => {
+                        let partial = paths::partial_path(path);
+                        // Recorded before creation so a half-prepared file is cleaned up too.
+                        partials.push(partial.clone());
+                        Some(partial)
+                    }
+                    _ => destination.clone(),
+                };
+                let new = prepare_entry(entry, target.as_deref())
+                    .await
+                    .map_err(|e| TransferError::Local(format!("failed to create '{}': {}", entry.path, e)))?;
+                (destination, target, new)
+            }
+        };
+        destinations.push(destination);
+        targets.push(target);
+        bases.push(basis.is_some());
+        created.push(new);
+    }
+
+    let total = (!manifest.has_stream()).then(|| manifest.total_size());
+    let events = &write.events;
+    events.emit(&Event::Started { files: manifest.file_count(), total_bytes: total });
+    let mut context = StreamContext::new(negotiated, CHUNK_SIZE, total, "green/white", events)
+        .with_limiter(write.limiter.clone());
+    let hashing = negotiated.capabilities.contains(Capabilities::HASHING);
+
+    let files: Vec<(&ManifestEntry, &Option<PathBuf>, bool)> = manifest
+        .entries
+        .iter()
+        .zip(&targets)
+        .zip(&bases)
+        .filter(|((entry, _), _)| entry.kind == EntryKind::File)
+        .map(|((entry, target), basis)| (entry, target, *basis))
+        .collect();
+    let mut digests = vec![String::new(); files.len()];
+
+    if use_delta {
+        for (index, (entry, target, basis)) in files.iter().enumerate() {
+            let (Some(path), true) = (target, basis) else { continue };
+            context.current.set_message(entry.path.clone());
+            context.current.set_length(entry.size);
+            context.current.set_position(0);
+
+            digests[index] = delta::receive_file(socket, &mut context, index as u32, path, entry.size, partials)
+                .await
+                .map_err(|e| error::context(e, format!("failed to update '{}'", entry.path)))?;
+            // Deltas always end with the digest of the rebuilt file, and it is already in place.
+            events.emit(&Event::Verified { path: &entry.path, sha256: &digests[index] });
+            events.emit(&Event::FileCompleted { path: &entry.path, size: entry.size });
+        }
+        protocol::write_message(socket, &None::<delta::Signature>, AES_KEY).await?;
+    }
+
+    let whole: Vec<usize> = (0..files.len()).filter(|&i| !files[i].2).collect();
+    if negotiated.streams > 1 {
+        let joins = joins.ok_or("parallel streams are not available for this transfer")?;
+        let mut extra = parallel::collect_streams(joins, negotiated.streams).await?;
+        let ranges: Vec<(Option<PathBuf>, u64)> = whole.iter().map(|&i| (files[i].1.clone(), files[i].0.size)).collect();
+        let streams = std::iter::once(&mut *socket).chain(extra.iter_mut()).collect();
+        let stats = parallel::receive_parallel(streams, &ranges, &context).await?;
+        context.stats = context.stats.merge(stats);
+
+        for &index in &whole {
+            if let Some(path) = files[index].1 {
+                digests[index] = file_digest(path).await?;
+                // Each stream checked the digest of its own range.
+                if hashing {
+                    events.emit(&Event::Verified { path: &files[index].0.path, sha256: &digests[index] });
+                }
+            }
+        }
+    } else {
+        for &index in &whole {
+            let (entry, target, _) = files[index];
+            context.current.set_message(entry.path.clone());
+            context.current.set_length(entry.size);
+            context.current.set_position(0);
+
+            let mut file = match target {
+                Some(path) => Some(OpenOptions::new().write(true).open(path).await?),
+                None => None,
+            };
+            let sink = file.as_mut().map_or(Sink::Discard, Sink::File);
+            digests[index] = receive_range(socket, &mut context, sink, entry.size)
+                .await
+                .map_err(|e| error::context(e, format!("failed to receive '{}'", entry.path)))?;
+            if hashing && target.is_some() {
+                events.emit(&Event::Verified { path: &entry.path, sha256: &digests[index] });
+            }
+        }
+    }
+
+    // Streams of unknown length follow everything else on the control connection.
+    let mut streamed = Vec::new();
+    for (entry, target) in manifest.entries.iter().zip(&targets) {
+        if entry.kind != EntryKind::Stream {
+            continue;
+        }
+        let mut file = match target {
+            Some(path) => Some(OpenOptions::new().write(true).open(path).await?),
+            None => None,
+        };
+        let sink = file.as_mut().map_or(Sink::Discard, Sink::File);
+        let (size, digest) = receive_stream(socket, &mut context, sink)
+            .await
+            .map_err(|e| error::context(e, format!("failed to receive '{}'", entry.path)))?;
+        if hashing && target.is_some() {
+            events.emit(&Event::Verified { path: &entry.path, sha256: &digest });
+        }
+        if target.is_some() {
+            streamed.push(TransferredFile { path: entry.path.clone(), size, sha256: Some(digest) });
+        }
+    }
+
+    // Everything arrived intact: move the partial files over their real names.
+    for ((entry, destination), (target, basis)) in manifest.entries.iter().zip(&destinations).zip(targets.iter().zip(&bases)) {
+        let (Some(destination), Some(partial), false) = (destination, target, basis) else { continue };
+        if !matches!(entry.kind, EntryKind::File | EntryKind::Stream) {
+            continue;
+        }
+        let expected = (entry.kind == EntryKind::File).then_some(entry.size);
+        let size = commit_partial(partial, destination, expected)
+            .await
+            .map_err(|e| error::context(e, format!("failed to finish '{}'", entry.path)))?;
+        events.emit(&Event::FileCompleted { path: &entry.path, size });
+    }
+
+    let mut report = TransferReport {
+        session_id: protocol::to_hex(&header.session_id),
+        peer: String::new(),
+        root: Some(root),
+        files: Vec::new(),
+        skipped: Vec::new(),
+        stats: context.stats,
+        compression: negotiated.compression,
+        streams: negotiated.streams,
+        duration: Duration::ZERO,
+    };
+    for ((entry, target, _), digest) in files.iter().zip(digests) {
+        if target.is_some() {
+            report.files.push(TransferredFile { path: entry.path.clone(), size: entry.size, sha256: Some(digest) });
+        }
+    }
+    report.files.extend(streamed);
+    for (entry, destination) in manifest.entries.iter().zip(&destinations) {
+        if destination.is_none() {
+            context.events.emit(&Event::Skipped { path: &entry.path, reason: skip_reason(entry) });
+            report.skipped.push(SkippedEntry { path: entry.path.clone(), reason: skip_reason(entry) });
+        }
+    }
+
+    // Directories are finalised last so writing their children doesn't bump the mtime again.
+    // Only what this transfer created: a directory that was already there keeps its mode and times.
+    for ((entry, destination), new) in manifest.entries.iter().zip(&destinations).zip(&created).rev() {
+        let (Some(destination), true) = (destination, *new) else { continue };
+        if let Err(e) = apply_metadata(destination, entry, write.restore_privileged) {
+            warn!("Could not set metadata on '{}': {}", entry.path, e);
+        }
+    }
+
+    context.current.finish_and_clear();
+    context.overall.finish_with_message("Files received");
+    Ok(report)
+}
+
+/// Create the directory, symlink or empty (partial) file for `entry` at `target` (if not skipped).
+///
+/// Returns whether anything was created; a directory that already existed is
+/// not, and must keep its own mode and times.
+async fn prepare_entry(entry: &ManifestEntry, target: Option<&Path>) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let Some(target) = target else { return Ok(false) };
+    match &entry.kind {
+        EntryKind::Dir => {
+            if target.symlink_metadata().is_ok() {
+                return Ok(false);
+            }
+            create_dir_all(target)?
+        }
+        EntryKind::Symlink { target: link } => create_symlink(link, target)?,
+        EntryKind::File | EntryKind::Stream => {
+            if let Some(parent) = target.parent() {
+                create_dir_all(parent)?;
+            }
+            // A partial file kept from an earlier failed attempt is started over.
+            if target.symlink_metadata().is_ok() {
+                std::fs::remove_file(target)?;
+            }
+            let file = OpenOptions::new().write(true).create_new(true).open(target).await?;
+            file.set_len(entry.size).await?;
+        }
+    }
+    Ok(true)
+}
+
+/// Flush a completely received `partial` file to disk, check its length and rename it to `destination`.
+///
+/// Returns the size of the file.
+async fn commit_partial(
+    partial: &Path,
+    destination: &Path,
+    expected: Option<u64>,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let file = OpenOptions::new().write(true).open(partial).await?;
+    file.sync_all().await?;
+    let written = file.metadata().await?.len();
+    if let Some(expected) = expected
+        && written != expected
+    {
+        return Err(TransferError::Integrity(format!("expected {} bytes, found {}", expected, written)).into());
+    }
+    drop(file);
+    tokio::fs::rename(partial, destination).await?;
+    Ok(written)
+}
+
+/// Why [`resolve_destination`] skipped `entry`.
+fn skip_reason(entry: &ManifestEntry) -> &'static str {
+    if !cfg!(unix) && matches!(entry.kind, EntryKind::Symlink { .. }) { NO_SYMLINKS } else { ALREADY_EXISTS }
+}
+
+/// Work out where `entry` should be written under `root`. `None` means the entry is skipped.
+fn resolve_destination(root: &Path, entry: &ManifestEntry, policy: ExistingFilePolicy) -> Result<Option<PathBuf>, String> {
+    if !cfg!(unix) && matches!(entry.kind, EntryKind::Symlink { .. }) {
+        warn!("Skipping symlink '{}': {}", entry.path, NO_SYMLINKS);
+        return Ok(None);
+    }
+    let relative = paths::sanitize_relative(&entry.path)?;
+    paths::check_no_symlink_ancestors(root, &relative)?;
+    if let EntryKind::Symlink { target } = &entry.kind {
+        paths::check_symlink_target(root, &relative, target)?;
+    }
+
+    let target = root.join(&relative);
+    let Ok(existing) = target.symlink_metadata() else {
+        return Ok(Some(target));
+    };
+
+    if entry.kind == EntryKind::Dir {
+        return if existing.is_dir() {
+            Ok(Some(target))
+        } else {
+            Err(format!("'{}' exists and is not a directory", target.display()))
+        };
+    }
+    if existing.is_dir() {
+        return Err(format!("'{}' is an existing directory", target.display()));
+    }
+
+    match policy {
+        ExistingFilePolicy::Fail => Err(format!(
+            "'{}' already exists (use --overwrite, --skip or --rename)",
+            target.display()
+        )),
+        ExistingFilePolicy::Overwrite => {
+            // Files are renamed over the old one once complete, which also replaces
+            // (never writes through) a symlink; a new symlink needs the name freed now.
+            if matches!(entry.kind, EntryKind::Symlink { .. }) {
+                std::fs::remove_file(&target).map_err(|e| format!("could not replace '{}': {}", target.display(), e))?;
+            }
+            Ok(Some(target))
+        }
+        ExistingFilePolicy::Skip => Ok(None),
+        ExistingFilePolicy::Rename => Ok(Some(paths::renamed_path(&target))),
+    }
+}
+
+/// Where received file data goes.
+enum Sink<'a> {
+    /// The file is being skipped.
+    Discard,
+    File(&'a mut File),
+    Stdout(&'a mut Stdout),
+}
+
+impl Sink<'_> {
+    async fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        match self {
+            Sink::Discard => Ok(()),
+            Sink::File(file) => file.write_all(data).await,
+            Sink::Stdout(out) => out.write_all(data).await,
+        }
+    }
+
+    /// Skip `len` zero bytes. Files were created at full length, so seeking leaves the hole unallocated.
+    async fn skip(&mut self, len: u64) -> std::io::Result<()> {
+        match self {
+            Sink::Discard => Ok(()),
+            Sink::File(file) => {
+                let offset = i64::try_from(len).map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "hole too large"))?;
+                file.seek(SeekFrom::Current(offset)).await.map(|_| ())
+            }
+            Sink::Stdout(out) => {
+                let mut remaining = len;
+                while remaining > 0 {
+                    let step = remaining.min(ZEROS.len() as u64) as usize;
+                    out.write_all(&ZEROS[..step]).await?;
+                    remaining -= step as u64;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Sink::Discard => Ok(()),
+            Sink::File(file) => file.flush().await,
+            Sink::Stdout(out) => out.flush().await,
+        }
+    }
+}
+
+/// Read one data chunk off the socket, counting its wire bytes.
+async fn read_chunk(socket: &mut TcpStream, context: &mut StreamContext) -> Result<Chunk, Box<dyn std::error::Error + Send + Sync>> {
+    let frame = read_frame(socket).await?.ok_or("connection closed mid-file")?;
+    context.wire(frame.len() + 4).await;
+    compression::decode_chunk(&decrypt_chunk(&frame, AES_KEY).map_err(|e| TransferError::Crypto(e.to_string()))?)
+}
+
+/// Finish a file: check the sender's digest when hashing was negotiated and return ours in hex.
+async fn verify_digest(
+    socket: &mut TcpStream,
+    context: &StreamContext,
+    hasher: Sha256,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let digest = hasher.finalize();
+    if context.negotiated.capabilities.contains(Capabilities::HASHING) {
+        let frame = read_frame(socket).await?.ok_or("connection closed before file digest")?;
+        let expected = decrypt_chunk(&frame, AES_KEY).map_err(|e| TransferError::Crypto(e.to_string()))?;
+        if expected.as_slice() != digest.as_slice() {
+            return Err(TransferError::Integrity("SHA-256 digest mismatch, file is corrupt".to_string()).into());
+        }
+    }
+    Ok(protocol::to_hex(&digest))
+}
+
+/// Read encrypted chunks until `len` bytes have been received and put them
+/// in `sink`. The trailing digest is checked when hashing was negotiated.
+///
+/// Returns the hex SHA-256 of the received bytes.
+async fn receive_range(
+    socket: &mut TcpStream,
+    context: &mut StreamContext,
+    mut sink: Sink<'_>,
+    len: u64,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut hasher = Sha256::new();
+    let mut total_written = 0u64;
+    while total_written < len {
+        let chunk = read_chunk(socket, context).await?;
+        let n = match &chunk {
+            Chunk::Data(data) => data.len() as u64,
+            Chunk::Hole(n) => *n,
+        };
+        // `total_written < len` here, so this can't overflow the way `total_written + n` could.
+        if n > len - total_written {
+            return Err("sender sent more data than announced".into());
+        }
+
+        match chunk {
+            Chunk::Data(data) => {
+                hasher.update(&data);
+                sink.write(&data).await?;
+            }
+            Chunk::Hole(n) => {
+                let mut remaining = n;
+                while remaining > 0 {
+                    let step = remaining.min(ZEROS.len() as u64) as usize;
+                    hasher.update(&ZEROS[..step]);
+                    remaining -= step as u64;
+                }
+                sink.skip(n).await?;
+            }
+        }
+
+        total_written += n;
+        context.stats.file_bytes += n;
+        context.current.set_position(total_written);
+        context.overall.inc(n);
+    }
+
+    sink.flush().await?;
+    verify_digest(socket, context, hasher).await
+}
+
+/// Read a stream of unknown length into `sink` until its empty end-of-stream chunk.
+///
+/// Returns the number of bytes received and their hex SHA-256.
+async fn receive_stream(
+    socket: &mut TcpStream,
+    context: &mut StreamContext,
+    mut sink: Sink<'_>,
+) -> Result<(u64, String), Box<dyn std::error::Error + Send + Sync>> {
+    let mut hasher = Sha256::new();
+    let mut total_written = 0u64;
+    loop {
+        let Chunk::Data(data) = read_chunk(socket, context).await? else {
+            return Err("unexpected hole in a stream".into());
+        };
+        if data.is_empty() {
+            break;
+        }
+
+        hasher.update(&data);
+        sink.write(&data).await?;
+        total_written += data.len() as u64;
+        context.stats.file_bytes += data.len() as u64;
+        context.overall.inc(data.len() as u64);
+    }
+
+    sink.flush().await?;
+    Ok((total_written, verify_digest(socket, context, hasher).await?))
+}
+
+/// Write the single file or stream of an upload to stdout (`--output -`).
+async fn receive_to_stdout(
+    socket: &mut TcpStream,
+    header: TransferHeader,
+    negotiated: Negotiated,
+    events: &Reporter,
+    limiter: Option<Arc<RateLimiter>>,
+) -> Result<TransferReport, Box<dyn std::error::Error + Send + Sync>> {
+    let entry = header.manifest.entries.first().ok_or("empty upload")?;
+    let total = (entry.kind == EntryKind::File).then_some(entry.size);
+    events.emit(&Event::Started { files: 1, total_bytes: total });
+    let mut context = StreamContext::new(negotiated, CHUNK_SIZE, total, "green/white", events).with_limiter(limiter);
+    context.current = ProgressBar::hidden();
+
+    let mut out = tokio::io::stdout();
+    let (size, digest) = if entry.kind == EntryKind::Stream {
+        receive_stream(socket, &mut context, Sink::Stdout(&mut out)).await?
+    } else {
+        (entry.size, receive_range(socket, &mut context, Sink::Stdout(&mut out), entry.size).await?)
+    };
+    context.overall.finish_and_clear();
+    if negotiated.capabilities.contains(Capabilities::HASHING) {
+        events.emit(&Event::Verified { path: &entry.path, sha256: &digest });
+    }
+    events.emit(&Event::FileCompleted { path: &entry.path, size });
+
+    Ok(TransferReport {
+        session_id: protocol::to_hex(&header.session_id),
+        peer: String::new(),
+        root: Some(PathBuf::from("-")),
+        files: vec![TransferredFile { path: entry.path.clone(), size, sha256: Some(digest) }],
+        skipped: Vec::new(),
+        stats: context.stats,
+        compression: negotiated.compression,
+        streams: 1,
+        duration: Duration::ZERO,
+    })
+}
+
+/// Hex SHA-256 of a file on disk.
+async fn file_digest(path: &Path) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut file = File::open(path).await?;
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut hasher = Sha256::new();
+    loop {
+        let n = file.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(protocol::to_hex(&hasher.finalize()))
+}
+
+#[cfg(unix)]
+fn create_symlink(link: &str, path: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    std::os::unix::fs::symlink(link, path)?;
+    Ok(())
+}
+
+/// Never reached: [`resolve_destination`] skips symlinks on these platforms.
+#[cfg(not(unix))]
+fn create_symlink(_link: &str, _path: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    Err(NO_SYMLINKS.into())
+}
+
+/// Restore the metadata recorded in the manifest: always permissions and
+/// mtime, plus atime when the sender preserved it.
+///
+/// Ownership, extended attributes and setuid, setgid and sticky bits are only
+/// applied when `privileged`: otherwise a sender could plant a setuid-root
+/// program, or one with file capabilities, on a receiver running as root.
+fn apply_metadata(path: &Path, entry: &ManifestEntry, privileged: bool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    #[cfg(unix)]
+    if privileged && let Some(extra) = &entry.extra {
+        apply_ownership_and_xattrs(path, extra)?;
+    }
+    if matches!(entry.kind, EntryKind::Symlink { .. }) {
+        return Ok(());
+    }
+
+    let mut times = FileTimes::new().set_modified(UNIX_EPOCH + Duration::from_secs(entry.mtime.max(0) as u64));
+    if let Some(extra) = &entry.extra {
+        times = times.set_accessed(UNIX_EPOCH + Duration::from_secs(extra.atime.max(0) as u64));
+    }
+    std::fs::File::open(path)?.set_times(times)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mask = if privileged { 0o7777 } else { 0o777 };
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(entry.mode & mask))?;
+    }
+    Ok(())
+}
+
+/// Ownership is only restored when running as root; ordinary users can't give files away.
+#[cfg(unix)]
+fn apply_ownership_and_xattrs(path: &Path, extra: &ExtraMetadata) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // SAFETY: geteuid has no preconditions and cannot fail.
+    if unsafe { libc::geteuid() } == 0 {
+        std::os::unix::fs::lchown(path, Some(extra.uid), Some(extra.gid))?;
+    }
+    for (name, value) in &extra.xattrs {
+        xattr::set(path, name, value).map_err(|e| format!("xattr '{}': {}", name, e))?;
+    }
+    Ok(())
+}
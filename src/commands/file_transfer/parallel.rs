@@ -0,0 +1,117 @@
+use std::{path::PathBuf, time::Duration};
+
+use futures::future::try_join_all;
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncSeekExt, SeekFrom},
+    net::TcpStream,
+    sync::mpsc,
+    time::timeout,
+};
+
+use super::compression::TransferStats;
+use super::protocol::{self, SessionId};
+use super::{AES_KEY, Sink, StreamContext, error, receive_range, send_range};
+
+/// How long the receiver waits for the sender's extra data connections.
+const JOIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Extra data connections handed from the accept loop to the transfer they belong to.
+pub type JoinedStreams = mpsc::UnboundedReceiver<(u16, TcpStream)>;
+
+/// Byte range `(offset, len)` of a `size`-byte file carried by stream `index` of `streams`.
+pub fn stream_range(size: u64, streams: u16, index: u16) -> (u64, u64) {
+    let start = (size as u128 * index as u128 / streams as u128) as u64;
+    let end = (size as u128 * (index as u128 + 1) / streams as u128) as u64;
+    (start, end - start)
+}
+
+/// Open the additional connections for streams `1..streams` of `session_id`.
+pub async fn open_extra_streams(address: &str, session_id: SessionId, streams: u16) -> Result<Vec<TcpStream>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut extra = Vec::with_capacity(streams.saturating_sub(1) as usize);
+    for index in 1..streams {
+        let mut stream = TcpStream::connect(address).await?;
+        protocol::join(&mut stream, session_id, index, AES_KEY).await?;
+        extra.push(stream);
+    }
+    Ok(extra)
+}
+
+/// Send every file in `files` split across `streams`, each carrying its own byte range of every file.
+pub async fn send_parallel(
+    streams: Vec<&mut TcpStream>,
+    files: &[(PathBuf, u64)],
+    context: &StreamContext,
+) -> Result<TransferStats, Box<dyn std::error::Error + Send + Sync>> {
+    let count = streams.len() as u16;
+    let tasks = streams.into_iter().enumerate().map(|(index, stream)| {
+        let mut context = context.for_stream();
+        async move {
+            for (source, size) in files {
+                let (offset, len) = stream_range(*size, count, index as u16);
+                send_range(stream, &mut context, source, offset, len)
+                    .await
+                    .map_err(|e| format!("stream {} failed on '{}': {}", index, source.display(), e))?;
+            }
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(context.stats)
+        }
+    });
+
+    Ok(try_join_all(tasks).await?.into_iter().fold(TransferStats::default(), TransferStats::merge))
+}
+
+/// Collect the sender's extra connections for this session, ordered by stream index.
+pub async fn collect_streams(mut joins: JoinedStreams, streams: u16) -> Result<Vec<TcpStream>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut slots: Vec<Option<TcpStream>> = (1..streams).map(|_| None).collect();
+    let mut missing = slots.len();
+
+    while missing > 0 {
+        let (index, stream) = timeout(JOIN_TIMEOUT, joins.recv())
+            .await
+            .map_err(|_| format!("only {} of {} data streams connected", streams as usize - missing, streams))?
+            .ok_or("transfer closed while waiting for data streams")?;
+        let slot = slots
+            .get_mut((index as usize).wrapping_sub(1))
+            .ok_or_else(|| format!("invalid stream index {}", index))?;
+        if slot.replace(stream).is_some() {
+            return Err(format!("stream {} joined twice", index).into());
+        }
+        missing -= 1;
+    }
+
+    Ok(slots.into_iter().flatten().collect())
+}
+
+/// Receive every file in `files` from `streams`, each stream writing its own range with positioned writes.
+///
+/// A `None` destination means the file is being skipped and its bytes are discarded.
+pub async fn receive_parallel(
+    streams: Vec<&mut TcpStream>,
+    files: &[(Option<PathBuf>, u64)],
+    context: &StreamContext,
+) -> Result<TransferStats, Box<dyn std::error::Error + Send + Sync>> {
+    let count = streams.len() as u16;
+    let tasks = streams.into_iter().enumerate().map(|(index, stream)| {
+        let mut context = context.for_stream();
+        async move {
+            for (destination, size) in files {
+                let (offset, len) = stream_range(*size, count, index as u16);
+                let mut file = match destination {
+                    Some(path) => {
+                        let mut file = OpenOptions::new().write(true).open(path).await?;
+                        file.seek(SeekFrom::Start(offset)).await?;
+                        Some(file)
+                    }
+                    None => None,
+                };
+                let sink = file.as_mut().map_or(Sink::Discard, Sink::File);
+                receive_range(stream, &mut context, sink, len)
+                    .await
+                    .map_err(|e| error::context(e, format!("stream {} failed", index)))?;
+            }
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(context.stats)
+        }
+    });
+
+    Ok(try_join_all(tasks).await?.into_iter().fold(TransferStats::default(), TransferStats::merge))
+}
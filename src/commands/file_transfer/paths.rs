@@ -0,0 +1,194 @@
+use std::path::{Component, Path, PathBuf};
+
+/// What to do when an incoming file already exists in the output directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExistingFilePolicy {
+    /// Abort the transfer rather than touch the existing file.
+    #[default]
+    Fail,
+    /// Replace the existing file.
+    Overwrite,
+    /// Keep the existing file and discard the incoming one.
+    Skip,
+    /// Save the incoming file next to it as `name (1).ext`, `name (2).ext`, ...
+    Rename,
+}
+
+/// Turn a sender-supplied `/`-separated path into a relative path that stays inside the output directory.
+///
+/// Absolute paths, drive prefixes, `..` components, backslashes and control
+/// characters are rejected outright rather than silently rewritten.
+pub fn sanitize_relative(path: &str) -> Result<PathBuf, String> {
+    if path.is_empty() {
+        return Err("empty path".to_string());
+    }
+    if path.starts_with('/') {
+        return Err(format!("absolute path '{}' not allowed", path));
+    }
+    if path.contains('\\') {
+        return Err(format!("backslash in path '{}' not allowed", path.escape_debug()));
+    }
+    if path.chars().any(|c| c.is_control()) {
+        return Err(format!("control character in path '{}' not allowed", path.escape_debug()));
+    }
+
+    let mut clean = PathBuf::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => continue,
+            ".." => return Err(format!("'..' in path '{}' not allowed", path)),
+            _ => {}
+        }
+        // A component like `C:` would turn the join into an absolute path on Windows.
+        if !matches!(Path::new(part).components().next(), Some(Component::Normal(_))) {
+            return Err(format!("invalid component '{}' in '{}'", part, path));
+        }
+        clean.push(part);
+    }
+
+    if clean.as_os_str().is_empty() {
+        return Err(format!("path '{}' has no usable components", path));
+    }
+    Ok(clean)
+}
+
+/// Refuse to write through a symlink that already exists in the output tree.
+///
+/// Every directory between `root` and the final component of `relative` must
+/// be a real directory (or not exist yet), otherwise a previously planted
+/// link could redirect the write outside `root`.
+pub fn check_no_symlink_ancestors(root: &Path, relative: &Path) -> Result<(), String> {
+    let mut current = root.to_path_buf();
+    let parents: Vec<_> = relative.parent().map(|p| p.components().collect()).unwrap_or_default();
+    for component in parents {
+        current.push(component);
+        match current.symlink_metadata() {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                return Err(format!("'{}' is a symlink, refusing to write through it", current.display()));
+            }
+            Ok(meta) if !meta.is_dir() => {
+                return Err(format!("'{}' exists and is not a directory", current.display()));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Make sure a received symlink at `relative` under `root` cannot point outside it.
+///
+/// Counting `..` is not enough once other links exist: after `d/l -> ..`, the
+/// target `l/..` climbs out of `root`, because `..` leaves wherever `l` points.
+/// So a `..` may only step out of the link's own parent directories, or out of
+/// one that is already a real directory under `root`; a directory that exists
+/// can't be replaced by a link later, while a missing name could still arrive as one.
+pub fn check_symlink_target(root: &Path, relative: &Path, target: &str) -> Result<(), String> {
+    let target_path = Path::new(target);
+    if target_path.is_absolute() || target.starts_with('/') {
+        return Err(format!("symlink to absolute path '{}' not allowed", target));
+    }
+
+    let mut resolved: Vec<Component> = relative.parent().map(|p| p.components().collect()).unwrap_or_default();
+    // The leading components of `resolved` that are the link's own parents,
+    // which are checked and created as real directories.
+    let mut ancestors = resolved.len();
+    for component in target_path.components() {
+        match component {
+            Component::ParentDir if resolved.is_empty() => {
+                return Err(format!("symlink target '{}' escapes the output directory", target));
+            }
+            Component::ParentDir if resolved.len() <= ancestors => {
+                resolved.pop();
+                ancestors = resolved.len();
+            }
+            Component::ParentDir => {
+                let dir = root.join(resolved.iter().collect::<PathBuf>());
+                if !dir.symlink_metadata().is_ok_and(|m| m.is_dir()) {
+                    return Err(format!("symlink target '{}' climbs out of '{}', which is not a directory", target, dir.display()));
+                }
+                resolved.pop();
+            }
+            Component::Normal(_) => resolved.push(component),
+            Component::CurDir => {}
+            _ => return Err(format!("invalid symlink target '{}'", target)),
+        }
+    }
+    Ok(())
+}
+
+/// Find a free `name (n).ext` sibling of `path`.
+pub fn renamed_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let extension = path.extension().map(|e| e.to_string_lossy().into_owned());
+
+    (1..)
+        .map(|n| {
+            let name = match &extension {
+                Some(ext) => format!("{} ({}).{}", stem, n, ext),
+                None => format!("{} ({})", stem, n),
+            };
+            path.with_file_name(name)
+        })
+        .find(|candidate| candidate.symlink_metadata().is_err())
+        .expect("unbounded counter always finds a free name")
+}
+
+/// The hidden `.name.ntpart` sibling a file is received into before being renamed over `path`.
+pub fn partial_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!(".{}.ntpart", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_keeps_normal_components() {
+        assert_eq!(sanitize_relative("a/./b//c.txt").unwrap(), PathBuf::from("a/b/c.txt"));
+    }
+
+    #[test]
+    fn sanitize_rejects_escapes() {
+        for path in ["", "/etc/passwd", "a/../../b", "..", "a\\b", "a/\u{1b}[2J", ".", "./"] {
+            assert!(sanitize_relative(path).is_err(), "{:?} was accepted", path);
+        }
+    }
+
+    #[test]
+    fn symlink_targets_inside_the_root_are_allowed() {
+        let root = tempfile::tempdir().unwrap();
+        assert!(check_symlink_target(root.path(), Path::new("a/link"), "../b/file").is_ok());
+        assert!(check_symlink_target(root.path(), Path::new("a/b/link"), "../../c").is_ok());
+        assert!(check_symlink_target(root.path(), Path::new("link"), "./sub/file").is_ok());
+    }
+
+    #[test]
+    fn symlink_targets_outside_the_root_are_rejected() {
+        let root = tempfile::tempdir().unwrap();
+        assert!(check_symlink_target(root.path(), Path::new("link"), "/etc/passwd").is_err());
+        assert!(check_symlink_target(root.path(), Path::new("link"), "..").is_err());
+        assert!(check_symlink_target(root.path(), Path::new("a/link"), "../../x").is_err());
+        assert!(check_symlink_target(root.path(), Path::new("a/link"), "b/../../../x").is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn chained_symlinks_cannot_escape() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("d")).unwrap();
+        assert!(check_symlink_target(root.path(), Path::new("d/l"), "..").is_ok());
+        std::os::unix::fs::symlink("..", root.path().join("d/l")).unwrap();
+        // `d/l/..` is the parent of the root, not `d`.
+        assert!(check_symlink_target(root.path(), Path::new("d/m"), "l/..").is_err());
+    }
+
+    #[test]
+    fn climbing_out_of_a_missing_directory_is_rejected() {
+        // `x` could still arrive as a link to `..`.
+        let root = tempfile::tempdir().unwrap();
+        assert!(check_symlink_target(root.path(), Path::new("d/m"), "x/..").is_err());
+        std::fs::create_dir_all(root.path().join("d/x")).unwrap();
+        assert!(check_symlink_target(root.path(), Path::new("d/m"), "x/..").is_ok());
+    }
+}
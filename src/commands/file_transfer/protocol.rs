@@ -0,0 +1,275 @@
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::compression::Compression;
+use super::manifest::{Manifest, ManifestEntry};
+use super::TransferError;
+use crate::utils::encryption::decrypt_chunk;
+use crate::utils::networking::{read_frame, write_encrypted_frame};
+
+/// Identifies a nettool file transfer connection before anything else is parsed.
+pub const MAGIC: [u8; 4] = *b"NTFT";
+/// Wire protocol version spoken by this build.
+pub const PROTOCOL_VERSION: u16 = 4;
+/// Oldest peer version this build can still talk to.
+pub const MIN_PROTOCOL_VERSION: u16 = 4;
+/// Most parallel data connections a receiver grants to one transfer.
+pub const MAX_STREAMS: u16 = 32;
+
+/// Optional protocol features, offered by the sender and narrowed down by the receiver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 0);
+    // Reserved on the wire until both ends implement resuming.
+    #[allow(dead_code)]
+    pub const RESUME: Capabilities = Capabilities(1 << 1);
+    pub const HASHING: Capabilities = Capabilities(1 << 2);
+    pub const PARALLEL: Capabilities = Capabilities(1 << 3);
+    pub const DELTA: Capabilities = Capabilities(1 << 4);
+    pub const SPARSE: Capabilities = Capabilities(1 << 5);
+
+    /// Everything this build knows how to do.
+    pub const SUPPORTED: Capabilities =
+        Capabilities(Self::COMPRESSION.0 | Self::HASHING.0 | Self::PARALLEL.0 | Self::DELTA.0 | Self::SPARSE.0);
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+
+    pub fn without(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & !other.0)
+    }
+}
+
+/// Random identifier tying together everything that belongs to one transfer.
+pub type SessionId = [u8; 16];
+
+pub fn new_session_id() -> SessionId {
+    let mut id = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut id);
+    id
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Describes a transfer; carried by [`Opening::Transfer`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferHeader {
+    pub version: u16,
+    pub capabilities: Capabilities,
+    pub session_id: SessionId,
+    /// Algorithm the sender wants to use when [`Capabilities::COMPRESSION`] is offered.
+    pub compression: Compression,
+    /// Data connections the sender would like to use when [`Capabilities::PARALLEL`] is offered.
+    pub streams: u16,
+    pub manifest: Manifest,
+}
+
+/// First encrypted message on every sender connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Opening {
+    /// Start a new transfer.
+    Transfer(TransferHeader),
+    /// Attach extra data stream `index` to the accepted transfer `session_id`.
+    Join { session_id: SessionId, index: u16 },
+}
+
+/// Receiver's answer to a [`TransferHeader`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum HeaderReply {
+    /// Go ahead, using only the listed capabilities.
+    Accepted { capabilities: Capabilities, compression: Compression, streams: u16 },
+    Rejected { reason: String },
+}
+
+/// Request sent by a pull client (`get`/`ls`) right after the version exchange.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PullRequest {
+    /// List a directory (or describe a single file) under the served root.
+    List { path: String },
+    /// Download a file or directory tree under the served root.
+    Get { path: String },
+}
+
+/// File server's answer to a [`PullRequest`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PullResponse {
+    Listing { entries: Vec<ManifestEntry> },
+    /// The server is about to act as a sender: an [`Opening::Transfer`] follows.
+    Sending,
+    Error { reason: String },
+}
+
+/// Outcome of the handshake that governs how file data is framed.
+#[derive(Debug, Clone, Copy)]
+pub struct Negotiated {
+    pub capabilities: Capabilities,
+    pub compression: Compression,
+    /// Number of data connections, including the control connection.
+    pub streams: u16,
+}
+
+/// Exchange magic and versions, returning the version both sides will speak.
+///
+/// Each side writes its preamble before reading the peer's, so either end
+/// can produce a clean error about a mismatch instead of a parse failure.
+pub async fn exchange_versions<S>(stream: &mut S) -> Result<u16, Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut preamble = MAGIC.to_vec();
+    preamble.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    stream.write_all(&preamble).await?;
+
+    let mut peer = [0u8; 6];
+    stream
+        .read_exact(&mut peer)
+        .await
+        .map_err(|e| format!("peer closed the connection during the version exchange: {}", e))?;
+    if peer[..4] != MAGIC {
+        return Err("peer is not speaking the nettool file transfer protocol".into());
+    }
+
+    let peer_version = u16::from_be_bytes([peer[4], peer[5]]);
+    let version = peer_version.min(PROTOCOL_VERSION);
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(format!(
+            "incompatible protocol version: peer speaks v{}, this build supports v{}..=v{}",
+            peer_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        )
+        .into());
+    }
+    Ok(version)
+}
+
+/// Encode `value` with bincode and send it as an encrypted frame.
+pub async fn write_message<W, T>(writer: &mut W, value: &T, key: &[u8]) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let encoded = bincode::serialize(value)?;
+    write_encrypted_frame(writer, &encoded, key).await?;
+    Ok(())
+}
+
+/// Read an encrypted frame and decode it with bincode.
+pub async fn read_message<R, T>(reader: &mut R, key: &[u8]) -> Result<T, Box<dyn std::error::Error + Send + Sync>>
+where
+    R: AsyncRead + Unpin,
+    T: serde::de::DeserializeOwned,
+{
+    let frame = read_frame(reader)
+        .await?
+        .ok_or("connection closed while waiting for a protocol message")?;
+    let frame = decrypt_chunk(&frame, key).map_err(|e| TransferError::Crypto(e.to_string()))?;
+    bincode::deserialize(&frame).map_err(|e| format!("malformed protocol message: {}", e).into())
+}
+
+/// Sender side of the handshake: agree on a version, send `header` and wait for the verdict.
+///
+/// Returns what the receiver accepted.
+pub async fn offer<S>(stream: &mut S, header: &mut TransferHeader, key: &[u8]) -> Result<Negotiated, Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    header.version = exchange_versions(stream).await?;
+    offer_header(stream, header, key).await
+}
+
+/// Send `header` on a connection whose version was already agreed and wait for the verdict.
+pub async fn offer_header<S>(stream: &mut S, header: &TransferHeader, key: &[u8]) -> Result<Negotiated, Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    write_message(stream, &Opening::Transfer(header.clone()), key).await?;
+
+    match read_message(stream, key).await? {
+        HeaderReply::Accepted { capabilities, compression, streams } => {
+            let capabilities = capabilities.intersection(header.capabilities);
+            let compression = if capabilities.contains(Capabilities::COMPRESSION) {
+                compression
+            } else {
+                Compression::None
+            };
+            let streams = if capabilities.contains(Capabilities::PARALLEL) {
+                streams.clamp(1, header.streams)
+            } else {
+                1
+            };
+            Ok(Negotiated { capabilities, compression, streams })
+        }
+        HeaderReply::Rejected { reason } => Err(format!("receiver rejected the transfer: {}", reason).into()),
+    }
+}
+
+/// Attach an extra data connection to an accepted multi-stream transfer.
+pub async fn join<S>(stream: &mut S, session_id: SessionId, index: u16, key: &[u8]) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    exchange_versions(stream).await?;
+    write_message(stream, &Opening::Join { session_id, index }, key).await
+}
+
+/// Receiver side: read the first message of a connection whose version was already agreed.
+pub async fn read_opening<S>(stream: &mut S, key: &[u8]) -> Result<Opening, Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + Unpin,
+{
+    read_message(stream, key).await
+}
+
+/// Receiver side: answer a sender's header on a connection already speaking `version`.
+///
+/// `check` gets a chance to refuse the upload (size limits, quotas); its
+/// reason is sent back to the sender. Otherwise the header is accepted with
+/// whatever capabilities both sides support.
+pub async fn answer_header<S, F>(
+    stream: &mut S,
+    key: &[u8],
+    version: u16,
+    header: &TransferHeader,
+    check: F,
+) -> Result<Negotiated, Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: FnOnce(&TransferHeader) -> Result<(), String>,
+{
+    if header.version != version {
+        let reason = format!("header is v{} but v{} was negotiated", header.version, version);
+        write_message(stream, &HeaderReply::Rejected { reason: reason.clone() }, key).await?;
+        return Err(reason.into());
+    }
+    if let Err(reason) = check(header) {
+        write_message(stream, &HeaderReply::Rejected { reason: reason.clone() }, key).await?;
+        return Err(reason.into());
+    }
+
+    let mut capabilities = header.capabilities.intersection(Capabilities::SUPPORTED);
+    if header.compression == Compression::None {
+        capabilities = capabilities.without(Capabilities::COMPRESSION);
+    }
+    let compression = if capabilities.contains(Capabilities::COMPRESSION) {
+        header.compression
+    } else {
+        Compression::None
+    };
+    let streams = if capabilities.contains(Capabilities::PARALLEL) {
+        header.streams.clamp(1, MAX_STREAMS)
+    } else {
+        1
+    };
+
+    write_message(stream, &HeaderReply::Accepted { capabilities, compression, streams }, key).await?;
+    Ok(Negotiated { capabilities, compression, streams })
+}
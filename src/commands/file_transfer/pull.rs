@@ -0,0 +1,364 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
+
+use chrono::{Local, TimeZone};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{error, info, warn};
+
+use super::manifest::{EntryKind, Manifest, ManifestEntry};
+use super::protocol::{self, Capabilities, Opening, PullRequest, PullResponse, TransferHeader};
+use super::error::handshake;
+use super::events::Reporter;
+use super::{
+    AES_KEY, CHUNK_SIZE, Compression, Event, ReceiveOptions, StreamContext, TransferError, TransferReport, WriteOptions, cancel_on_ctrl_c, paths,
+    print_failure, receive_entries, send_entries,
+};
+use crate::utils::rate_limit::{RateLimiter, RateSchedule};
+
+/// Expose `root` read-only to `get` and `ls` clients.
+///
+/// Every requested path is resolved inside `root`; anything that would
+/// escape it, including via symlinks, is refused.
+pub async fn serve(
+    root: &str,
+    port: u16,
+    compression: Compression,
+    preserve: bool,
+    limit_rate: Option<RateSchedule>,
+) -> Result<(), TransferError> {
+    let root = match Path::new(root).canonicalize() {
+        Ok(r) if r.is_dir() => Arc::new(r),
+        Ok(r) => {
+            let e = TransferError::Local(format!("'{}' is not a directory", r.display()));
+            eprintln!("{}", e);
+            return Err(e);
+        }
+        Err(e) => {
+            let e = TransferError::Local(format!("Could not open served root '{}': {}", root, e));
+            eprintln!("{}", e);
+            return Err(e);
+        }
+    };
+
+    tracing_subscriber::fmt::init();
+    let listener = match TcpListener::bind(("0.0.0.0", port)).await {
+        Ok(l) => l,
+        Err(e) => {
+            let e = TransferError::Connect(format!("Could not listen on port {}: {}", port, e));
+            error!("{}", e);
+            return Err(e);
+        }
+    };
+    info!("Serving '{}' read-only on port {}", root.display(), port);
+    let limiter = limit_rate.map(|schedule| Arc::new(RateLimiter::new(schedule)));
+
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Accept failed: {}", e);
+                continue;
+            }
+        };
+
+        let root = Arc::clone(&root);
+        let limiter = limiter.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_pull(socket, &root, compression, preserve, limiter).await {
+                error!(peer = %addr, "Request failed: {}", e);
+            }
+        });
+    }
+}
+
+/// Answer a single `ls` or `get` request.
+async fn handle_pull(
+    mut socket: TcpStream,
+    root: &Path,
+    compression: Compression,
+    preserve: bool,
+    limiter: Option<Arc<RateLimiter>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let addr = socket.peer_addr()?;
+    let version = protocol::exchange_versions(&mut socket).await?;
+    let request: PullRequest = protocol::read_message(&mut socket, AES_KEY).await?;
+
+    match request {
+        PullRequest::List { path } => {
+            let response = match resolve_in_root(root, &path).and_then(|p| Manifest::list(&p).map_err(|e| e.to_string())) {
+                Ok(entries) => PullResponse::Listing { entries },
+                Err(reason) => PullResponse::Error { reason },
+            };
+            info!(peer = %addr, "ls '{}'", path);
+            protocol::write_message(&mut socket, &response, AES_KEY).await
+        }
+        PullRequest::Get { path } => {
+            let prepared = resolve_in_root(root, &path).and_then(|p| Manifest::build(&[p], preserve).map_err(|e| e.to_string()));
+            let (mut manifest, sources) = match prepared {
+                Ok(m) => m,
+                Err(reason) => {
+                    protocol::write_message(&mut socket, &PullResponse::Error { reason: reason.clone() }, AES_KEY).await?;
+                    return Err(reason.into());
+                }
+            };
+            for special in std::mem::take(&mut manifest.special) {
+                warn!(peer = %addr, "Skipping special file '{}'", special.display());
+            }
+            protocol::write_message(&mut socket, &PullResponse::Sending, AES_KEY).await?;
+
+            let header = TransferHeader {
+                version,
+                capabilities: Capabilities::SUPPORTED,
+                session_id: protocol::new_session_id(),
+                compression,
+                streams: 1,
+                manifest,
+            };
+            let negotiated = protocol::offer_header(&mut socket, &header, AES_KEY).await?;
+            let total = Some(header.manifest.total_size());
+            let mut context = StreamContext::new(negotiated, CHUNK_SIZE, total, "cyan/blue", &Reporter::silent()).with_limiter(limiter);
+            send_entries(&mut socket, &header.manifest, &sources, &mut context, None).await?;
+            let stats = context.stats;
+            info!(
+                peer = %addr,
+                session = %protocol::to_hex(&header.session_id),
+                files = header.manifest.file_count(),
+                bytes = stats.file_bytes,
+                "get '{}'",
+                path
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Map a client-supplied path onto the served root, refusing anything outside it.
+fn resolve_in_root(root: &Path, requested: &str) -> Result<PathBuf, String> {
+    let trimmed = requested.trim_start_matches('/');
+    let candidate = if trimmed.is_empty() || trimmed == "." {
+        root.to_path_buf()
+    } else {
+        root.join(paths::sanitize_relative(trimmed)?)
+    };
+
+    // Canonicalising resolves any symlinks along the way, so a link pointing
+    // outside the share is caught here even though its name looks harmless.
+    // The resolved path is what gets served, so a link swapped after this
+    // check can't redirect the request.
+    let resolved = candidate
+        .canonicalize()
+        .map_err(|_| format!("'{}' not found", requested))?;
+    if !resolved.starts_with(root) {
+        return Err(format!("'{}' is outside the served directory", requested));
+    }
+    Ok(resolved)
+}
+
+/// Connect to a file server, complete the version exchange and send `request`.
+async fn connect(address: &str, request: &PullRequest) -> Result<(TcpStream, u16), Box<dyn std::error::Error + Send + Sync>> {
+    let mut stream = TcpStream::connect(address)
+        .await
+        .map_err(|e| TransferError::Connect(format!("Failed to connect to file server at {}: {}", address, e)))?;
+    let version = protocol::exchange_versions(&mut stream)
+        .await
+        .map_err(|e| handshake(e, "version exchange failed"))?;
+    protocol::write_message(&mut stream, request, AES_KEY).await?;
+    Ok((stream, version))
+}
+
+/// Download `path` from the file server at `address` into `output_dir`.
+///
+/// Ctrl-C stops the download and removes its partial files.
+pub async fn get(address: &str, path: &str, output_dir: &str, options: ReceiveOptions) -> Result<(), TransferError> {
+    let events = Reporter::new(options.progress, None, true);
+    let cancel = cancel_on_ctrl_c();
+    let fetched = tokio::select! {
+        fetched = fetch(address, path, output_dir, &options, &events) => {
+            fetched.map_err(|e| TransferError::from(e).context(format!("get '{}' from {} failed", path, address)))
+        }
+        _ = cancel.cancelled() => Err(TransferError::Cancelled),
+    };
+    match fetched {
+        Ok(report) => {
+            println!("{}", report);
+            Ok(())
+        }
+        Err(e) => {
+            events.emit(&Event::Error { class: e.class(), exit_code: e.exit_code(), message: &e.to_string() });
+            print_failure(options.progress, &e);
+            Err(e)
+        }
+    }
+}
+
+async fn fetch(
+    address: &str,
+    path: &str,
+    output_dir: &str,
+    options: &ReceiveOptions,
+    events: &Reporter,
+) -> Result<TransferReport, Box<dyn std::error::Error + Send + Sync>> {
+    let started = Instant::now();
+    let (mut stream, version) = connect(address, &PullRequest::Get { path: path.to_string() }).await?;
+    match protocol::read_message(&mut stream, AES_KEY).await? {
+        PullResponse::Sending => {}
+        PullResponse::Error { reason } => return Err(TransferError::Protocol(reason).into()),
+        PullResponse::Listing { .. } => return Err("unexpected listing in reply to get".into()),
+    }
+
+    let header = match protocol::read_opening(&mut stream, AES_KEY).await? {
+        Opening::Transfer(header) => header,
+        Opening::Join { .. } => return Err("unexpected data stream in reply to get".into()),
+    };
+    let negotiated = protocol::answer_header(&mut stream, AES_KEY, version, &header, |_| Ok(()))
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send + Sync> { Box::new(handshake(e, "handshake failed")) })?;
+    let write = WriteOptions {
+        policy: options.policy,
+        events: events.clone(),
+        limiter: options.limit_rate.clone().map(|schedule| Arc::new(RateLimiter::new(schedule))),
+        keep_partial: options.keep_partial,
+        restore_privileged: options.restore_privileged,
+    };
+    let mut report = receive_entries(&mut stream, PathBuf::from(output_dir), &write, header, negotiated, None).await?;
+    report.peer = address.to_string();
+    report.duration = started.elapsed();
+    Ok(report)
+}
+
+/// Print the entries under `path` on the file server at `address`.
+pub async fn list(address: &str, path: &str) -> Result<(), TransferError> {
+    match fetch_listing(address, path).await {
+        Ok(entries) => {
+            for entry in &entries {
+                println!("{}", format_listing(entry));
+            }
+            Ok(())
+        }
+        Err(e) => {
+            let e = TransferError::from(e).context(format!("ls '{}' on {} failed", path, address));
+            eprintln!("{}", e);
+            Err(e)
+        }
+    }
+}
+
+async fn fetch_listing(address: &str, path: &str) -> Result<Vec<ManifestEntry>, Box<dyn std::error::Error + Send + Sync>> {
+    let (mut stream, _) = connect(address, &PullRequest::List { path: path.to_string() }).await?;
+    match protocol::read_message(&mut stream, AES_KEY).await? {
+        PullResponse::Listing { entries } => Ok(entries),
+        PullResponse::Error { reason } => Err(TransferError::Protocol(reason).into()),
+        PullResponse::Sending => Err("unexpected transfer in reply to ls".into()),
+    }
+}
+
+/// One `ls -l`-style line.
+fn format_listing(entry: &ManifestEntry) -> String {
+    let kind = match entry.kind {
+        EntryKind::File | EntryKind::Stream => '-',
+        EntryKind::Dir => 'd',
+        EntryKind::Symlink { .. } => 'l',
+    };
+    let mtime = Local
+        .timestamp_opt(entry.mtime, 0)
+        .single()
+        .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_default();
+    let mut line = format!("{}{:04o} {:>12} {} {}", kind, entry.mode, entry.size, mtime, entry.path);
+    if let EntryKind::Symlink { target } = &entry.kind {
+        line.push_str(" -> ");
+        line.push_str(target);
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    /// A served root with `file.txt`, `docs/guide.txt`, links inside and out
+    /// of it, and a secret next to it.
+    fn share() -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().canonicalize().unwrap();
+        let root = base.join("share");
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(root.join("file.txt"), "hello").unwrap();
+        std::fs::write(root.join("docs/guide.txt"), "read me").unwrap();
+        std::fs::create_dir(base.join("private")).unwrap();
+        std::fs::write(base.join("private/secret.txt"), "secret").unwrap();
+        symlink("file.txt", root.join("alias.txt")).unwrap();
+        symlink("../private/secret.txt", root.join("escape.txt")).unwrap();
+        symlink(base.join("private"), root.join("outside")).unwrap();
+        (dir, root)
+    }
+
+    #[test]
+    fn paths_resolve_inside_the_root() {
+        let (_dir, root) = share();
+        assert_eq!(resolve_in_root(&root, "").unwrap(), root);
+        assert_eq!(resolve_in_root(&root, ".").unwrap(), root);
+        assert_eq!(resolve_in_root(&root, "/").unwrap(), root);
+        assert_eq!(resolve_in_root(&root, "docs/guide.txt").unwrap(), root.join("docs/guide.txt"));
+        // Absolute paths are taken relative to the root.
+        assert_eq!(resolve_in_root(&root, "/file.txt").unwrap(), root.join("file.txt"));
+        // A link within the share is served as what it points to.
+        assert_eq!(resolve_in_root(&root, "alias.txt").unwrap(), root.join("file.txt"));
+    }
+
+    #[test]
+    fn paths_cannot_leave_the_root() {
+        let (_dir, root) = share();
+        assert!(resolve_in_root(&root, "../private/secret.txt").is_err());
+        assert!(resolve_in_root(&root, "docs/../../private").is_err());
+        assert!(resolve_in_root(&root, "/etc/passwd").is_err());
+        assert!(resolve_in_root(&root, "escape.txt").unwrap_err().contains("outside"));
+        assert!(resolve_in_root(&root, "outside").unwrap_err().contains("outside"));
+        assert!(resolve_in_root(&root, "outside/secret.txt").unwrap_err().contains("outside"));
+        assert!(resolve_in_root(&root, "missing.txt").unwrap_err().contains("not found"));
+    }
+
+    /// Serve `root` for exactly one request, returning its address.
+    async fn serve_once(root: PathBuf) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let _ = handle_pull(socket, &root, Compression::None, false, None).await;
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn ls_lists_a_directory_and_refuses_outside_paths() {
+        let (_dir, root) = share();
+        let entries = fetch_listing(&serve_once(root.clone()).await, "docs").await.unwrap();
+        let names: Vec<&str> = entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(names, ["guide.txt"]);
+
+        let refused = fetch_listing(&serve_once(root).await, "outside").await.unwrap_err();
+        assert!(refused.to_string().contains("outside the served directory"), "{}", refused);
+    }
+
+    #[tokio::test]
+    async fn get_downloads_a_directory_and_reports_missing_paths() {
+        let (dir, root) = share();
+        let output = dir.path().join("downloads");
+        std::fs::create_dir(&output).unwrap();
+        let output_dir = output.to_string_lossy().into_owned();
+
+        let address = serve_once(root.clone()).await;
+        let report = fetch(&address, "docs", &output_dir, &ReceiveOptions::default(), &Reporter::silent()).await.unwrap();
+        assert_eq!(report.files.len(), 1);
+        assert_eq!(std::fs::read_to_string(output.join("docs/guide.txt")).unwrap(), "read me");
+
+        let address = serve_once(root).await;
+        let missing = fetch(&address, "nope.txt", &output_dir, &ReceiveOptions::default(), &Reporter::silent()).await.unwrap_err();
+        assert!(missing.to_string().contains("not found"), "{}", missing);
+    }
+}
@@ -1,4 +1,4 @@
-use std::{error::Error, io::{BufRead, Read, Write}, net::{TcpListener, TcpStream}, process::{Command, Stdio}, thread};
+use std::{error::Error, io::{BufRead, Read, Write}, net::{TcpListener, TcpStream}, process::{Command, Stdio}, sync::Arc, thread};
 
 use aes_gcm::{aead::Aead, Aes256Gcm, Key, KeyInit, Nonce};
 use rand::{rngs::OsRng};
@@ -6,6 +6,8 @@ use sha2::Sha256;
 use sha2::digest::Digest;
 use x25519_dalek::{EphemeralSecret, PublicKey};
 
+use crate::utils::rate_limit::{RateLimiter, RateSchedule};
+
 
 fn generate_keypair() -> (EphemeralSecret, PublicKey) {
     let private = EphemeralSecret::random_from_rng(OsRng);
@@ -25,7 +27,7 @@ pub fn derive_shared_key(private: EphemeralSecret, peer_public: PublicKey) -> [u
 fn send_encrypted(stream: &mut TcpStream, key: &Key<Aes256Gcm>, data: &[u8]) -> Result<(), Box<dyn Error>> {
     let cipher = Aes256Gcm::new(key);
     let nonce = Nonce::from_slice(b"unique_nonce"); // 12 bytes
-    let ciphertext = cipher.encrypt(nonce, data).map_err(|_| format!("encryption failed"))?;
+    let ciphertext = cipher.encrypt(nonce, data).map_err(|_| "encryption failed")?;
     stream.write_all(&(ciphertext.len() as u32).to_be_bytes())?;
     stream.write_all(&ciphertext)?;
     Ok(())
@@ -39,13 +41,20 @@ fn receive_encrypted(stream: &mut TcpStream, key: &Key<Aes256Gcm>) -> Result<Vec
     let len = u32::from_be_bytes(len_buf) as usize;
     let mut buffer = vec![0u8; len];
     stream.read_exact(&mut buffer)?;
-    let plaintext = cipher.decrypt(nonce, buffer.as_ref()).map_err(|_| format!("decryption failed"))?;
+    let plaintext = cipher.decrypt(nonce, buffer.as_ref()).map_err(|_| "decryption failed")?;
     Ok(plaintext)
 }
 
 
 
-fn handle_client(mut stream: TcpStream) -> Result<(), Box<dyn Error>> {
+/// Count `bytes` against `limiter`, if there is one, before they go on.
+fn throttle(limiter: Option<&RateLimiter>, bytes: usize) {
+    if let Some(limiter) = limiter {
+        limiter.consume_blocking(bytes);
+    }
+}
+
+fn handle_client(mut stream: TcpStream, limiter: Option<Arc<RateLimiter>>) -> Result<(), Box<dyn Error>> {
     // Key exchange
     let (priv_key, pub_key) = generate_keypair();
     stream.write_all(pub_key.as_bytes())?;
@@ -53,7 +62,7 @@ fn handle_client(mut stream: TcpStream) -> Result<(), Box<dyn Error>> {
     stream.read_exact(&mut peer_pub_bytes)?;
     let peer_pub = PublicKey::from(peer_pub_bytes);
     let shared_key = derive_shared_key(priv_key, peer_pub);
-    let aes_key = Key::<Aes256Gcm>::from_slice(&shared_key).clone();
+    let aes_key = *Key::<Aes256Gcm>::from_slice(&shared_key);
 
     // Shell process
     let mut child = Command::new("/bin/sh")
@@ -65,15 +74,12 @@ fn handle_client(mut stream: TcpStream) -> Result<(), Box<dyn Error>> {
     let mut child_stdout = child.stdout.take().unwrap();
     let mut stream_clone = stream.try_clone()?;
     let key_clone = aes_key;
+    let limiter_clone = limiter.clone();
 
     thread::spawn(move || {
-        loop {
-            match receive_encrypted(&mut stream, &key_clone) {
-                Ok(cmd) => {
-                    let _ = child_stdin.write_all(&cmd);
-                }
-                Err(_) => break,
-            }
+        while let Ok(cmd) = receive_encrypted(&mut stream, &key_clone) {
+            throttle(limiter_clone.as_deref(), cmd.len());
+            let _ = child_stdin.write_all(&cmd);
         }
     });
 
@@ -83,21 +89,25 @@ fn handle_client(mut stream: TcpStream) -> Result<(), Box<dyn Error>> {
         if n == 0 {
             break;
         }
+        throttle(limiter.as_deref(), n);
         send_encrypted(&mut stream_clone, &aes_key, &buffer[..n])?;
     }
     Ok(())
 }
 
-pub fn start_listener(port: u16) -> Result<(), Box<dyn Error>> {
+/// Serve a shell to everyone who connects. `limit_rate` caps the traffic of all sessions together.
+pub fn start_listener(port: u16, limit_rate: Option<RateSchedule>) -> Result<(), Box<dyn Error>> {
     let listener = TcpListener::bind(("0.0.0.0", port))?;
+    let limiter = limit_rate.map(|schedule| Arc::new(RateLimiter::new(schedule)));
     println!("🔒 Listening for remote shell on port {}...", port);
 
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
                 println!("✅ Connection established from {}", stream.peer_addr()?);
-                thread::spawn(|| {
-                    if let Err(e) = handle_client(stream) {
+                let limiter = limiter.clone();
+                thread::spawn(move || {
+                    if let Err(e) = handle_client(stream, limiter) {
                         eprintln!("❌ Client error: {}", e);
                     }
                 });
@@ -108,7 +118,9 @@ pub fn start_listener(port: u16) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-pub fn start_connector(ip: &str, port: u16) -> Result<(), Box<dyn Error>> {
+/// Connect to a shell listener and relay stdin and its output, throttled to `limit_rate`.
+pub fn start_connector(ip: &str, port: u16, limit_rate: Option<RateSchedule>) -> Result<(), Box<dyn Error>> {
+    let limiter = limit_rate.map(|schedule| Arc::new(RateLimiter::new(schedule)));
     let mut stream = TcpStream::connect((ip, port))?;
     println!("🔐 Connected to remote shell at {}:{}", ip, port);
 
@@ -119,23 +131,23 @@ pub fn start_connector(ip: &str, port: u16) -> Result<(), Box<dyn Error>> {
     stream.write_all(pub_key.as_bytes())?;
     let peer_pub = PublicKey::from(peer_pub_bytes);
     let shared_key = derive_shared_key(priv_key, peer_pub);
-    let aes_key = Key::<Aes256Gcm>::from_slice(&shared_key).clone();
+    let aes_key = *Key::<Aes256Gcm>::from_slice(&shared_key);
 
     let mut stream_clone = stream.try_clone()?;
     let key_clone = aes_key;
+    let limiter_clone = limiter.clone();
 
     thread::spawn(move || {
-        loop {
-            match receive_encrypted(&mut stream, &key_clone) {
-                Ok(output) => print!("{}", String::from_utf8_lossy(&output)),
-                Err(_) => break,
-            }
+        while let Ok(output) = receive_encrypted(&mut stream, &key_clone) {
+            throttle(limiter_clone.as_deref(), output.len());
+            print!("{}", String::from_utf8_lossy(&output));
         }
     });
 
     let stdin = std::io::stdin();
     for line in stdin.lock().lines() {
         let line = line? + "\n";
+        throttle(limiter.as_deref(), line.len());
         send_encrypted(&mut stream_clone, &aes_key, line.as_bytes())?;
     }
     Ok(())
@@ -1,7 +1,14 @@
-mod commands;
-mod utils;
+use std::path::PathBuf;
+use std::time::Duration;
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
+use nettool_rust::commands;
+use nettool_rust::commands::file_transfer::{
+    Compression, ExistingFilePolicy, MAX_CHUNK_SIZE, MAX_STREAMS, PackOptions, ProgressMode, ReceiveOptions, SendOptions,
+    UnpackOptions,
+};
+use nettool_rust::utils::rate_limit::{RateSchedule, parse_rate_schedule};
+use nettool_rust::utils::units::{parse_byte_size, parse_duration};
 
 #[derive(Parser)]
 #[command(name = "nettool")]
@@ -11,8 +18,22 @@ struct Cli {
     command: Commands,
 }
 
+/// Exit codes of the file-transfer commands, one per failure class.
+const FILE_TRANSFER_EXIT_CODES: &str = "\
+Exit codes:
+    0  success
+    1  local files could not be read or created, or conflicting options
+    2  invalid command line
+    3  could not connect to the peer or listen on the port
+    4  handshake failed, or the peer refused the transfer or broke the protocol
+    5  connection dropped or I/O failed mid-transfer
+    6  received data failed its SHA-256 check
+    7  a frame could not be decrypted
+  130  interrupted with Ctrl-C";
+
 #[derive(Subcommand)]
 enum Commands {
+    #[command(after_help = FILE_TRANSFER_EXIT_CODES)]
     FileTransfer{
         #[command(subcommand)]
         mode: FileTransferMode,
@@ -26,6 +47,38 @@ enum Commands {
 
         #[arg(short, long)]
         port: u16,
+
+        /// Ed25519 identity key file, created on first use (default: ~/.nettool/chat_{client,server}_ed25519)
+        #[arg(long)]
+        identity: Option<PathBuf>,
+
+        /// Client only: print messages line by line instead of opening the full-screen interface
+        #[arg(long)]
+        plain: bool,
+
+        /// Server only: relay nothing but end-to-end encrypted messages, so the server can't read them
+        #[arg(long)]
+        e2e: bool,
+
+        /// Server only: registered usernames and password hashes, created when the first user runs /register
+        #[arg(long, value_name = "FILE")]
+        accounts: Option<PathBuf>,
+
+        /// Server only: keep each room's messages in this directory, encrypted with ~/.nettool/chat_history_key
+        #[arg(long, value_name = "DIR")]
+        history: Option<PathBuf>,
+
+        /// Server only: recent messages sent to a client joining a room
+        #[arg(long, value_name = "N", default_value_t = 20)]
+        history_on_join: usize,
+
+        /// Server only: how long direct messages for offline users are kept (e.g. 12h, 7d)
+        #[arg(long, value_name = "DURATION", default_value = "7d", value_parser = parse_duration)]
+        offline_retention: Duration,
+
+        /// Server only: direct messages each offline user can have waiting; 0 turns queueing off
+        #[arg(long, value_name = "N", default_value_t = 100)]
+        offline_limit: usize,
     },
     PortScan,
 
@@ -38,23 +91,228 @@ enum Commands {
 
 #[derive(Subcommand)]
 enum FileTransferMode {
+    /// Send one or more files or directories (recursively), or stdin with `-f -`
     Send {
-        #[arg(short = 'f', long)]
-        file: String,
+        #[arg(short = 'f', long = "file", required = true, num_args = 1..)]
+        files: Vec<String>,
+
+        /// File name for the receiver when sending stdin
+        #[arg(long, default_value = "stdin")]
+        name: String,
 
         #[arg(short = 'H', long)]
         host: String, 
 
         #[arg(short, long)]
         port: u16,
+
+        /// Compress chunks before encryption
+        #[arg(long, value_enum, default_value_t = Compression::None)]
+        compress: Compression,
+
+        /// Split every file across this many parallel connections
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..=MAX_STREAMS as i64))]
+        streams: u16,
+
+        /// Plaintext bytes per encrypted chunk (e.g. 64K, 1M)
+        #[arg(long, default_value = "8K", value_parser = parse_chunk_size)]
+        chunk_size: usize,
+
+        /// Also carry atime, ownership and extended attributes (ownership and xattrs are applied only with --restore-privileged)
+        #[arg(long)]
+        preserve: bool,
+
+        /// Cap bandwidth, e.g. 10M, or a schedule such as mon-fri@09:00-17:00=2M,10M
+        #[arg(long, value_parser = parse_rate_schedule)]
+        limit_rate: Option<RateSchedule>,
+
+        /// Progress output on stderr: bars (only on a terminal), JSON Lines events, or none
+        #[arg(long, value_enum, default_value_t = ProgressMode::Bar)]
+        progress: ProgressMode,
     },
+    /// Receive files and recreate their tree under the output directory
     Receive {
         #[arg(short, long)]
         port: u16,
 
+        /// Output directory, or `-` to write a single incoming file to stdout
         #[arg(short, long)]
         output: String,
+
+        #[command(flatten)]
+        existing: ExistingFileArgs,
+
+        /// Keep accepting concurrent senders instead of exiting after one upload
+        #[arg(long)]
+        serve: bool,
+
+        /// Store each upload under a subdirectory named after the sender's IP
+        #[arg(long)]
+        per_sender_dirs: bool,
+
+        /// Reject uploads containing a file larger than this (e.g. 500M, 2G)
+        #[arg(long, value_parser = parse_byte_size)]
+        max_file_size: Option<u64>,
+
+        /// Stop accepting uploads once this much data has been received (e.g. 50G)
+        #[arg(long, value_parser = parse_byte_size)]
+        quota: Option<u64>,
+
+        /// Cap bandwidth, e.g. 10M, or a schedule such as mon-fri@09:00-17:00=2M,10M
+        #[arg(long, value_parser = parse_rate_schedule)]
+        limit_rate: Option<RateSchedule>,
+
+        /// Keep the hidden `.name.ntpart` files of a failed upload instead of deleting them
+        #[arg(long)]
+        keep_partial: bool,
+
+        /// Apply ownership (as root), extended attributes and setuid/setgid/sticky bits sent with --preserve; only for trusted senders
+        #[arg(long)]
+        restore_privileged: bool,
+
+        /// Progress output on stderr: bars (only on a terminal), JSON Lines events, or none
+        #[arg(long, value_enum, default_value_t = ProgressMode::Bar)]
+        progress: ProgressMode,
+    },
+    /// Expose a directory read-only to `get` and `ls` clients
+    Serve {
+        #[arg(short, long)]
+        root: String,
+
+        #[arg(short, long)]
+        port: u16,
+
+        /// Compress chunks before encryption
+        #[arg(long, value_enum, default_value_t = Compression::None)]
+        compress: Compression,
+
+        /// Also carry atime, ownership and extended attributes (ownership and xattrs are applied only with --restore-privileged)
+        #[arg(long)]
+        preserve: bool,
+
+        /// Cap bandwidth, e.g. 10M, or a schedule such as mon-fri@09:00-17:00=2M,10M
+        #[arg(long, value_parser = parse_rate_schedule)]
+        limit_rate: Option<RateSchedule>,
     },
+    /// Download a file or directory from a file server
+    Get {
+        /// Server address as host:port
+        address: String,
+
+        /// Path relative to the server's shared directory
+        path: String,
+
+        #[arg(short, long, default_value = ".")]
+        output: String,
+
+        #[command(flatten)]
+        existing: ExistingFileArgs,
+
+        /// Cap bandwidth, e.g. 10M, or a schedule such as mon-fri@09:00-17:00=2M,10M
+        #[arg(long, value_parser = parse_rate_schedule)]
+        limit_rate: Option<RateSchedule>,
+
+        /// Keep the hidden `.name.ntpart` files of a failed download instead of deleting them
+        #[arg(long)]
+        keep_partial: bool,
+
+        /// Apply ownership (as root), extended attributes and setuid/setgid/sticky bits sent with --preserve; only for trusted servers
+        #[arg(long)]
+        restore_privileged: bool,
+
+        /// Progress output on stderr: bars (only on a terminal), JSON Lines events, or none
+        #[arg(long, value_enum, default_value_t = ProgressMode::Bar)]
+        progress: ProgressMode,
+    },
+    /// List a directory on a file server
+    Ls {
+        /// Server address as host:port
+        address: String,
+
+        /// Path relative to the server's shared directory
+        #[arg(default_value = "")]
+        path: String,
+    },
+    /// Write files and directories to a passphrase-encrypted archive (.ntar)
+    #[command(after_help = "The passphrase is read from --passphrase-file, else from $NETTOOL_PASSPHRASE, else prompted for.")]
+    Pack {
+        #[arg(short = 'f', long = "file", required = true, num_args = 1..)]
+        files: Vec<String>,
+
+        /// Archive to create
+        #[arg(long)]
+        out: String,
+
+        /// Compress chunks before encryption
+        #[arg(long, value_enum, default_value_t = Compression::None)]
+        compress: Compression,
+
+        /// Also store atime, ownership and extended attributes (ownership and xattrs are applied only with --restore-privileged)
+        #[arg(long)]
+        preserve: bool,
+
+        /// Read the passphrase from the first line of this file
+        #[arg(long)]
+        passphrase_file: Option<PathBuf>,
+    },
+    /// Verify an encrypted archive and extract it under the output directory
+    #[command(after_help = "The passphrase is read from --passphrase-file, else from $NETTOOL_PASSPHRASE, else prompted for.")]
+    Unpack {
+        /// Archive to read
+        archive: String,
+
+        #[arg(short, long, default_value = ".")]
+        output: String,
+
+        #[command(flatten)]
+        existing: ExistingFileArgs,
+
+        /// Check the archive without extracting anything
+        #[arg(long)]
+        verify_only: bool,
+
+        /// Keep the hidden `.name.ntpart` files of a failed extraction instead of deleting them
+        #[arg(long)]
+        keep_partial: bool,
+
+        /// Apply ownership (as root), extended attributes and setuid/setgid/sticky bits stored with --preserve; only for trusted archives
+        #[arg(long)]
+        restore_privileged: bool,
+
+        /// Read the passphrase from the first line of this file
+        #[arg(long)]
+        passphrase_file: Option<PathBuf>,
+    },
+}
+
+/// What to do with incoming files whose name is already taken (default: abort).
+#[derive(Args)]
+struct ExistingFileArgs {
+    /// Replace files that already exist in the output directory
+    #[arg(long, conflicts_with_all = ["skip", "rename"])]
+    overwrite: bool,
+
+    /// Keep existing files and discard the incoming copies
+    #[arg(long, conflicts_with = "rename")]
+    skip: bool,
+
+    /// Save incoming files as `name (1).ext` when the name is taken
+    #[arg(long)]
+    rename: bool,
+}
+
+impl ExistingFileArgs {
+    fn policy(&self) -> ExistingFilePolicy {
+        if self.overwrite {
+            ExistingFilePolicy::Overwrite
+        } else if self.skip {
+            ExistingFilePolicy::Skip
+        } else if self.rename {
+            ExistingFilePolicy::Rename
+        } else {
+            ExistingFilePolicy::Fail
+        }
+    }
 }
 
 #[derive(Subcommand)]
@@ -63,6 +321,10 @@ enum ShellMode {
     Listen {
         #[arg(short, long)]
         port: u16,
+
+        /// Cap bandwidth, e.g. 10M, or a schedule such as mon-fri@09:00-17:00=2M,10M
+        #[arg(long, value_parser = parse_rate_schedule)]
+        limit_rate: Option<RateSchedule>,
     },
     /// Connect to a remote shell at given IP and port
     Connect {
@@ -71,6 +333,10 @@ enum ShellMode {
 
         #[arg(short, long)]
         port: u16,
+
+        /// Cap bandwidth, e.g. 10M, or a schedule such as mon-fri@09:00-17:00=2M,10M
+        #[arg(long, value_parser = parse_rate_schedule)]
+        limit_rate: Option<RateSchedule>,
     },
 }
 
@@ -80,33 +346,142 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>>{
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::FileTransfer { mode } => match mode {
-            FileTransferMode::Send { file, host, port } => {
-                commands::file_transfer::send(&file, &host, port).await
+        Commands::FileTransfer { mode } => {
+            let result = match mode {
+                FileTransferMode::Send {
+                    files,
+                    name,
+                    host,
+                    port,
+                    compress,
+                    streams,
+                    chunk_size,
+                    preserve,
+                    limit_rate,
+                    progress,
+                } => {
+                    let options = SendOptions {
+                        compression: compress,
+                        streams,
+                        chunk_size,
+                        preserve,
+                        stdin_name: name,
+                        limit_rate,
+                        progress,
+                    };
+                    commands::file_transfer::send(&files, &host, port, options).await
+                }
+                FileTransferMode::Receive {
+                    port,
+                    output,
+                    existing,
+                    serve,
+                    per_sender_dirs,
+                    max_file_size,
+                    quota,
+                    limit_rate,
+                    keep_partial,
+                    restore_privileged,
+                    progress,
+                } => {
+                    let options = ReceiveOptions {
+                        policy: existing.policy(),
+                        serve,
+                        per_sender_dirs,
+                        max_file_size,
+                        quota,
+                        limit_rate,
+                        keep_partial,
+                        restore_privileged,
+                        progress,
+                    };
+                    commands::file_transfer::receive(port, &output, options).await
+                }
+                FileTransferMode::Serve { root, port, compress, preserve, limit_rate } => {
+                    commands::file_transfer::serve(&root, port, compress, preserve, limit_rate).await
+                }
+                FileTransferMode::Get { address, path, output, existing, limit_rate, keep_partial, restore_privileged, progress } => {
+                    let options = ReceiveOptions {
+                        policy: existing.policy(),
+                        limit_rate,
+                        keep_partial,
+                        restore_privileged,
+                        progress,
+                        ..Default::default()
+                    };
+                    commands::file_transfer::get(&address, &path, &output, options).await
+                }
+                FileTransferMode::Ls { address, path } => {
+                    commands::file_transfer::list(&address, &path).await
+                }
+                FileTransferMode::Pack { files, out, compress, preserve, passphrase_file } => {
+                    let options = PackOptions { compression: compress, preserve, passphrase_file };
+                    commands::file_transfer::pack(&files, &out, options).await
+                }
+                FileTransferMode::Unpack {
+                    archive,
+                    output,
+                    existing,
+                    verify_only,
+                    keep_partial,
+                    restore_privileged,
+                    passphrase_file,
+                } => {
+                    let options = UnpackOptions {
+                        policy: existing.policy(),
+                        keep_partial,
+                        verify_only,
+                        restore_privileged,
+                        passphrase_file,
+                    };
+                    commands::file_transfer::unpack(&archive, &output, options).await
+                }
+            };
+            if let Err(e) = result {
+                std::process::exit(e.exit_code());
             }
-            FileTransferMode::Receive { port, output } => {
-                commands::file_transfer::receive(port, &output).await
-            }
-            
-        },
-          Commands::EncryptedChat { mode, host, port } => {
+        }
+          Commands::EncryptedChat {
+            mode,
+            host,
+            port,
+            identity,
+            plain,
+            e2e,
+            accounts,
+            history,
+            history_on_join,
+            offline_retention,
+            offline_limit,
+        } => {
             match mode.to_lowercase().as_str() {
-                "server" => commands::encrypted_chat::chat_server(port).await?,
-                "client" => commands::encrypted_chat::chat_client(&host, port).await?,
+                "server" => {
+                    let options = commands::encrypted_chat::ChatServerOptions { e2e, accounts, history, history_on_join, offline_retention, offline_limit };
+                    commands::encrypted_chat::chat_server(port, identity.as_deref(), options).await?
+                }
+                "client" => commands::encrypted_chat::chat_client(&host, port, identity.as_deref(), plain).await?,
                 _ => eprintln!("Invalid mode. Use 'server' or 'client'."),
             }
         }
         Commands::PortScan => commands::port_scan::run().await,
         Commands::ShellAccess { mode } => match mode {
-            ShellMode::Listen { port } => {
-                let _ = commands::shell_access::start_listener(port);
+            ShellMode::Listen { port, limit_rate } => {
+                let _ = commands::shell_access::start_listener(port, limit_rate);
             }
-            ShellMode::Connect { host, port } => {
-                let _ = commands::shell_access::start_connector(&host, port);
+            ShellMode::Connect { host, port, limit_rate } => {
+                let _ = commands::shell_access::start_connector(&host, port, limit_rate);
             }
         },
     }
 
     Ok(())
 }
-        
+
+/// `--chunk-size` must fit comfortably inside a single frame.
+fn parse_chunk_size(input: &str) -> Result<usize, String> {
+    let size = parse_byte_size(input)?;
+    if size == 0 || size > MAX_CHUNK_SIZE as u64 {
+        return Err(format!("chunk size must be between 1 byte and {} bytes", MAX_CHUNK_SIZE));
+    }
+    Ok(size as usize)
+}
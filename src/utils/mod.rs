@@ -1,2 +1,4 @@
 pub mod encryption;
 pub mod networking;
+pub mod rate_limit;
+pub mod units;
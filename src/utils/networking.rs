@@ -0,0 +1,47 @@
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::utils::encryption::encrypt_chunk;
+
+/// Upper bound on a single frame, so a corrupt length prefix cannot make us allocate gigabytes.
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Write `data` as a length-prefixed frame, returning the number of bytes put on the wire.
+pub async fn write_frame<W>(writer: &mut W, data: &[u8]) -> Result<usize, Box<dyn std::error::Error + Send + Sync>>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(&(data.len() as u32).to_be_bytes()).await?;
+    writer.write_all(data).await?;
+    Ok(data.len() + 4)
+}
+
+/// Read one length-prefixed frame. Returns `None` on a clean end of stream.
+pub async fn read_frame<R>(reader: &mut R) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error + Send + Sync>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut size_buf = [0u8; 4];
+    match reader.read_exact(&mut size_buf).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let size = u32::from_be_bytes(size_buf) as usize;
+    if size > MAX_FRAME_SIZE {
+        return Err(format!("Frame of {} bytes exceeds limit of {} bytes", size, MAX_FRAME_SIZE).into());
+    }
+
+    let mut buffer = vec![0u8; size];
+    reader.read_exact(&mut buffer).await?;
+    Ok(Some(buffer))
+}
+
+/// Encrypt `data` with `key` and write it as a length-prefixed frame, returning the number of bytes put on the wire.
+pub async fn write_encrypted_frame<W>(writer: &mut W, data: &[u8], key: &[u8]) -> Result<usize, Box<dyn std::error::Error + Send + Sync>>
+where
+    W: AsyncWrite + Unpin,
+{
+    let encrypted = encrypt_chunk(data, key)?;
+    write_frame(writer, &encrypted).await
+}
@@ -0,0 +1,240 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::{Datelike, Local, NaiveTime, Timelike, Weekday};
+
+use crate::utils::units::parse_byte_size;
+
+/// One `[days@]HH:MM-HH:MM=RATE` rule of a schedule.
+#[derive(Debug, Clone)]
+struct Window {
+    /// Inclusive weekday range, Monday = 0; `None` means every day.
+    days: Option<(u32, u32)>,
+    start: NaiveTime,
+    end: NaiveTime,
+    rate: u64,
+}
+
+impl Window {
+    fn matches(&self, weekday: Weekday, time: NaiveTime) -> bool {
+        if let Some((first, last)) = self.days {
+            let day = weekday.num_days_from_monday();
+            let in_days = if first <= last { (first..=last).contains(&day) } else { day >= first || day <= last };
+            if !in_days {
+                return false;
+            }
+        }
+        if self.start <= self.end {
+            time >= self.start && time < self.end
+        } else {
+            // Wraps past midnight, e.g. 22:00-06:00.
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Bytes-per-second limit that may vary with the local time of day.
+///
+/// Written as comma-separated rules, e.g. `mon-fri@09:00-17:00=2M,10M`:
+/// the first matching time window wins, otherwise the bare rate applies
+/// (or no limit if there is none).
+#[derive(Debug, Clone)]
+pub struct RateSchedule {
+    windows: Vec<Window>,
+    default: Option<u64>,
+}
+
+impl RateSchedule {
+    /// The limit in force right now, `None` meaning unlimited.
+    pub fn current_rate(&self) -> Option<u64> {
+        let now = Local::now();
+        let time = NaiveTime::from_hms_opt(now.hour(), now.minute(), now.second()).unwrap_or_default();
+        self.windows
+            .iter()
+            .find(|w| w.matches(now.weekday(), time))
+            .map(|w| w.rate)
+            .or(self.default)
+    }
+}
+
+fn parse_weekday(input: &str) -> Result<u32, String> {
+    input
+        .parse::<Weekday>()
+        .map(|d| d.num_days_from_monday())
+        .map_err(|_| format!("invalid weekday '{}'", input))
+}
+
+fn parse_time(input: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(input, "%H:%M").map_err(|_| format!("invalid time '{}' (expected HH:MM)", input))
+}
+
+fn parse_rate(input: &str) -> Result<u64, String> {
+    match parse_byte_size(input)? {
+        0 => Err("rate must be greater than zero".to_string()),
+        rate => Ok(rate),
+    }
+}
+
+/// Parse a `--limit-rate` value: a plain rate such as `10M`, or a schedule
+/// such as `mon-fri@09:00-17:00=2M,10M`.
+pub fn parse_rate_schedule(input: &str) -> Result<RateSchedule, String> {
+    let mut schedule = RateSchedule { windows: Vec::new(), default: None };
+
+    for rule in input.split(',').map(str::trim) {
+        let Some((when, rate)) = rule.split_once('=') else {
+            if schedule.default.replace(parse_rate(rule)?).is_some() {
+                return Err("more than one default rate".to_string());
+            }
+            continue;
+        };
+
+        let (days, hours) = match when.split_once('@') {
+            Some((days, hours)) => {
+                let days = match days.split_once('-') {
+                    Some((first, last)) => (parse_weekday(first)?, parse_weekday(last)?),
+                    None => {
+                        let day = parse_weekday(days)?;
+                        (day, day)
+                    }
+                };
+                (Some(days), hours)
+            }
+            None => (None, when),
+        };
+        let (start, end) = hours
+            .split_once('-')
+            .ok_or_else(|| format!("invalid time window '{}' (expected HH:MM-HH:MM)", hours))?;
+
+        schedule.windows.push(Window {
+            days,
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+            rate: parse_rate(rate)?,
+        });
+    }
+
+    Ok(schedule)
+}
+
+struct Bucket {
+    /// May go negative: a large write is let through and paid back by waiting.
+    tokens: f64,
+    last: Instant,
+}
+
+/// Token bucket shared by every connection it should throttle together.
+pub struct RateLimiter {
+    schedule: RateSchedule,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(schedule: RateSchedule) -> Self {
+        RateLimiter {
+            schedule,
+            bucket: Mutex::new(Bucket { tokens: 0.0, last: Instant::now() }),
+        }
+    }
+
+    /// Account for `bytes` just sent or received, sleeping as long as needed to stay under the current rate.
+    pub async fn consume(&self, bytes: usize) {
+        if let Some(wait) = self.take(bytes) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// [`consume`](Self::consume) for plain threads: blocks the calling thread instead.
+    pub fn consume_blocking(&self, bytes: usize) {
+        if let Some(wait) = self.take(bytes) {
+            std::thread::sleep(wait);
+        }
+    }
+
+    /// Take `bytes` out of the bucket; returns how long to wait if it ran dry.
+    fn take(&self, bytes: usize) -> Option<Duration> {
+        let rate = self.schedule.current_rate()? as f64;
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        // Allow bursts of at most a tenth of a second's worth of data.
+        let capacity = rate / 10.0;
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.last).as_secs_f64() * rate).min(capacity);
+        bucket.last = now;
+        bucket.tokens -= bytes as f64;
+        (bucket.tokens < 0.0).then(|| Duration::from_secs_f64(-bucket.tokens / rate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn plain_rate_is_the_default() {
+        let schedule = parse_rate_schedule("10M").unwrap();
+        assert!(schedule.windows.is_empty());
+        assert_eq!(schedule.default, Some(10 << 20));
+    }
+
+    #[test]
+    fn windows_match_their_days_and_hours() {
+        let schedule = parse_rate_schedule("mon-fri@09:00-17:00=2M, 22:00-06:00=50M, 10M").unwrap();
+        assert_eq!(schedule.default, Some(10 << 20));
+        let [office, night] = &schedule.windows[..] else { panic!("expected two windows") };
+
+        assert_eq!(office.rate, 2 << 20);
+        assert!(office.matches(Weekday::Mon, at(9, 0)));
+        assert!(office.matches(Weekday::Fri, at(16, 59)));
+        assert!(!office.matches(Weekday::Fri, at(17, 0)));
+        assert!(!office.matches(Weekday::Sat, at(12, 0)));
+
+        // Wraps past midnight on every day.
+        assert!(night.matches(Weekday::Sun, at(23, 30)));
+        assert!(night.matches(Weekday::Wed, at(5, 59)));
+        assert!(!night.matches(Weekday::Wed, at(6, 0)));
+    }
+
+    #[test]
+    fn day_ranges_wrap_past_sunday() {
+        let schedule = parse_rate_schedule("sat-mon@00:00-23:59=1K").unwrap();
+        let window = &schedule.windows[0];
+        assert!(window.matches(Weekday::Sat, at(12, 0)));
+        assert!(window.matches(Weekday::Sun, at(12, 0)));
+        assert!(window.matches(Weekday::Mon, at(12, 0)));
+        assert!(!window.matches(Weekday::Tue, at(12, 0)));
+    }
+
+    #[test]
+    fn rejects_malformed_schedules() {
+        for input in [
+            "",
+            "0",
+            "10M,20M",
+            "09:00=1M",
+            "25:00-26:00=1M",
+            "funday@09:00-17:00=1M",
+            "mon@09:00-17:00=fast",
+            "mon@09:00-17:00=0",
+        ] {
+            assert!(parse_rate_schedule(input).is_err(), "accepted '{}'", input);
+        }
+    }
+
+    #[test]
+    fn blocking_consumers_wait_for_the_rate() {
+        let limiter = RateLimiter::new(parse_rate_schedule("100K").unwrap());
+        let started = Instant::now();
+        // The bucket starts empty, so 30K at 100K/s takes about 0.3 s.
+        for _ in 0..30 {
+            limiter.consume_blocking(1024);
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(250), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+}
@@ -0,0 +1,70 @@
+/// Parse a human-friendly byte count such as `512`, `64K`, `10M` or `2GiB`.
+///
+/// Suffixes are binary multiples (`K` = 1024) and case-insensitive.
+pub fn parse_byte_size(input: &str) -> Result<u64, String> {
+    let trimmed = input.trim();
+    let lower = trimmed.to_ascii_lowercase();
+    let without_unit = lower
+        .strip_suffix("ib")
+        .or_else(|| lower.strip_suffix('b'))
+        .unwrap_or(&lower);
+
+    let (digits, multiplier) = match without_unit.chars().last() {
+        Some('k') => (&without_unit[..without_unit.len() - 1], 1u64 << 10),
+        Some('m') => (&without_unit[..without_unit.len() - 1], 1u64 << 20),
+        Some('g') => (&without_unit[..without_unit.len() - 1], 1u64 << 30),
+        Some('t') => (&without_unit[..without_unit.len() - 1], 1u64 << 40),
+        _ => (without_unit, 1),
+    };
+
+    let value: f64 = digits
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid size '{}' (expected e.g. 512, 64K, 10M, 2G)", trimmed))?;
+    if !value.is_finite() || value < 0.0 {
+        return Err(format!("invalid size '{}'", trimmed));
+    }
+    Ok((value * multiplier as f64) as u64)
+}
+
+/// Parse a duration such as `90`, `30s`, `15m`, `12h` or `7d`; a bare number is seconds.
+pub fn parse_duration(input: &str) -> Result<std::time::Duration, String> {
+    let trimmed = input.trim();
+    let lower = trimmed.to_ascii_lowercase();
+    let (digits, multiplier) = match lower.chars().last() {
+        Some('s') => (&lower[..lower.len() - 1], 1),
+        Some('m') => (&lower[..lower.len() - 1], 60),
+        Some('h') => (&lower[..lower.len() - 1], 60 * 60),
+        Some('d') => (&lower[..lower.len() - 1], 24 * 60 * 60),
+        _ => (lower.as_str(), 1),
+    };
+
+    let value: u64 = digits
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid duration '{}' (expected e.g. 90, 30s, 15m, 12h, 7d)", trimmed))?;
+    Ok(std::time::Duration::from_secs(value.saturating_mul(multiplier)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_sizes_accept_binary_suffixes() {
+        assert_eq!(parse_byte_size("512"), Ok(512));
+        assert_eq!(parse_byte_size("64K"), Ok(64 << 10));
+        assert_eq!(parse_byte_size("10m"), Ok(10 << 20));
+        assert_eq!(parse_byte_size("2GiB"), Ok(2 << 30));
+        assert_eq!(parse_byte_size("1tb"), Ok(1 << 40));
+        assert_eq!(parse_byte_size(" 1.5K "), Ok(1536));
+        assert_eq!(parse_byte_size("100B"), Ok(100));
+    }
+
+    #[test]
+    fn byte_sizes_reject_garbage() {
+        for input in ["", "K", "ten", "-1M", "1X", "inf", "NaN"] {
+            assert!(parse_byte_size(input).is_err(), "accepted '{}'", input);
+        }
+    }
+}